@@ -0,0 +1,12 @@
+-- Create Unsubscribe Tokens Table
+-- Each subscriber gets a single long-lived token, embedded in the
+-- unsubscribe link and `List-Unsubscribe` header of every email we send.
+CREATE TABLE unsubscribe_tokens(
+    unsubscribe_token TEXT NOT NULL,
+    subscriber_id uuid NOT NULL UNIQUE
+        REFERENCES subscriptions (id),
+    PRIMARY KEY (unsubscribe_token)
+);
+-- Backfill a token for historical entries
+INSERT INTO unsubscribe_tokens (unsubscribe_token, subscriber_id)
+    SELECT md5(random()::text || id::text), id FROM subscriptions;
@@ -0,0 +1,4 @@
+-- Record when a subscription token was issued so that it can expire.
+-- Historical tokens are treated as freshly issued.
+ALTER TABLE subscription_tokens
+    ADD COLUMN issued_at timestamptz NOT NULL DEFAULT now();
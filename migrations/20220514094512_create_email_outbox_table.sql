@@ -0,0 +1,18 @@
+-- Create Email Outbox Table
+-- Transactional emails (e.g. subscription confirmations) are written here
+-- in the same transaction as the state change that triggers them.
+-- A background worker delivers them and deletes the row on success.
+CREATE TABLE email_outbox(
+    id uuid NOT NULL,
+    subscriber_id uuid NOT NULL
+        REFERENCES subscriptions (id),
+    recipient_email TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    html_body TEXT NOT NULL,
+    text_body TEXT NOT NULL,
+    unsubscribe_link TEXT NOT NULL,
+    n_retries SMALLINT NOT NULL DEFAULT 0,
+    execute_after timestamptz NOT NULL DEFAULT now(),
+    created_at timestamptz NOT NULL DEFAULT now(),
+    PRIMARY KEY (id)
+);
@@ -0,0 +1,29 @@
+-- Create Newsletter Issues Table
+CREATE TABLE newsletter_issues(
+    newsletter_issue_id uuid NOT NULL,
+    title TEXT NOT NULL,
+    text_content TEXT NOT NULL,
+    html_content TEXT NOT NULL,
+    published_at timestamptz NOT NULL,
+    PRIMARY KEY (newsletter_issue_id)
+);
+-- Create Issue Delivery Queue Table
+-- One row per (issue, subscriber). `status` is one of:
+-- * `pending`: waiting to be delivered, not before `execute_after`;
+-- * `delivered`: handed over to the email provider;
+-- * `skipped`: the subscriber left the list before we got to them;
+-- * `dead_letter`: we gave up, `last_error` tells why.
+CREATE TABLE issue_delivery_queue(
+    newsletter_issue_id uuid NOT NULL
+        REFERENCES newsletter_issues (newsletter_issue_id),
+    subscriber_id uuid NOT NULL
+        REFERENCES subscriptions (id),
+    status TEXT NOT NULL DEFAULT 'pending',
+    n_retries SMALLINT NOT NULL DEFAULT 0,
+    execute_after timestamptz NOT NULL DEFAULT now(),
+    last_error TEXT NULL,
+    PRIMARY KEY (newsletter_issue_id, subscriber_id)
+);
+CREATE INDEX issue_delivery_queue_pending_idx
+    ON issue_delivery_queue (execute_after)
+    WHERE status = 'pending';
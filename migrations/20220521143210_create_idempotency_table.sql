@@ -0,0 +1,16 @@
+-- Create Idempotency Table
+-- The response columns are only populated once the request has been
+-- processed, within the same transaction that inserted the row.
+CREATE TYPE header_pair AS (
+    name TEXT,
+    value BYTEA
+);
+CREATE TABLE idempotency (
+    username TEXT NOT NULL,
+    idempotency_key TEXT NOT NULL,
+    response_status_code SMALLINT NULL,
+    response_headers header_pair[] NULL,
+    response_body BYTEA NULL,
+    created_at timestamptz NOT NULL,
+    PRIMARY KEY(username, idempotency_key)
+);
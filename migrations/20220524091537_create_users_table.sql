@@ -0,0 +1,7 @@
+-- Create Users Table
+-- Passwords are stored as Argon2id hashes in PHC string format.
+CREATE TABLE users(
+    user_id uuid PRIMARY KEY,
+    username TEXT NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL
+);
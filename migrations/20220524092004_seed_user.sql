@@ -0,0 +1,9 @@
+-- Seed the initial admin user.
+-- The password is `everythinghastostartsomewhere`: change it right after
+-- the first deployment!
+INSERT INTO users (user_id, username, password_hash)
+VALUES (
+    'ddf8994f-d522-4659-8d02-c1d479057be6',
+    'admin',
+    '$argon2id$v=19$m=15000,t=2,p=1$Saa3cCycMVAzQ1XlCcFVDw$puHcu1qu8SpCtLmgKzsU7a6q5BLsGHn9KvmuJxCzBz0'
+);
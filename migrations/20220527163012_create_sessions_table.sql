@@ -0,0 +1,10 @@
+-- Create Sessions Table
+-- Server-side state of the admin sessions, looked up by the key stored
+-- in the (signed) session cookie.
+CREATE TABLE sessions(
+    session_key TEXT NOT NULL,
+    state JSONB NOT NULL,
+    expires_at timestamptz NOT NULL,
+    PRIMARY KEY (session_key)
+);
+CREATE INDEX sessions_expires_at_idx ON sessions (expires_at);
@@ -0,0 +1,6 @@
+-- Record which email provider message carried the latest confirmation
+-- email, so that "I never got my confirmation" complaints can be traced
+-- to a specific record on the provider's side.
+ALTER TABLE subscriptions
+    ADD COLUMN confirmation_email_message_id TEXT NULL,
+    ADD COLUMN confirmation_email_submitted_at timestamptz NULL;
@@ -0,0 +1,4 @@
+-- The language subscribers want to receive our emails in.
+-- Existing subscribers have only ever received English emails.
+ALTER TABLE subscriptions
+    ADD COLUMN locale TEXT NOT NULL DEFAULT 'en';
@@ -0,0 +1,4 @@
+-- How many times in a row delivery to the subscriber failed temporarily.
+-- Past a threshold we stop mailing them, as we do after a hard bounce.
+ALTER TABLE subscriptions
+    ADD COLUMN soft_bounce_count INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,19 @@
+-- `status` only ever takes the values of the `SubscriptionStatus` enum.
+BEGIN;
+    -- Backfill rows written by hand, or by older versions of the application.
+    UPDATE subscriptions
+        SET status = lower(trim(status));
+    -- We cannot tell what they signed up for: do not mail them until
+    -- they subscribe again.
+    UPDATE subscriptions
+        SET status = 'unsubscribed'
+        WHERE status NOT IN (
+            'pending_confirmation', 'confirmed', 'unsubscribed', 'bounced', 'complained'
+        );
+    ALTER TABLE subscriptions
+        ADD CONSTRAINT subscriptions_status_check CHECK (
+            status IN (
+                'pending_confirmation', 'confirmed', 'unsubscribed', 'bounced', 'complained'
+            )
+        );
+COMMIT;
@@ -0,0 +1,61 @@
+-- We run several newsletters. `subscriptions` now holds who subscribers
+-- are (one row per email address), `list_memberships` which lists they
+-- are on, each with its own status.
+BEGIN;
+    CREATE TABLE lists(
+        list_id uuid NOT NULL,
+        -- Identifies the list in forms and API calls, e.g. `rust-weekly`
+        slug TEXT NOT NULL UNIQUE,
+        name TEXT NOT NULL,
+        created_at timestamptz NOT NULL DEFAULT now(),
+        PRIMARY KEY (list_id)
+    );
+    -- The single list we ran so far: requests that do not name a list
+    -- keep going to it.
+    INSERT INTO lists (list_id, slug, name)
+    VALUES ('3f5b3a1e-6f0d-4c53-9a4e-2f1c8b7d9e10', 'newsletter', 'Our newsletter');
+
+    CREATE TABLE list_memberships(
+        subscriber_id uuid NOT NULL
+            REFERENCES subscriptions (id),
+        list_id uuid NOT NULL
+            REFERENCES lists (list_id),
+        status TEXT NOT NULL CHECK (
+            status IN (
+                'pending_confirmation', 'confirmed', 'unsubscribed', 'bounced', 'complained'
+            )
+        ),
+        subscribed_at timestamptz NOT NULL,
+        PRIMARY KEY (subscriber_id, list_id)
+    );
+    CREATE INDEX list_memberships_list_id_status_idx
+        ON list_memberships (list_id, status);
+    INSERT INTO list_memberships (subscriber_id, list_id, status, subscribed_at)
+        SELECT id, '3f5b3a1e-6f0d-4c53-9a4e-2f1c8b7d9e10', status, subscribed_at
+        FROM subscriptions;
+    ALTER TABLE subscriptions DROP COLUMN status;
+
+    -- Confirmation and unsubscribe links act on a single membership.
+    ALTER TABLE subscription_tokens
+        ADD COLUMN list_id uuid NULL REFERENCES lists (list_id);
+    UPDATE subscription_tokens
+        SET list_id = '3f5b3a1e-6f0d-4c53-9a4e-2f1c8b7d9e10';
+    ALTER TABLE subscription_tokens ALTER COLUMN list_id SET NOT NULL;
+
+    ALTER TABLE unsubscribe_tokens
+        DROP CONSTRAINT unsubscribe_tokens_subscriber_id_key,
+        ADD COLUMN list_id uuid NULL REFERENCES lists (list_id);
+    UPDATE unsubscribe_tokens
+        SET list_id = '3f5b3a1e-6f0d-4c53-9a4e-2f1c8b7d9e10';
+    ALTER TABLE unsubscribe_tokens
+        ALTER COLUMN list_id SET NOT NULL,
+        ADD CONSTRAINT unsubscribe_tokens_subscriber_id_list_id_key
+            UNIQUE (subscriber_id, list_id);
+
+    -- Issues are delivered to the confirmed members of a single list.
+    ALTER TABLE newsletter_issues
+        ADD COLUMN list_id uuid NULL REFERENCES lists (list_id);
+    UPDATE newsletter_issues
+        SET list_id = '3f5b3a1e-6f0d-4c53-9a4e-2f1c8b7d9e10';
+    ALTER TABLE newsletter_issues ALTER COLUMN list_id SET NOT NULL;
+COMMIT;
@@ -0,0 +1,24 @@
+-- Settings subscribers manage themselves from the preferences page.
+BEGIN;
+    -- How often subscribers want to hear from us: issues published in
+    -- between are held back until their next slot.
+    ALTER TABLE subscriptions
+        ADD COLUMN send_frequency TEXT NOT NULL DEFAULT 'immediately'
+            CONSTRAINT subscriptions_send_frequency_check
+            CHECK (send_frequency IN ('immediately', 'daily', 'weekly'));
+
+    -- New addresses only replace the current one once their owner
+    -- followed the link we sent them.
+    CREATE TABLE email_change_requests(
+        email_change_token TEXT NOT NULL,
+        subscriber_id uuid NOT NULL
+            REFERENCES subscriptions (id),
+        new_email TEXT NOT NULL,
+        requested_at timestamptz NOT NULL,
+        PRIMARY KEY (email_change_token)
+    );
+
+    -- Emails about the subscriber's account are not sent on behalf of a
+    -- list: there is nothing to unsubscribe from.
+    ALTER TABLE email_outbox ALTER COLUMN unsubscribe_link DROP NOT NULL;
+COMMIT;
@@ -0,0 +1,36 @@
+-- Data subject requests: erasing a subscriber deletes everything that
+-- hangs off their row, and leaves a tombstone behind.
+BEGIN;
+    ALTER TABLE subscription_tokens
+        DROP CONSTRAINT subscription_tokens_subscriber_id_fkey,
+        ADD CONSTRAINT subscription_tokens_subscriber_id_fkey
+            FOREIGN KEY (subscriber_id) REFERENCES subscriptions (id) ON DELETE CASCADE;
+    ALTER TABLE unsubscribe_tokens
+        DROP CONSTRAINT unsubscribe_tokens_subscriber_id_fkey,
+        ADD CONSTRAINT unsubscribe_tokens_subscriber_id_fkey
+            FOREIGN KEY (subscriber_id) REFERENCES subscriptions (id) ON DELETE CASCADE;
+    ALTER TABLE email_outbox
+        DROP CONSTRAINT email_outbox_subscriber_id_fkey,
+        ADD CONSTRAINT email_outbox_subscriber_id_fkey
+            FOREIGN KEY (subscriber_id) REFERENCES subscriptions (id) ON DELETE CASCADE;
+    ALTER TABLE issue_delivery_queue
+        DROP CONSTRAINT issue_delivery_queue_subscriber_id_fkey,
+        ADD CONSTRAINT issue_delivery_queue_subscriber_id_fkey
+            FOREIGN KEY (subscriber_id) REFERENCES subscriptions (id) ON DELETE CASCADE;
+    ALTER TABLE list_memberships
+        DROP CONSTRAINT list_memberships_subscriber_id_fkey,
+        ADD CONSTRAINT list_memberships_subscriber_id_fkey
+            FOREIGN KEY (subscriber_id) REFERENCES subscriptions (id) ON DELETE CASCADE;
+    ALTER TABLE email_change_requests
+        DROP CONSTRAINT email_change_requests_subscriber_id_fkey,
+        ADD CONSTRAINT email_change_requests_subscriber_id_fkey
+            FOREIGN KEY (subscriber_id) REFERENCES subscriptions (id) ON DELETE CASCADE;
+
+    -- The SHA-256 digest of each erased address, lowercased: enough to
+    -- recognise it if it comes back, not to recover it.
+    CREATE TABLE erased_subscribers(
+        email_hash TEXT NOT NULL,
+        erased_at timestamptz NOT NULL,
+        PRIMARY KEY (email_hash)
+    );
+COMMIT;
@@ -0,0 +1,11 @@
+-- Token buckets shared by every instance of the application, when rate
+-- limits are configured with the `postgres` store.
+CREATE TABLE rate_limit_buckets(
+    key TEXT NOT NULL,
+    tokens DOUBLE PRECISION NOT NULL,
+    updated_at timestamptz NOT NULL,
+    -- From then on the bucket is no different from a missing one
+    full_at timestamptz NOT NULL,
+    PRIMARY KEY (key)
+);
+CREATE INDEX rate_limit_buckets_full_at_idx ON rate_limit_buckets (full_at);
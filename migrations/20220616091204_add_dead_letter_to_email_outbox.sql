@@ -0,0 +1,9 @@
+-- Add Dead Letter State to Email Outbox
+-- Emails are retried a bounded number of times. `status` is one of:
+-- * `pending`: waiting to be delivered, not before `execute_after`;
+-- * `dead_letter`: we gave up, `last_error` tells why.
+-- Delivered emails are deleted, as before.
+BEGIN;
+    ALTER TABLE email_outbox ADD COLUMN status TEXT NOT NULL DEFAULT 'pending';
+    ALTER TABLE email_outbox ADD COLUMN last_error TEXT NULL;
+COMMIT;
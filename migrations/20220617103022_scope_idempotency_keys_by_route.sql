@@ -0,0 +1,6 @@
+-- Scope idempotency keys to the route they were used on, on top of the user.
+ALTER TABLE idempotency ADD COLUMN route TEXT NULL;
+UPDATE idempotency SET route = '/newsletters';
+ALTER TABLE idempotency ALTER COLUMN route SET NOT NULL;
+ALTER TABLE idempotency DROP CONSTRAINT idempotency_pkey;
+ALTER TABLE idempotency ADD PRIMARY KEY (username, route, idempotency_key);
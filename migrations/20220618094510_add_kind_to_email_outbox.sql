@@ -0,0 +1,4 @@
+-- Tell confirmation emails apart from the other emails in the outbox:
+-- only their message ID is recorded against the subscriber.
+ALTER TABLE email_outbox ADD COLUMN kind TEXT NOT NULL DEFAULT 'confirmation';
+ALTER TABLE email_outbox ALTER COLUMN kind DROP DEFAULT;
@@ -0,0 +1,11 @@
+-- Lease Issue Deliveries
+-- Workers no longer hold row locks while they talk to the email provider:
+-- they claim tasks instead, moving them to a new status.
+-- * `in_flight`: claimed by a worker until `execute_after`. If the worker
+--   dies with the task, the lease runs out and the task is claimed again.
+BEGIN;
+    DROP INDEX issue_delivery_queue_pending_idx;
+    CREATE INDEX issue_delivery_queue_due_idx
+        ON issue_delivery_queue (execute_after)
+        WHERE status IN ('pending', 'in_flight');
+COMMIT;
@@ -0,0 +1,3 @@
+-- Preferences links carry the version they were signed for: bumping it
+-- revokes every link sent so far (e.g. once the address changes).
+ALTER TABLE subscriptions ADD COLUMN preferences_version INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,14 @@
+-- Data subject requests made from the preferences page only go through
+-- once the subscriber follows the link we email them: the preferences
+-- link alone never expires, and may have been forwarded.
+-- Each token can be used once, shortly after it was issued.
+CREATE TABLE data_request_tokens(
+    data_request_token TEXT NOT NULL,
+    subscriber_id uuid NOT NULL
+        REFERENCES subscriptions (id) ON DELETE CASCADE,
+    kind TEXT NOT NULL
+        CONSTRAINT data_request_tokens_kind_check
+        CHECK (kind IN ('export', 'erase')),
+    requested_at timestamptz NOT NULL,
+    PRIMARY KEY (data_request_token)
+);
@@ -0,0 +1,130 @@
+//! src/authentication/extractor.rs
+use super::{validate_credentials, AuthError, Credentials};
+use crate::routes::error_chain_fmt;
+use actix_web::dev::Payload;
+use actix_web::http::header::{HeaderMap, HeaderValue};
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
+use secrecy::Secret;
+use sqlx::PgPool;
+use std::future::Future;
+use std::pin::Pin;
+use uuid::Uuid;
+
+/// A user who proved their identity with HTTP Basic authentication.
+///
+/// Add it to the arguments of a handler to restrict it to authenticated
+/// users: requests with missing or invalid credentials are rejected with
+/// a `401 Unauthorized` before the handler is invoked.
+#[derive(Clone, Debug)]
+pub struct AuthenticatedUser {
+    pub user_id: Uuid,
+    pub username: String,
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = AuthenticationError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(request: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let request = request.clone();
+        Box::pin(async move {
+            // Verifying a password is expensive: we only do it once per
+            // request, even if the user is extracted more than once
+            // (e.g. by a middleware and then by the handler).
+            if let Some(user) = request.extensions().get::<AuthenticatedUser>() {
+                return Ok(user.clone());
+            }
+            let credentials =
+                basic_authentication(request.headers()).map_err(AuthenticationError::AuthError)?;
+            let pool = request
+                .app_data::<web::Data<PgPool>>()
+                .context("The database pool is not registered")
+                .map_err(AuthenticationError::UnexpectedError)?;
+            let username = credentials.username.clone();
+            let user_id = validate_credentials(credentials, pool)
+                .await
+                .map_err(|e| match e {
+                    AuthError::InvalidCredentials(_) => AuthenticationError::AuthError(e.into()),
+                    AuthError::UnexpectedError(_) => AuthenticationError::UnexpectedError(e.into()),
+                })?;
+            let user = AuthenticatedUser { user_id, username };
+            request.extensions_mut().insert(user.clone());
+            Ok(user)
+        })
+    }
+}
+
+pub(crate) fn basic_authentication(headers: &HeaderMap) -> Result<Credentials, anyhow::Error> {
+    // The header value, if present, must be a valid UTF8 string
+    let header_value = headers
+        .get("Authorization")
+        .context("The 'Authorization' header was missing")?
+        .to_str()
+        .context("The 'Authorization' header was not a valid UTF8 string.")?;
+    let base64encoded_segment = header_value
+        .strip_prefix("Basic ")
+        .context("The authorization scheme was not 'Basic'.")?;
+    let decoded_bytes = base64::decode_config(base64encoded_segment, base64::STANDARD)
+        .context("Failed to base64-decode 'Basic' credentials.")?;
+    let decoded_credentials = String::from_utf8(decoded_bytes)
+        .context("The decoded credential string is not valid UTF8.")?;
+
+    // Split into two segments, using ':' as delimitator
+    let mut credentials = decoded_credentials.splitn(2, ':');
+    let username = credentials
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("A username must be provided in 'Basic' auth."))?
+        .to_string();
+    let password = credentials
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("A password must be provided in 'Basic' auth."))?
+        .to_string();
+
+    Ok(Credentials {
+        username,
+        password: Secret::new(password),
+    })
+}
+
+#[derive(thiserror::Error)]
+pub enum AuthenticationError {
+    #[error("Authentication failed.")]
+    AuthError(#[source] anyhow::Error),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for AuthenticationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for AuthenticationError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthenticationError::AuthError(_) => StatusCode::UNAUTHORIZED,
+            AuthenticationError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            AuthenticationError::AuthError(_) => {
+                let mut response = HttpResponse::new(StatusCode::UNAUTHORIZED);
+                let header_value = HeaderValue::from_str(r#"Basic realm="publish""#).unwrap();
+                response
+                    .headers_mut()
+                    // actix_web::http::header provides a collection of constants
+                    // for the names of several well-known/standard HTTP headers
+                    .insert(header::WWW_AUTHENTICATE, header_value);
+                response
+            }
+            AuthenticationError::UnexpectedError(_) => {
+                HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+}
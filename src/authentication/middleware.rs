@@ -0,0 +1,86 @@
+//! src/authentication/middleware.rs
+use crate::session_state::TypedSession;
+use crate::utils::see_other;
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::HttpMessage;
+use std::future::{ready, Future, Ready};
+use std::ops::Deref;
+use std::pin::Pin;
+use std::rc::Rc;
+use uuid::Uuid;
+
+/// The id of the user logged in with the current session.
+///
+/// Available, via `web::ReqData<UserId>`, to every handler behind
+/// `RejectAnonymousUsers`.
+#[derive(Copy, Clone, Debug)]
+pub struct UserId(Uuid);
+
+impl std::fmt::Display for UserId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Deref for UserId {
+    type Target = Uuid;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Redirect to the login page the requests that do not come with a
+/// logged-in session.
+pub struct RejectAnonymousUsers;
+
+impl<S, B> Transform<S, ServiceRequest> for RejectAnonymousUsers
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    type Transform = RejectAnonymousUsersMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RejectAnonymousUsersMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RejectAnonymousUsersMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RejectAnonymousUsersMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        Box::pin(async move {
+            let session = TypedSession::from(&request);
+            match session.get_user_id().map_err(ErrorInternalServerError)? {
+                Some(user_id) => {
+                    request.extensions_mut().insert(UserId(user_id));
+                    Ok(service.call(request).await?.map_into_boxed_body())
+                }
+                None => Ok(request.into_response(see_other("/login"))),
+            }
+        })
+    }
+}
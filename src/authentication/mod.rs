@@ -0,0 +1,12 @@
+//! src/authentication/mod.rs
+
+mod extractor;
+mod middleware;
+mod password;
+
+pub(crate) use extractor::basic_authentication;
+pub use extractor::{AuthenticatedUser, AuthenticationError};
+pub use middleware::{RejectAnonymousUsers, UserId};
+pub use password::{
+    change_password, compute_password_hash, validate_credentials, AuthError, Credentials,
+};
@@ -1,12 +1,23 @@
 //! src/configuration.rs
 use crate::domain::SubscriberEmail;
+use crate::email_client::{EmailClient, EmailSender, RetryPolicy};
+use crate::email_templates::EmailTemplates;
+use crate::file_email_client::FileEmailClient;
+use crate::rate_limit::{
+    InMemoryRateLimitStore, PostgresRateLimitStore, RateLimit, RateLimitStore, TokenBucket,
+};
+use crate::smtp_email_client::SmtpEmailClient;
+use crate::startup::EmailHashSecret;
+use actix_web::cookie::Key;
 use secrecy::{ExposeSecret, Secret};
 use serde_aux::field_attributes::deserialize_number_from_string;
 use sqlx::{
     postgres::{PgConnectOptions, PgSslMode},
-    ConnectOptions,
+    ConnectOptions, PgPool,
 };
 use std::convert::{TryFrom, TryInto};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 #[derive(Clone, serde::Deserialize)]
 pub struct Settings {
@@ -14,19 +25,146 @@ pub struct Settings {
     pub application: ApplicationSettings,
     // New field!
     pub email_client: EmailClientSettings,
+    #[serde(default)]
+    pub email_templates: EmailTemplateSettings,
+    pub webhooks: WebhookSettings,
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct WebhookSettings {
+    pub postmark: PostmarkWebhookSettings,
+}
+
+// The placeholder secrets shipped in `configuration/base.yaml`.
+const DEFAULT_POSTMARK_WEBHOOK_SECRET: &str = "my-webhook-secret";
+const DEFAULT_EMAIL_HASH_SECRET: &str = "another-secret-key-to-hash-email-addresses";
+
+#[derive(Clone, serde::Deserialize)]
+pub struct PostmarkWebhookSettings {
+    // Postmark authenticates with HTTP Basic auth, using these credentials,
+    // or by sending the secret in the `X-Webhook-Secret` header
+    pub username: String,
+    pub secret: Secret<String>,
+    // Soft bounces in a row after which we stop mailing a subscriber
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub soft_bounce_threshold: i32,
+}
+
+#[derive(Clone, Default, serde::Deserialize)]
+pub struct EmailTemplateSettings {
+    // Templates found here override the built-in ones
+    pub directory: Option<String>,
+}
+
+impl EmailTemplateSettings {
+    pub fn templates(&self) -> Result<EmailTemplates, anyhow::Error> {
+        EmailTemplates::load(self.directory.as_deref().map(Path::new))
+    }
 }
 
 #[derive(Clone, serde::Deserialize)]
 pub struct EmailClientSettings {
+    // Which backend delivers our emails
+    pub provider: EmailProvider,
     pub base_url: String,
     pub sender_email: String,
     // New (secret) configuration value!
     pub authorization_token: Secret<String>,
     // New configuration value!
     pub timeout_milliseconds: u64,
+    // Only used by the `smtp` provider
+    pub smtp: Option<SmtpSettings>,
+    // Only used by the `file` provider: emails are printed to stdout if unset
+    pub output_directory: Option<String>,
+    // How the `postmark` and `smtp` providers deal with transient failures
+    pub retry: RetrySettings,
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct RetrySettings {
+    // Including the first one: set it to 1 to disable retries
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_attempts: u32,
+    // Doubles after every failed attempt
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub base_delay_milliseconds: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_jitter_milliseconds: u64,
+}
+
+impl RetrySettings {
+    pub fn policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.max_attempts.max(1),
+            base_delay: std::time::Duration::from_millis(self.base_delay_milliseconds),
+            max_jitter: std::time::Duration::from_millis(self.max_jitter_milliseconds),
+        }
+    }
+}
+
+/// The backends we can deliver emails through.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailProvider {
+    Postmark,
+    Smtp,
+    File,
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct SmtpSettings {
+    pub host: String,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<Secret<String>>,
+    // Only disable it for relays on a trusted network
+    pub require_tls: bool,
 }
 
 impl EmailClientSettings {
+    pub fn client(self) -> Arc<dyn EmailSender> {
+        let sender_email = self.sender().expect("Invalid sender email address.");
+        let timeout = self.timeout();
+        let retry_policy = self.retry.policy();
+        match self.provider {
+            EmailProvider::Postmark => Arc::new(EmailClient::new(
+                self.base_url,
+                sender_email,
+                self.authorization_token,
+                timeout,
+                retry_policy,
+            )),
+            EmailProvider::Smtp => {
+                let smtp = self
+                    .smtp
+                    .expect("The `smtp` provider requires an `smtp` section.");
+                let credentials = smtp.username.map(|username| {
+                    (
+                        username,
+                        smtp.password.unwrap_or_else(|| Secret::new("".into())),
+                    )
+                });
+                Arc::new(
+                    SmtpEmailClient::new(
+                        &smtp.host,
+                        smtp.port,
+                        credentials,
+                        smtp.require_tls,
+                        sender_email,
+                        timeout,
+                        retry_policy,
+                    )
+                    .expect("Invalid SMTP relay configuration."),
+                )
+            }
+            EmailProvider::File => Arc::new(FileEmailClient::new(
+                self.output_directory.map(PathBuf::from),
+                sender_email,
+            )),
+        }
+    }
+
     pub fn sender(&self) -> Result<SubscriberEmail, String> {
         SubscriberEmail::parse(self.sender_email.clone())
     }
@@ -43,6 +181,113 @@ pub struct ApplicationSettings {
     pub host: String,
     // New field!
     pub base_url: String,
+    // How long a confirmation link stays valid after being issued
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub subscription_token_ttl_seconds: u64,
+    // Signs the session and flash message cookies
+    pub hmac_secret: Secret<String>,
+    // Keys the digests we keep of email addresses
+    pub email_hash_secret: Secret<String>,
+    // How long an admin stays logged in
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub session_ttl_seconds: u64,
+    // Limits on the public routes that send emails on behalf of anyone
+    pub rate_limits: RateLimitSettings,
+}
+
+impl ApplicationSettings {
+    pub fn subscription_token_ttl(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.subscription_token_ttl_seconds)
+    }
+
+    pub fn session_ttl(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.session_ttl_seconds)
+    }
+
+    /// The key signing the session and flash message cookies.
+    ///
+    /// It is derived from `hmac_secret`, which must be at least 64 bytes long.
+    pub fn secret_key(&self) -> Result<Key, String> {
+        Key::try_from(self.hmac_secret.expose_secret().as_bytes())
+            .map_err(|e| format!("`application.hmac_secret` cannot sign cookies: {}", e))
+    }
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct RateLimitSettings {
+    // Where the token buckets live
+    pub store: RateLimitStoreKind,
+    // Read the client IP from the last `X-Forwarded-For` entry, the one
+    // added by our proxy: only enable it behind a proxy that sets it
+    pub trust_forwarded_for: bool,
+    // `POST /subscriptions`
+    pub subscribe: RouteRateLimitSettings,
+    // `GET /subscriptions/confirm`
+    pub confirm: RouteRateLimitSettings,
+}
+
+impl RateLimitSettings {
+    pub fn store(&self, pool: PgPool) -> Arc<dyn RateLimitStore> {
+        match self.store {
+            RateLimitStoreKind::Memory => Arc::new(InMemoryRateLimitStore::new()),
+            RateLimitStoreKind::Postgres => Arc::new(PostgresRateLimitStore::new(pool)),
+        }
+    }
+}
+
+/// The places rate limits can be enforced from.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitStoreKind {
+    // Each instance of the application enforces the limits on its own
+    Memory,
+    // The limits hold across instances
+    Postgres,
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct RouteRateLimitSettings {
+    pub per_ip: TokenBucketSettings,
+    // Keyed by the `email` field of the submitted form
+    pub per_email: Option<TokenBucketSettings>,
+}
+
+impl RouteRateLimitSettings {
+    pub fn middleware(
+        &self,
+        route: &'static str,
+        store: Arc<dyn RateLimitStore>,
+        trust_forwarded_for: bool,
+        email_hash_secret: EmailHashSecret,
+    ) -> RateLimit {
+        RateLimit::new(
+            route,
+            store,
+            self.per_ip.bucket(),
+            self.per_email.as_ref().map(TokenBucketSettings::bucket),
+            trust_forwarded_for,
+            email_hash_secret,
+        )
+    }
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct TokenBucketSettings {
+    // Requests allowed in a burst
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub capacity: u32,
+    // One more request is allowed every interval, up to `capacity`
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub refill_interval_milliseconds: u64,
+}
+
+impl TokenBucketSettings {
+    pub fn bucket(&self) -> TokenBucket {
+        TokenBucket {
+            capacity: self.capacity,
+            refill_interval: std::time::Duration::from_millis(self.refill_interval_milliseconds),
+        }
+    }
 }
 
 #[derive(Clone, serde::Deserialize)]
@@ -108,7 +353,23 @@ pub fn get_configuration() -> Result<Settings, config::ConfigError> {
 
     // Try to convert the configuration values it read into
     // our Settings type
-    settings.try_into()
+    let settings: Settings = settings.try_into()?;
+
+    if let Environment::Production = environment {
+        // Anyone reading `base.yaml` could forge webhook calls.
+        if settings.webhooks.postmark.secret.expose_secret() == DEFAULT_POSTMARK_WEBHOOK_SECRET {
+            return Err(config::ConfigError::Message(
+                "`webhooks.postmark.secret` must be set in production.".into(),
+            ));
+        }
+        // ... or tell which addresses we erased, by hashing candidates.
+        if settings.application.email_hash_secret.expose_secret() == DEFAULT_EMAIL_HASH_SECRET {
+            return Err(config::ConfigError::Message(
+                "`application.email_hash_secret` must be set in production.".into(),
+            ));
+        }
+    }
+    Ok(settings)
 }
 
 /// The possible runtime environment for our application.
@@ -1,9 +1,15 @@
 //! src/domain/mod.rs
 
 mod new_subscriber;
+mod send_frequency;
 mod subscriber_email;
+mod subscriber_locale;
 mod subscriber_name;
+mod subscription_status;
 
 pub use new_subscriber::NewSubscriber;
+pub use send_frequency::SendFrequency;
 pub use subscriber_email::SubscriberEmail;
+pub use subscriber_locale::SubscriberLocale;
 pub use subscriber_name::SubscriberName;
+pub use subscription_status::{IllegalTransition, SubscriptionStatus};
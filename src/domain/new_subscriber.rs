@@ -1,10 +1,12 @@
 //! src/domain/new_subscriber.rs
 
 use crate::domain::SubscriberEmail;
+use crate::domain::SubscriberLocale;
 use crate::domain::SubscriberName;
 
 pub struct NewSubscriber {
     // We are not using `String` anymore!
     pub email: SubscriberEmail,
     pub name: SubscriberName,
+    pub locale: SubscriberLocale,
 }
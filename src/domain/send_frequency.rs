@@ -0,0 +1,120 @@
+//! src/domain/send_frequency.rs
+use chrono::{DateTime, Datelike, Duration, Utc};
+use std::fmt;
+use std::str::FromStr;
+
+/// How often a subscriber wants to receive our newsletters.
+///
+/// Issues published between two slots are held back, then delivered
+/// together at the start of the next one (midnight UTC for `daily`,
+/// Monday midnight UTC for `weekly`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SendFrequency {
+    #[default]
+    Immediately,
+    Daily,
+    Weekly,
+}
+
+impl SendFrequency {
+    pub const ALL: [SendFrequency; 3] = [
+        SendFrequency::Immediately,
+        SendFrequency::Daily,
+        SendFrequency::Weekly,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SendFrequency::Immediately => "immediately",
+            SendFrequency::Daily => "daily",
+            SendFrequency::Weekly => "weekly",
+        }
+    }
+
+    /// When an issue published at `now` should be delivered.
+    pub fn next_slot(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let midnight = now
+            .date_naive()
+            .and_hms_opt(0, 0, 0)
+            .expect("Midnight is a valid time.")
+            .and_utc();
+        match self {
+            SendFrequency::Immediately => now,
+            SendFrequency::Daily => midnight + Duration::days(1),
+            SendFrequency::Weekly => {
+                let days_since_monday = i64::from(now.weekday().num_days_from_monday());
+                midnight + Duration::days(7 - days_since_monday)
+            }
+        }
+    }
+}
+
+impl fmt::Display for SendFrequency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for SendFrequency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "immediately" => Ok(SendFrequency::Immediately),
+            "daily" => Ok(SendFrequency::Daily),
+            "weekly" => Ok(SendFrequency::Weekly),
+            other => Err(format!("{} is not a valid send frequency.", other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SendFrequency;
+    use chrono::{DateTime, Utc};
+    use claim::assert_err;
+
+    fn at(timestamp: &str) -> DateTime<Utc> {
+        timestamp.parse().unwrap()
+    }
+
+    #[test]
+    fn frequencies_round_trip_through_their_database_representation() {
+        for frequency in SendFrequency::ALL {
+            assert_eq!(frequency.as_str().parse(), Ok(frequency));
+        }
+        assert_err!("hourly".parse::<SendFrequency>());
+    }
+
+    #[test]
+    fn immediate_deliveries_are_not_held_back() {
+        let now = at("2022-06-08T15:27:30Z");
+        assert_eq!(SendFrequency::Immediately.next_slot(now), now);
+    }
+
+    #[test]
+    fn daily_deliveries_wait_for_the_next_midnight() {
+        assert_eq!(
+            SendFrequency::Daily.next_slot(at("2022-06-08T15:27:30Z")),
+            at("2022-06-09T00:00:00Z")
+        );
+        assert_eq!(
+            SendFrequency::Daily.next_slot(at("2022-06-08T00:00:00Z")),
+            at("2022-06-09T00:00:00Z")
+        );
+    }
+
+    #[test]
+    fn weekly_deliveries_wait_for_the_next_monday() {
+        // 2022-06-08 is a Wednesday.
+        assert_eq!(
+            SendFrequency::Weekly.next_slot(at("2022-06-08T15:27:30Z")),
+            at("2022-06-13T00:00:00Z")
+        );
+        // 2022-06-13 is a Monday.
+        assert_eq!(
+            SendFrequency::Weekly.next_slot(at("2022-06-13T09:00:00Z")),
+            at("2022-06-20T00:00:00Z")
+        );
+    }
+}
@@ -0,0 +1,79 @@
+//! src/domain/subscriber_locale.rs
+
+/// The language a subscriber wants to receive emails in, as a BCP 47
+/// language tag restricted to a language and an optional region -
+/// e.g. `fr` or `pt-BR`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SubscriberLocale(String);
+
+impl SubscriberLocale {
+    /// Used when a subscriber did not tell us which language they speak.
+    pub const DEFAULT: &'static str = "en";
+
+    /// Parse `s`, normalizing its case (`PT_br` becomes `pt-BR`).
+    pub fn parse(s: String) -> Result<SubscriberLocale, String> {
+        let mut parts = s.trim().split(['-', '_']);
+        let language = parts.next().unwrap_or_default();
+        let region = parts.next();
+        let is_valid_language =
+            (2..=3).contains(&language.len()) && language.chars().all(|c| c.is_ascii_alphabetic());
+        let is_valid_region = region.is_none_or(|region| {
+            (region.len() == 2 && region.chars().all(|c| c.is_ascii_alphabetic()))
+                || (region.len() == 3 && region.chars().all(|c| c.is_ascii_digit()))
+        });
+        if !is_valid_language || !is_valid_region || parts.next().is_some() {
+            return Err(format!("{} is not a valid locale.", s));
+        }
+        let mut locale = language.to_ascii_lowercase();
+        if let Some(region) = region {
+            locale.push('-');
+            locale.push_str(&region.to_ascii_uppercase());
+        }
+        Ok(Self(locale))
+    }
+
+    /// The language, without the region.
+    pub fn language(&self) -> &str {
+        self.0.split('-').next().unwrap_or(&self.0)
+    }
+}
+
+impl Default for SubscriberLocale {
+    fn default() -> Self {
+        Self(Self::DEFAULT.into())
+    }
+}
+
+impl AsRef<str> for SubscriberLocale {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriberLocale;
+    use claim::{assert_err, assert_ok};
+
+    #[test]
+    fn languages_are_valid() {
+        let locale = assert_ok!(SubscriberLocale::parse("fr".into()));
+        assert_eq!(locale.as_ref(), "fr");
+    }
+
+    #[test]
+    fn languages_with_a_region_are_normalized() {
+        let locale = SubscriberLocale::parse("PT_br".into()).unwrap();
+        assert_eq!(locale.as_ref(), "pt-BR");
+        assert_eq!(locale.language(), "pt");
+        let locale = SubscriberLocale::parse("es-419".into()).unwrap();
+        assert_eq!(locale.as_ref(), "es-419");
+    }
+
+    #[test]
+    fn malformed_locales_are_rejected() {
+        for locale in ["", "f", "french", "fr-", "fr-B", "fr-BE-x", "f1", "*"] {
+            assert_err!(SubscriberLocale::parse(locale.into()));
+        }
+    }
+}
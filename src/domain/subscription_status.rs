@@ -0,0 +1,160 @@
+//! src/domain/subscription_status.rs
+use std::fmt;
+use std::str::FromStr;
+
+/// Where a subscriber stands with respect to our mailing list.
+///
+/// Subscribers move between statuses as follows:
+/// - `pending_confirmation` to `confirmed`, by following the confirmation
+///   link;
+/// - `pending_confirmation` or `confirmed` to `unsubscribed`;
+/// - `unsubscribed` back to `pending_confirmation`, by subscribing again;
+/// - any status but `complained` to `bounced`;
+/// - any status to `complained`.
+///
+/// Nothing leads back from `bounced` or `complained`: we never mail those
+/// addresses again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubscriptionStatus {
+    PendingConfirmation,
+    Confirmed,
+    Unsubscribed,
+    Bounced,
+    Complained,
+}
+
+impl SubscriptionStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SubscriptionStatus::PendingConfirmation => "pending_confirmation",
+            SubscriptionStatus::Confirmed => "confirmed",
+            SubscriptionStatus::Unsubscribed => "unsubscribed",
+            SubscriptionStatus::Bounced => "bounced",
+            SubscriptionStatus::Complained => "complained",
+        }
+    }
+
+    /// Whether we stopped mailing the subscriber because of delivery
+    /// failures or complaints.
+    pub fn is_suppressed(&self) -> bool {
+        matches!(
+            self,
+            SubscriptionStatus::Bounced | SubscriptionStatus::Complained
+        )
+    }
+
+    /// Move to `next`, if it can be reached from the current status.
+    ///
+    /// Staying in the same status is always allowed, so that repeated
+    /// requests (e.g. following a confirmation link twice) are harmless.
+    pub fn transition_to(self, next: Self) -> Result<Self, IllegalTransition> {
+        use SubscriptionStatus::*;
+        let is_legal = self == next
+            || matches!(
+                (self, next),
+                (PendingConfirmation, Confirmed)
+                    | (PendingConfirmation, Unsubscribed)
+                    | (Confirmed, Unsubscribed)
+                    | (Unsubscribed, PendingConfirmation)
+                    | (PendingConfirmation | Confirmed | Unsubscribed, Bounced)
+                    | (_, Complained)
+            );
+        if is_legal {
+            Ok(next)
+        } else {
+            Err(IllegalTransition {
+                from: self,
+                to: next,
+            })
+        }
+    }
+}
+
+impl fmt::Display for SubscriptionStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for SubscriptionStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending_confirmation" => Ok(SubscriptionStatus::PendingConfirmation),
+            "confirmed" => Ok(SubscriptionStatus::Confirmed),
+            "unsubscribed" => Ok(SubscriptionStatus::Unsubscribed),
+            "bounced" => Ok(SubscriptionStatus::Bounced),
+            "complained" => Ok(SubscriptionStatus::Complained),
+            other => Err(format!("{} is not a valid subscription status.", other)),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+#[error("A subscription cannot go from `{from}` to `{to}`.")]
+pub struct IllegalTransition {
+    pub from: SubscriptionStatus,
+    pub to: SubscriptionStatus,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriptionStatus::{self, *};
+    use claim::{assert_err, assert_ok};
+
+    const ALL: [SubscriptionStatus; 5] = [
+        PendingConfirmation,
+        Confirmed,
+        Unsubscribed,
+        Bounced,
+        Complained,
+    ];
+
+    #[test]
+    fn statuses_round_trip_through_their_database_representation() {
+        for status in ALL {
+            assert_eq!(status.as_str().parse::<SubscriptionStatus>(), Ok(status));
+        }
+        assert_err!("CONFIRMED".parse::<SubscriptionStatus>());
+    }
+
+    #[test]
+    fn the_subscription_lifecycle_is_allowed() {
+        assert_ok!(PendingConfirmation.transition_to(Confirmed));
+        assert_ok!(Confirmed.transition_to(Unsubscribed));
+        assert_ok!(Unsubscribed.transition_to(PendingConfirmation));
+        assert_ok!(PendingConfirmation.transition_to(Unsubscribed));
+    }
+
+    #[test]
+    fn staying_in_the_same_status_is_allowed() {
+        for status in ALL {
+            assert_eq!(status.transition_to(status), Ok(status));
+        }
+    }
+
+    #[test]
+    fn delivery_failures_and_complaints_apply_to_every_subscriber() {
+        for status in [PendingConfirmation, Confirmed, Unsubscribed, Bounced] {
+            assert_ok!(status.transition_to(Bounced));
+            assert_ok!(status.transition_to(Complained));
+        }
+    }
+
+    #[test]
+    fn illegal_transitions_are_rejected() {
+        for (from, to) in [
+            (Unsubscribed, Confirmed),
+            (Confirmed, PendingConfirmation),
+            (Bounced, PendingConfirmation),
+            (Bounced, Confirmed),
+            (Bounced, Unsubscribed),
+            (Complained, PendingConfirmation),
+            (Complained, Bounced),
+        ] {
+            let e = assert_err!(from.transition_to(to));
+            assert_eq!((e.from, e.to), (from, to));
+        }
+    }
+}
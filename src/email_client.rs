@@ -1,14 +1,168 @@
 //! src/email_client.rs
 use crate::domain::SubscriberEmail;
-use reqwest::Client;
+use crate::routes::error_chain_fmt;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use reqwest::{Client, StatusCode};
 use secrecy::{ExposeSecret, Secret};
+use std::future::Future;
+use std::time::Duration;
 
+/// A backend able to deliver emails to our subscribers.
+///
+/// Emails sent to the members of a list carry an unsubscribe link. The
+/// bodies are expected to include it already (see `EmailTemplates`): where
+/// the transport allows it, implementations advertise it through the
+/// RFC 8058 `List-Unsubscribe` and `List-Unsubscribe-Post` headers too, so
+/// that mail clients can offer a one-click unsubscribe button.
+///
+/// Emails about the subscriber's account (e.g. confirming a new address)
+/// are not sent on behalf of a list, and have no unsubscribe link.
+#[async_trait::async_trait]
+pub trait EmailSender: Send + Sync {
+    /// Send an email to `recipient`.
+    async fn send_email(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        unsubscribe_link: Option<&str>,
+    ) -> Result<SendEmailResponse, EmailError>;
+
+    /// Send every email of `batch`, returning one outcome per email, in the
+    /// same order as `batch`.
+    ///
+    /// Emails are sent one at a time, unless the backend can hand several
+    /// of them over to the provider at once.
+    async fn send_batch(
+        &self,
+        batch: &[BatchEmail<'_>],
+    ) -> Vec<Result<SendEmailResponse, EmailError>> {
+        let mut outcomes = Vec::with_capacity(batch.len());
+        for email in batch {
+            let outcome = self
+                .send_email(
+                    email.recipient,
+                    email.subject,
+                    email.html_content,
+                    email.text_content,
+                    Some(email.unsubscribe_link),
+                )
+                .await;
+            outcomes.push(outcome);
+        }
+        outcomes
+    }
+}
+
+/// What the provider told us about an email it accepted for delivery.
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SendEmailResponse {
+    /// The provider's identifier for the email: it lets support look up
+    /// what happened to it on the provider's side.
+    #[serde(rename = "MessageID")]
+    pub message_id: String,
+    pub submitted_at: DateTime<Utc>,
+    /// `0` for accepted emails.
+    pub error_code: i64,
+}
+
+/// Why we failed to send an email.
+#[derive(thiserror::Error)]
+pub enum EmailError {
+    /// The failure might go away if we try again later: the provider being
+    /// unreachable, slow, overloaded or rate-limiting us.
+    #[error("Failed to send an email, it might work if we try again later.")]
+    Transient(#[source] anyhow::Error),
+    /// Trying again will not help: e.g. the provider rejected the email
+    /// as invalid.
+    #[error("Failed to send an email.")]
+    Permanent(#[source] anyhow::Error),
+}
+
+impl EmailError {
+    pub fn is_transient(&self) -> bool {
+        matches!(self, EmailError::Transient(_))
+    }
+}
+
+impl std::fmt::Debug for EmailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+/// How many times, and how often, an email client tries to send an email
+/// before giving up on transient failures.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    // Including the first one
+    pub max_attempts: u32,
+    // Doubles after every failed attempt
+    pub base_delay: Duration,
+    // A random delay, up to `max_jitter`, is added to each wait to avoid
+    // all retries hitting the provider at the same time.
+    pub max_jitter: Duration,
+}
+
+impl RetryPolicy {
+    /// A single attempt.
+    pub fn no_retries() -> Self {
+        Self {
+            max_attempts: 1,
+            base_delay: Duration::ZERO,
+            max_jitter: Duration::ZERO,
+        }
+    }
+
+    /// Call `f` until it succeeds, it fails with a permanent error or we
+    /// run out of attempts.
+    pub(crate) async fn retry<F, Fut, T>(&self, mut f: F) -> Result<T, EmailError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, EmailError>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f().await {
+                Err(e) if e.is_transient() && attempt < self.max_attempts => {
+                    let delay = self.delay(attempt);
+                    tracing::warn!(
+                        error.cause_chain = ?e,
+                        "Attempt {} to send an email failed. Retrying in {:?}.",
+                        attempt,
+                        delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                outcome => return outcome,
+            }
+        }
+    }
+
+    /// How long to wait after the `attempt`-th failed attempt.
+    fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)));
+        let max_jitter = self.max_jitter.as_millis() as u64;
+        let jitter = Duration::from_millis(rand::thread_rng().gen_range(0..=max_jitter));
+        backoff.saturating_add(jitter)
+    }
+}
+
+/// Sends emails through Postmark's HTTP API.
 pub struct EmailClient {
     http_client: Client,
     base_url: String,
     sender: SubscriberEmail,
     // We don't want to log this by accident
     authorization_token: Secret<String>,
+    retry_policy: RetryPolicy,
 }
 
 impl EmailClient {
@@ -18,6 +172,7 @@ impl EmailClient {
         authorization_token: Secret<String>,
         // New argument!
         timeout: std::time::Duration,
+        retry_policy: RetryPolicy,
     ) -> Self {
         let http_client = Client::builder().timeout(timeout).build().unwrap();
         Self {
@@ -25,16 +180,60 @@ impl EmailClient {
             base_url,
             sender,
             authorization_token,
+            retry_policy,
+        }
+    }
+
+    async fn try_post<B, R>(&self, url: &str, request_body: &B) -> Result<R, EmailError>
+    where
+        B: serde::Serialize,
+        R: serde::de::DeserializeOwned,
+    {
+        let response = self
+            .http_client
+            .post(url)
+            .header(
+                "X-Postmark-Server-Token",
+                self.authorization_token.expose_secret(),
+            )
+            .json(request_body)
+            .send()
+            // send is asynchronous, therefore we need to await the future it returns.
+            .await
+            // We did not get a response: Postmark is unreachable or too slow.
+            .map_err(|e| EmailError::Transient(e.into()))?;
+        let status = response.status();
+        if !status.is_success() {
+            // Postmark explains what went wrong in the body, along with
+            // its own `ErrorCode`.
+            let body = response.text().await.unwrap_or_default();
+            let e = anyhow::anyhow!("Postmark responded with {}: {}", status, body);
+            return if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+                Err(EmailError::Transient(e))
+            } else {
+                // Validation errors: the same request would fail again.
+                Err(EmailError::Permanent(e))
+            };
         }
+        // The email has been accepted: trying again would send it twice.
+        response
+            .json()
+            .await
+            .context("Failed to parse the response of Postmark.")
+            .map_err(EmailError::Permanent)
     }
+}
 
-    pub async fn send_email(
+#[async_trait::async_trait]
+impl EmailSender for EmailClient {
+    async fn send_email(
         &self,
-        recipient: SubscriberEmail,
+        recipient: &SubscriberEmail,
         subject: &str,
         html_content: &str,
         text_content: &str,
-    ) -> Result<(), reqwest::Error> {
+        unsubscribe_link: Option<&str>,
+    ) -> Result<SendEmailResponse, EmailError> {
         // **To do**:
         // You can do better using `reqwest::Url::join` if you change
         // `base_url`'s type from `String` to `reqwest::Url`.
@@ -46,23 +245,139 @@ impl EmailClient {
             subject,
             html_body: html_content,
             text_body: text_content,
+            headers: unsubscribe_headers(unsubscribe_link),
         };
 
-        // Builder
-        self.http_client
-            .post(&url)
-            .header(
-                "X-Postmark-Server-Token",
-                self.authorization_token.expose_secret(),
-            )
-            .json(&request_body)
-            .send()
-            // send is asynchronous, therefore we need to await the future it returns.
-            .await?
-            // `reqwest::Response` `error_for_status`
-            // "Turn a response into an error if the server returned an error."
-            .error_for_status()?;
-        Ok(())
+        self.retry_policy
+            .retry(|| self.try_post(&url, &request_body))
+            .await
+    }
+
+    /// Send the emails in `batch` through Postmark's batch endpoint, in
+    /// chunks of at most `MAX_BATCH_SIZE` emails.
+    ///
+    /// Every email gets its own outcome, in the same order as `batch`:
+    /// Postmark can reject some of the emails of a chunk while accepting
+    /// the others.
+    #[tracing::instrument(name = "Send a batch of emails", skip_all, fields(batch_size = batch.len()))]
+    async fn send_batch(
+        &self,
+        batch: &[BatchEmail<'_>],
+    ) -> Vec<Result<SendEmailResponse, EmailError>> {
+        let url = format!("{}/email/batch", self.base_url);
+        let mut outcomes = Vec::with_capacity(batch.len());
+        for chunk in batch.chunks(MAX_BATCH_SIZE) {
+            let request_body: Vec<_> = chunk
+                .iter()
+                .map(|email| SendEmailRequest {
+                    from: self.sender.as_ref(),
+                    to: email.recipient.as_ref(),
+                    subject: email.subject,
+                    html_body: email.html_content,
+                    text_body: email.text_content,
+                    headers: unsubscribe_headers(Some(email.unsubscribe_link)),
+                })
+                .collect();
+            let response = self
+                .retry_policy
+                .retry(|| self.try_post::<_, Vec<BatchEntryResponse>>(&url, &request_body))
+                .await;
+            match response {
+                Ok(entries) if entries.len() == chunk.len() => {
+                    outcomes.extend(entries.into_iter().map(BatchEntryResponse::into_outcome))
+                }
+                Ok(entries) => outcomes.extend(chunk.iter().map(|_| {
+                    Err(EmailError::Permanent(anyhow::anyhow!(
+                        "Postmark returned {} results for a batch of {} emails.",
+                        entries.len(),
+                        chunk.len()
+                    )))
+                })),
+                // The whole chunk failed: every email in it shares the same fate.
+                Err(e) => {
+                    let transient = e.is_transient();
+                    let message = format!("{:#}", anyhow::Error::from(e));
+                    outcomes.extend(chunk.iter().map(|_| {
+                        let e = anyhow::anyhow!("{}", message);
+                        Err(if transient {
+                            EmailError::Transient(e)
+                        } else {
+                            EmailError::Permanent(e)
+                        })
+                    }))
+                }
+            }
+        }
+        outcomes
+    }
+}
+
+/// Postmark accepts up to 500 emails per batch.
+pub const MAX_BATCH_SIZE: usize = 500;
+
+/// One of the emails sent by `EmailSender::send_batch`.
+pub struct BatchEmail<'a> {
+    pub recipient: &'a SubscriberEmail,
+    pub subject: &'a str,
+    pub html_content: &'a str,
+    pub text_content: &'a str,
+    pub unsubscribe_link: &'a str,
+}
+
+/// Postmark's verdict on one of the emails of a batch.
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct BatchEntryResponse {
+    #[serde(rename = "MessageID")]
+    message_id: Option<String>,
+    submitted_at: Option<DateTime<Utc>>,
+    error_code: i64,
+    message: String,
+}
+
+impl BatchEntryResponse {
+    fn into_outcome(self) -> Result<SendEmailResponse, EmailError> {
+        match self {
+            Self {
+                message_id: Some(message_id),
+                submitted_at: Some(submitted_at),
+                error_code: 0,
+                ..
+            } => Ok(SendEmailResponse {
+                message_id,
+                submitted_at,
+                error_code: 0,
+            }),
+            // Rejections of single emails of a batch are validation errors
+            // (e.g. an invalid or inactive recipient).
+            Self {
+                error_code,
+                message,
+                ..
+            } => Err(EmailError::Permanent(anyhow::anyhow!(
+                "Postmark rejected the email (error code {}): {}",
+                error_code,
+                message
+            ))),
+        }
+    }
+}
+
+/// The RFC 8058 one-click unsubscribe headers, if there is a link to
+/// advertise.
+fn unsubscribe_headers(unsubscribe_link: Option<&str>) -> Vec<EmailHeader<'static>> {
+    match unsubscribe_link {
+        Some(unsubscribe_link) => vec![
+            EmailHeader {
+                name: "List-Unsubscribe",
+                value: format!("<{}>", unsubscribe_link),
+            },
+            EmailHeader {
+                name: "List-Unsubscribe-Post",
+                value: "List-Unsubscribe=One-Click".into(),
+            },
+        ],
+        None => Vec::new(),
     }
 }
 
@@ -74,12 +389,23 @@ struct SendEmailRequest<'a> {
     subject: &'a str,
     html_body: &'a str,
     text_body: &'a str,
+    headers: Vec<EmailHeader<'a>>,
+}
+
+/// A custom header, in the shape expected by Postmark's `Headers` array.
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct EmailHeader<'a> {
+    name: &'a str,
+    value: String,
 }
 
 #[cfg(test)]
 mod tests {
     use crate::domain::SubscriberEmail;
-    use crate::email_client::EmailClient;
+    use crate::email_client::{
+        BatchEmail, EmailClient, EmailError, EmailSender, RetryPolicy, SendEmailResponse,
+    };
     use claim::{assert_err, assert_ok};
     use fake::faker::internet::en::SafeEmail;
     use fake::faker::lorem::en::{Paragraph, Sentence};
@@ -98,6 +424,14 @@ mod tests {
         Paragraph(1..10).fake()
     }
 
+    /// Generate a random unsubscribe link
+    fn unsubscribe_link() -> String {
+        format!(
+            "https://my-api.com/subscriptions/unsubscribe?token={}",
+            Faker.fake::<String>()
+        )
+    }
+
     /// Generate a random subscriber email
     fn email() -> SubscriberEmail {
         SubscriberEmail::parse(SafeEmail().fake()).unwrap()
@@ -111,9 +445,42 @@ mod tests {
             Secret::new(Faker.fake()),
             // Much lower than 10s!
             std::time::Duration::from_millis(200),
+            retry_policy(),
         )
     }
 
+    /// What Postmark answers when it accepts an email.
+    fn postmark_response() -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "To": "recipient@example.com",
+            "SubmittedAt": "2022-05-30T12:34:56.1234567-04:00",
+            "MessageID": "b7bc2f4a-e38e-4336-af7d-e6c392c2f817",
+            "ErrorCode": 0,
+            "Message": "OK"
+        }))
+    }
+
+    /// Retry quickly, so that tests do not take forever.
+    fn retry_policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: std::time::Duration::from_millis(10),
+            max_jitter: std::time::Duration::from_millis(5),
+        }
+    }
+
+    async fn send_email(email_client: &EmailClient) -> Result<SendEmailResponse, EmailError> {
+        email_client
+            .send_email(
+                &email(),
+                &subject(),
+                &content(),
+                &content(),
+                Some(&unsubscribe_link()),
+            )
+            .await
+    }
+
     struct SendEmailBodyMatcher;
 
     impl wiremock::Match for SendEmailBodyMatcher {
@@ -129,6 +496,7 @@ mod tests {
                     && body.get("Subject").is_some()
                     && body.get("HtmlBody").is_some()
                     && body.get("TextBody").is_some()
+                    && has_unsubscribe_headers(&body)
             } else {
                 // If parsing failed, do not match the request
                 false
@@ -136,6 +504,20 @@ mod tests {
         }
     }
 
+    /// Check that the one-click unsubscribe headers (RFC 8058) are populated.
+    fn has_unsubscribe_headers(body: &serde_json::Value) -> bool {
+        let headers = match body.get("Headers").and_then(|h| h.as_array()) {
+            Some(headers) => headers,
+            None => return false,
+        };
+        let has_header = |name: &str| {
+            headers
+                .iter()
+                .any(|h| h.get("Name").and_then(|n| n.as_str()) == Some(name))
+        };
+        has_header("List-Unsubscribe") && has_header("List-Unsubscribe-Post")
+    }
+
     #[tokio::test]
     async fn send_email_sends_the_expected_request() {
         // Arrange
@@ -147,17 +529,47 @@ mod tests {
             .and(path("/email"))
             .and(method("POST"))
             .and(SendEmailBodyMatcher)
-            .respond_with(ResponseTemplate::new(200))
+            .respond_with(postmark_response())
             .expect(1)
             .mount(&mock_server)
             .await;
 
         // Act
         let _ = email_client
-            .send_email(email(), &subject(), &content(), &content())
+            .send_email(
+                &email(),
+                &subject(),
+                &content(),
+                &content(),
+                Some(&unsubscribe_link()),
+            )
+            .await;
+
+        // Assert
+    }
+
+    #[tokio::test]
+    async fn send_email_omits_the_unsubscribe_headers_without_a_link() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(postmark_response())
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content(), None)
             .await;
 
         // Assert
+        assert_ok!(outcome);
+        let request = &mock_server.received_requests().await.unwrap()[0];
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        assert!(!has_unsubscribe_headers(&body));
     }
 
     #[tokio::test]
@@ -172,22 +584,65 @@ mod tests {
         // We add the bare minimum needed to trigger the path we want
         // to test in `send_email`.
         Mock::given(any())
-            .respond_with(ResponseTemplate::new(200))
+            .respond_with(postmark_response())
             .expect(1)
             .mount(&mock_server)
             .await;
 
         // Act
-        let outcome = email_client
-            .send_email(email(), &subject(), &content(), &content())
-            .await;
+        let outcome = send_email(&email_client).await;
 
         // Assert
         assert_ok!(outcome);
     }
 
     #[tokio::test]
-    async fn send_email_fails_if_the_server_returns_500() {
+    async fn send_email_returns_the_message_id_assigned_by_postmark() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(postmark_response())
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let response = assert_ok!(send_email(&email_client).await);
+
+        // Assert
+        assert_eq!(response.message_id, "b7bc2f4a-e38e-4336-af7d-e6c392c2f817");
+        assert_eq!(response.error_code, 0);
+        assert_eq!(
+            response.submitted_at.to_rfc3339(),
+            "2022-05-30T16:34:56.123456700+00:00"
+        );
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_without_retrying_if_the_response_is_invalid() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        // The email has been accepted: sending it again would deliver it twice.
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = send_email(&email_client).await;
+
+        // Assert
+        let error = assert_err!(outcome);
+        assert!(!error.is_transient());
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_if_the_server_keeps_returning_500() {
         // Arrange
         let mock_server = MockServer::start().await;
         let email_client = email_client(mock_server.uri());
@@ -195,17 +650,88 @@ mod tests {
         Mock::given(any())
             // Not a 200 anymore!
             .respond_with(ResponseTemplate::new(500))
+            // Every attempt allowed by the retry policy
+            .expect(3)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = send_email(&email_client).await;
+
+        // Assert
+        let error = assert_err!(outcome);
+        assert!(error.is_transient());
+    }
+
+    #[tokio::test]
+    async fn send_email_retries_if_the_server_returns_500() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(postmark_response())
             .expect(1)
             .mount(&mock_server)
             .await;
 
         // Act
-        let outcome = email_client
-            .send_email(email(), &subject(), &content(), &content())
+        let outcome = send_email(&email_client).await;
+
+        // Assert
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_retries_if_the_server_returns_429() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(postmark_response())
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = send_email(&email_client).await;
+
+        // Assert
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_does_not_retry_if_the_server_rejects_the_email() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        // Postmark's answer to e.g. an invalid recipient
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(422))
+            .expect(1)
+            .mount(&mock_server)
             .await;
 
+        // Act
+        let outcome = send_email(&email_client).await;
+
         // Assert
-        assert_err!(outcome);
+        let error = assert_err!(outcome);
+        assert!(!error.is_transient());
     }
 
     #[tokio::test]
@@ -214,21 +740,182 @@ mod tests {
         let mock_server = MockServer::start().await;
         let email_client = email_client(mock_server.uri());
 
-        let response = ResponseTemplate::new(200)
+        let response = postmark_response()
             // 3 minutes!
             .set_delay(std::time::Duration::from_secs(180));
         Mock::given(any())
+            .respond_with(response)
+            // Timeouts are retried
+            .expect(3)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = send_email(&email_client).await;
+
+        // Assert
+        let error = assert_err!(outcome);
+        assert!(error.is_transient());
+    }
+
+    /// Accept every email of a batch, like Postmark would.
+    struct AcceptBatch;
+
+    impl wiremock::Respond for AcceptBatch {
+        fn respond(&self, request: &Request) -> ResponseTemplate {
+            let batch: Vec<serde_json::Value> = serde_json::from_slice(&request.body).unwrap();
+            let results: Vec<_> = batch
+                .iter()
+                .map(|email| {
+                    serde_json::json!({
+                        "To": email["To"],
+                        "SubmittedAt": "2022-05-30T12:34:56.1234567-04:00",
+                        "MessageID": uuid::Uuid::new_v4().to_string(),
+                        "ErrorCode": 0,
+                        "Message": "OK"
+                    })
+                })
+                .collect();
+            ResponseTemplate::new(200).set_body_json(results)
+        }
+    }
+
+    /// Match batches of the given size, where every email carries its own
+    /// unsubscribe headers.
+    struct BatchOfSize(usize);
+
+    impl wiremock::Match for BatchOfSize {
+        fn matches(&self, request: &Request) -> bool {
+            match serde_json::from_slice::<Vec<serde_json::Value>>(&request.body) {
+                Ok(batch) => batch.len() == self.0 && batch.iter().all(has_unsubscribe_headers),
+                Err(_) => false,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn send_batch_splits_large_batches_in_chunks_of_500() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let recipients: Vec<_> = (0..501).map(|_| email()).collect();
+        let (subject, content, link) = (subject(), content(), unsubscribe_link());
+        let batch: Vec<_> = recipients
+            .iter()
+            .map(|recipient| BatchEmail {
+                recipient,
+                subject: &subject,
+                html_content: &content,
+                text_content: &content,
+                unsubscribe_link: &link,
+            })
+            .collect();
+
+        Mock::given(path("/email/batch"))
+            .and(method("POST"))
+            .and(BatchOfSize(500))
+            .respond_with(AcceptBatch)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(path("/email/batch"))
+            .and(method("POST"))
+            .and(BatchOfSize(1))
+            .respond_with(AcceptBatch)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcomes = email_client.send_batch(&batch).await;
+
+        // Assert
+        assert_eq!(outcomes.len(), 501);
+        assert!(outcomes.iter().all(|outcome| outcome.is_ok()));
+    }
+
+    #[tokio::test]
+    async fn send_batch_reports_rejected_emails_individually() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let (accepted, rejected) = (email(), email());
+        let (subject, content, link) = (subject(), content(), unsubscribe_link());
+        let batch: Vec<_> = [&accepted, &rejected]
+            .into_iter()
+            .map(|recipient| BatchEmail {
+                recipient,
+                subject: &subject,
+                html_content: &content,
+                text_content: &content,
+                unsubscribe_link: &link,
+            })
+            .collect();
+
+        // Postmark answers with a 200 even if some emails are rejected.
+        let response = ResponseTemplate::new(200).set_body_json(serde_json::json!([
+            {
+                "To": accepted.as_ref(),
+                "SubmittedAt": "2022-05-30T12:34:56.1234567-04:00",
+                "MessageID": "b7bc2f4a-e38e-4336-af7d-e6c392c2f817",
+                "ErrorCode": 0,
+                "Message": "OK"
+            },
+            {
+                "ErrorCode": 406,
+                "Message": "You tried to send to a recipient that has been marked as inactive."
+            }
+        ]));
+        Mock::given(path("/email/batch"))
             .respond_with(response)
             .expect(1)
             .mount(&mock_server)
             .await;
 
         // Act
-        let outcome = email_client
-            .send_email(email(), &subject(), &content(), &content())
+        let mut outcomes = email_client.send_batch(&batch).await.into_iter();
+
+        // Assert
+        let response = assert_ok!(outcomes.next().unwrap());
+        assert_eq!(response.message_id, "b7bc2f4a-e38e-4336-af7d-e6c392c2f817");
+        let error = assert_err!(outcomes.next().unwrap());
+        assert!(!error.is_transient());
+        assert!(outcomes.next().is_none());
+    }
+
+    #[tokio::test]
+    async fn send_batch_fails_every_email_of_a_chunk_if_the_server_keeps_returning_500() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let recipients = [email(), email()];
+        let (subject, content, link) = (subject(), content(), unsubscribe_link());
+        let batch: Vec<_> = recipients
+            .iter()
+            .map(|recipient| BatchEmail {
+                recipient,
+                subject: &subject,
+                html_content: &content,
+                text_content: &content,
+                unsubscribe_link: &link,
+            })
+            .collect();
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            // Every attempt allowed by the retry policy
+            .expect(3)
+            .mount(&mock_server)
             .await;
 
+        // Act
+        let outcomes = email_client.send_batch(&batch).await;
+
         // Assert
-        assert_err!(outcome);
+        assert_eq!(outcomes.len(), 2);
+        for outcome in outcomes {
+            let error = assert_err!(outcome);
+            assert!(error.is_transient());
+        }
     }
 }
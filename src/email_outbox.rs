@@ -0,0 +1,300 @@
+//! src/email_outbox.rs
+use crate::domain::SubscriberEmail;
+use crate::email_client::{EmailSender, SendEmailResponse};
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{field::display, Span};
+use uuid::Uuid;
+
+// How long the worker waits before polling an empty outbox again.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+// How long a failed delivery waits before being attempted again.
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+// How many times we try to deliver an email before moving it to the
+// dead-letter state.
+pub const MAX_ATTEMPTS: i16 = 10;
+
+/// What an email in the outbox is about.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutboxEmailKind {
+    /// Asks a new subscriber to confirm their subscription.
+    Confirmation,
+    /// Asks a subscriber to confirm their new address.
+    EmailChange,
+    /// Asks a subscriber to confirm they want their data exported or erased.
+    DataRequest,
+}
+
+impl OutboxEmailKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutboxEmailKind::Confirmation => "confirmation",
+            OutboxEmailKind::EmailChange => "email_change",
+            OutboxEmailKind::DataRequest => "data_request",
+        }
+    }
+}
+
+/// An email waiting in the outbox to be delivered.
+pub struct OutboxEmail<'a> {
+    pub kind: OutboxEmailKind,
+    pub recipient: &'a SubscriberEmail,
+    pub subject: &'a str,
+    pub html_body: &'a str,
+    pub text_body: &'a str,
+    /// `None` for emails about the subscriber's account, which are not
+    /// sent on behalf of a list.
+    pub unsubscribe_link: Option<&'a str>,
+}
+
+/// Write an email in the outbox.
+///
+/// It is going to be picked up by the outbox worker only once `transaction`
+/// has been committed: if the state change that triggered the email is
+/// rolled back, the email is never sent.
+#[tracing::instrument(name = "Enqueue an email in the outbox", skip(transaction, email))]
+pub async fn enqueue_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    email: OutboxEmail<'_>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"INSERT INTO email_outbox (
+            id,
+            subscriber_id,
+            recipient_email,
+            subject,
+            html_body,
+            text_body,
+            unsubscribe_link,
+            kind,
+            created_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)"#,
+        Uuid::new_v4(),
+        subscriber_id,
+        email.recipient.as_ref(),
+        email.subject,
+        email.html_body,
+        email.text_body,
+        email.unsubscribe_link,
+        email.kind.as_str(),
+        Utc::now()
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+/// Deliver emails from the outbox until `shutdown` is signalled.
+pub async fn run_worker_until_stopped(
+    pool: PgPool,
+    email_client: Arc<dyn EmailSender>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<(), anyhow::Error> {
+    while !*shutdown.borrow() {
+        let wait = match try_execute_task(&pool, email_client.as_ref()).await {
+            Ok(ExecutionOutcome::TaskCompleted) => continue,
+            Ok(ExecutionOutcome::EmptyQueue) => POLL_INTERVAL,
+            // Most likely the database is unreachable: back off for a bit.
+            Err(_) => Duration::from_secs(1),
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = shutdown.changed() => {}
+        }
+    }
+    Ok(())
+}
+
+pub enum ExecutionOutcome {
+    TaskCompleted,
+    EmptyQueue,
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(
+        outbox_email_id=tracing::field::Empty,
+        subscriber_email=tracing::field::Empty,
+        message_id=tracing::field::Empty
+    ),
+    err
+)]
+pub async fn try_execute_task(
+    pool: &PgPool,
+    email_client: &dyn EmailSender,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    let mut transaction = pool.begin().await?;
+    let task = match dequeue_task(&mut transaction).await? {
+        Some(task) => task,
+        None => return Ok(ExecutionOutcome::EmptyQueue),
+    };
+    Span::current()
+        .record("outbox_email_id", display(task.id))
+        .record("subscriber_email", display(&task.recipient_email));
+    match SubscriberEmail::parse(task.recipient_email.clone()) {
+        Ok(recipient) => match email_client
+            .send_email(
+                &recipient,
+                &task.subject,
+                &task.html_body,
+                &task.text_body,
+                task.unsubscribe_link.as_deref(),
+            )
+            .await
+        {
+            Ok(response) => {
+                Span::current().record("message_id", display(&response.message_id));
+                if task.kind == OutboxEmailKind::Confirmation.as_str() {
+                    record_message_id(&mut transaction, task.subscriber_id, &response).await?;
+                }
+            }
+            Err(e) => {
+                let attempts = task.n_retries + 1;
+                if e.is_transient() && attempts < MAX_ATTEMPTS {
+                    tracing::warn!(
+                        error.cause_chain = ?e,
+                        error.message = %e,
+                        "Failed to deliver an email from the outbox. \
+                        It will be retried later.",
+                    );
+                    schedule_retry(&mut transaction, task.id, &e.to_string()).await?;
+                } else {
+                    tracing::error!(
+                        error.cause_chain = ?e,
+                        error.message = %e,
+                        "Failed to deliver an email from the outbox. \
+                        Giving up after {} attempt(s).",
+                        attempts
+                    );
+                    move_to_dead_letter(&mut transaction, task.id, &e.to_string()).await?;
+                }
+                transaction.commit().await?;
+                return Ok(ExecutionOutcome::TaskCompleted);
+            }
+        },
+        // Trying again would fail the same way.
+        Err(e) => {
+            tracing::error!(
+                error.message = %e,
+                "Failed to deliver an email from the outbox. \
+                The recipient address is invalid.",
+            );
+            move_to_dead_letter(&mut transaction, task.id, &e).await?;
+            transaction.commit().await?;
+            return Ok(ExecutionOutcome::TaskCompleted);
+        }
+    }
+    delete_task(&mut transaction, task.id).await?;
+    transaction.commit().await?;
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+struct OutboxTask {
+    id: Uuid,
+    subscriber_id: Uuid,
+    recipient_email: String,
+    subject: String,
+    html_body: String,
+    text_body: String,
+    unsubscribe_link: Option<String>,
+    kind: String,
+    n_retries: i16,
+}
+
+#[tracing::instrument(skip_all)]
+async fn dequeue_task(
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<Option<OutboxTask>, sqlx::Error> {
+    // `SKIP LOCKED` lets several workers (or application instances) drain
+    // the outbox concurrently without stepping on each other's toes.
+    sqlx::query_as!(
+        OutboxTask,
+        r#"SELECT
+            id, subscriber_id, recipient_email, subject, html_body, text_body, unsubscribe_link,
+            kind, n_retries
+        FROM email_outbox
+        WHERE status = 'pending' AND execute_after <= now()
+        ORDER BY created_at
+        FOR UPDATE
+        SKIP LOCKED
+        LIMIT 1"#,
+    )
+    .fetch_optional(transaction)
+    .await
+}
+
+#[tracing::instrument(skip_all)]
+async fn delete_task(
+    transaction: &mut Transaction<'_, Postgres>,
+    id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(r#"DELETE FROM email_outbox WHERE id = $1"#, id)
+        .execute(transaction)
+        .await?;
+    Ok(())
+}
+
+/// Keep track of the provider message carrying the latest confirmation
+/// email sent to the subscriber.
+#[tracing::instrument(skip_all)]
+async fn record_message_id(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    response: &SendEmailResponse,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE subscriptions
+        SET confirmation_email_message_id = $2, confirmation_email_submitted_at = $3
+        WHERE id = $1"#,
+        subscriber_id,
+        response.message_id,
+        response.submitted_at
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn schedule_retry(
+    transaction: &mut Transaction<'_, Postgres>,
+    id: Uuid,
+    error: &str,
+) -> Result<(), sqlx::Error> {
+    let execute_after = Utc::now()
+        + chrono::Duration::from_std(RETRY_DELAY).expect("The retry delay is out of range.");
+    sqlx::query!(
+        r#"UPDATE email_outbox
+        SET n_retries = n_retries + 1, execute_after = $2, last_error = $3
+        WHERE id = $1"#,
+        id,
+        execute_after,
+        error
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn move_to_dead_letter(
+    transaction: &mut Transaction<'_, Postgres>,
+    id: Uuid,
+    error: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE email_outbox
+        SET status = 'dead_letter', n_retries = n_retries + 1, last_error = $2
+        WHERE id = $1"#,
+        id,
+        error
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,556 @@
+//! src/email_templates.rs
+use crate::domain::SubscriberLocale;
+use anyhow::Context;
+use minijinja::value::{Kwargs, Value};
+use minijinja::{context, escape_formatter, AutoEscape, Environment, State, UndefinedBehavior};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The built-in templates, used for every template the configured directory
+/// does not override.
+const DEFAULT_TEMPLATES: &[(&str, &str)] = &[
+    (
+        "confirmation.subject.txt",
+        include_str!("../templates/emails/confirmation.subject.txt"),
+    ),
+    (
+        "confirmation.html",
+        include_str!("../templates/emails/confirmation.html"),
+    ),
+    (
+        "confirmation.txt",
+        include_str!("../templates/emails/confirmation.txt"),
+    ),
+    (
+        "email_change.subject.txt",
+        include_str!("../templates/emails/email_change.subject.txt"),
+    ),
+    (
+        "email_change.html",
+        include_str!("../templates/emails/email_change.html"),
+    ),
+    (
+        "email_change.txt",
+        include_str!("../templates/emails/email_change.txt"),
+    ),
+    (
+        "data_request.subject.txt",
+        include_str!("../templates/emails/data_request.subject.txt"),
+    ),
+    (
+        "data_request.html",
+        include_str!("../templates/emails/data_request.html"),
+    ),
+    (
+        "data_request.txt",
+        include_str!("../templates/emails/data_request.txt"),
+    ),
+    (
+        "newsletter.subject.txt",
+        include_str!("../templates/emails/newsletter.subject.txt"),
+    ),
+    (
+        "newsletter.html",
+        include_str!("../templates/emails/newsletter.html"),
+    ),
+    (
+        "newsletter.txt",
+        include_str!("../templates/emails/newsletter.txt"),
+    ),
+];
+
+/// The built-in message catalogs, one per locale.
+///
+/// The configured directory can override them, or add new locales, with
+/// `messages/{locale}.json` files.
+const DEFAULT_CATALOGS: &[(&str, &str)] = &[
+    ("en", include_str!("../templates/emails/messages/en.json")),
+    ("fr", include_str!("../templates/emails/messages/fr.json")),
+];
+
+/// The translations of the messages used by the templates, by locale.
+type Catalogs = HashMap<String, HashMap<String, String>>;
+
+/// An email we send, rendered from three templates: `{NAME}.subject.txt`,
+/// `{NAME}.html` and `{NAME}.txt`.
+///
+/// Its fields are the variables available to the templates, along with
+/// `locale`.
+pub trait EmailTemplate: serde::Serialize {
+    const NAME: &'static str;
+
+    /// A context populating every variable, used to check the templates at
+    /// startup.
+    fn example() -> Self;
+}
+
+/// The email asking new subscribers to confirm their subscription.
+#[derive(serde::Serialize)]
+pub struct ConfirmationEmail<'a> {
+    pub name: &'a str,
+    /// The name of the list the subscriber signed up for.
+    pub list_name: &'a str,
+    pub confirmation_link: &'a str,
+    pub unsubscribe_link: &'a str,
+    pub preferences_link: &'a str,
+    pub base_url: &'a str,
+}
+
+impl EmailTemplate for ConfirmationEmail<'_> {
+    const NAME: &'static str = "confirmation";
+
+    fn example() -> Self {
+        Self {
+            name: "Ursula Le Guin",
+            list_name: "Our newsletter",
+            confirmation_link: "https://example.com/subscriptions/confirm?subscription_token=a",
+            unsubscribe_link: "https://example.com/subscriptions/unsubscribe?token=a",
+            preferences_link:
+                "https://example.com/subscriptions/preferences?subscriber_id=a&version=0&tag=b",
+            base_url: "https://example.com",
+        }
+    }
+}
+
+/// The email asking subscribers to confirm the address they want to
+/// receive our emails at from now on, sent to that new address.
+#[derive(serde::Serialize)]
+pub struct EmailChangeEmail<'a> {
+    pub name: &'a str,
+    pub new_email: &'a str,
+    pub confirmation_link: &'a str,
+    pub base_url: &'a str,
+}
+
+impl EmailTemplate for EmailChangeEmail<'_> {
+    const NAME: &'static str = "email_change";
+
+    fn example() -> Self {
+        Self {
+            name: "Ursula Le Guin",
+            new_email: "ursula@example.com",
+            confirmation_link: "https://example.com/subscriptions/confirm-email-change?token=a",
+            base_url: "https://example.com",
+        }
+    }
+}
+
+/// The email asking subscribers to confirm they want to download, or
+/// erase, the data we hold about them.
+#[derive(serde::Serialize)]
+pub struct DataRequestEmail<'a> {
+    pub name: &'a str,
+    /// `export` or `erase`.
+    pub kind: &'a str,
+    pub confirmation_link: &'a str,
+    pub base_url: &'a str,
+}
+
+impl EmailTemplate for DataRequestEmail<'_> {
+    const NAME: &'static str = "data_request";
+
+    fn example() -> Self {
+        Self {
+            name: "Ursula Le Guin",
+            kind: "erase",
+            confirmation_link: "https://example.com/subscriptions/data-request?token=a",
+            base_url: "https://example.com",
+        }
+    }
+}
+
+/// A newsletter issue, as delivered to one of our subscribers.
+#[derive(serde::Serialize)]
+pub struct NewsletterEmail<'a> {
+    pub title: &'a str,
+    /// Trusted HTML, written by one of our authors.
+    pub html_content: &'a str,
+    pub text_content: &'a str,
+    pub unsubscribe_link: &'a str,
+    pub preferences_link: &'a str,
+    pub base_url: &'a str,
+}
+
+impl EmailTemplate for NewsletterEmail<'_> {
+    const NAME: &'static str = "newsletter";
+
+    fn example() -> Self {
+        Self {
+            title: "Issue #1",
+            html_content: "<p>Newsletter body as HTML</p>",
+            text_content: "Newsletter body as plain text",
+            unsubscribe_link: "https://example.com/subscriptions/unsubscribe?token=a",
+            preferences_link:
+                "https://example.com/subscriptions/preferences?subscriber_id=a&version=0&tag=b",
+            base_url: "https://example.com",
+        }
+    }
+}
+
+pub struct RenderedEmail {
+    pub subject: String,
+    pub html_body: String,
+    pub text_body: String,
+}
+
+/// The templates of the emails we send.
+///
+/// Values interpolated in `.html` templates are HTML-escaped, unless
+/// marked as `safe`. Referencing a variable that does not exist is an
+/// error rather than an empty string.
+///
+/// Templates look up the text to display in the language of the recipient
+/// with `t("key")`: `{placeholders}` in the message are replaced by the
+/// keyword arguments of the same name - e.g. `t("greeting", name=name)`.
+/// Messages missing from the catalog of a locale are taken from the
+/// catalog of its language, then from the default one.
+#[derive(Debug)]
+pub struct EmailTemplates {
+    environment: Environment<'static>,
+    catalogs: Arc<Catalogs>,
+}
+
+impl EmailTemplates {
+    /// Load the templates, preferring the ones in `directory` over the
+    /// built-in ones, and check that every one of them renders in every
+    /// locale.
+    pub fn load(directory: Option<&Path>) -> Result<Self, anyhow::Error> {
+        let catalogs = Arc::new(load_catalogs(directory)?);
+        if !catalogs.contains_key(SubscriberLocale::DEFAULT) {
+            anyhow::bail!(
+                "There is no message catalog for the default locale, `{}`",
+                SubscriberLocale::DEFAULT
+            );
+        }
+        let mut environment = Environment::new();
+        environment.set_undefined_behavior(UndefinedBehavior::Strict);
+        environment.set_formatter(|out, state, value| {
+            // The default escaping turns every `/` into `&#x2f;`, mangling
+            // the links in our emails.
+            if state.auto_escape() == AutoEscape::Html && !value.is_safe() {
+                out.write_str(&htmlescape::encode_minimal(&value.to_string()))
+                    .map_err(minijinja::Error::from)
+            } else {
+                escape_formatter(out, state, value)
+            }
+        });
+        let translations = Arc::clone(&catalogs);
+        environment.add_function(
+            "t",
+            move |state: &State, key: &str, placeholders: Kwargs| {
+                translate(&translations, state, key, placeholders)
+            },
+        );
+        for (name, default) in DEFAULT_TEMPLATES {
+            let source = match directory.map(|directory| directory.join(name)) {
+                Some(path) if path.exists() => std::fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?,
+                _ => default.to_string(),
+            };
+            environment
+                .add_template_owned(*name, source)
+                .with_context(|| format!("The `{}` email template is invalid", name))?;
+        }
+        let templates = Self {
+            environment,
+            catalogs,
+        };
+        for locale in templates.catalogs.keys() {
+            let locale = SubscriberLocale::parse(locale.clone()).map_err(anyhow::Error::msg)?;
+            templates.check::<ConfirmationEmail>(&locale)?;
+            templates.check::<EmailChangeEmail>(&locale)?;
+            templates.check::<DataRequestEmail>(&locale)?;
+            templates.check::<NewsletterEmail>(&locale)?;
+        }
+        Ok(templates)
+    }
+
+    /// Whether we have a message catalog for `locale`, or for its language.
+    pub fn supports(&self, locale: &SubscriberLocale) -> bool {
+        self.catalogs.contains_key(locale.as_ref()) || self.catalogs.contains_key(locale.language())
+    }
+
+    /// Render the email for a recipient speaking `locale`.
+    pub fn render<T: EmailTemplate>(
+        &self,
+        locale: &SubscriberLocale,
+        context: &T,
+    ) -> Result<RenderedEmail, anyhow::Error> {
+        let context = context! { locale => locale.as_ref(), ..Value::from_serialize(context) };
+        let render = |extension: &str| {
+            let name = format!("{}.{}", T::NAME, extension);
+            self.environment
+                .get_template(&name)
+                .and_then(|template| template.render(&context))
+                .with_context(|| format!("Failed to render the `{}` email template", name))
+        };
+        Ok(RenderedEmail {
+            // Subjects are a single line.
+            subject: render("subject.txt")?.trim().to_string(),
+            html_body: render("html")?,
+            text_body: render("txt")?,
+        })
+    }
+
+    fn check<T: EmailTemplate>(&self, locale: &SubscriberLocale) -> Result<(), anyhow::Error> {
+        self.render(locale, &T::example()).map(|_| ())
+    }
+}
+
+fn load_catalogs(directory: Option<&Path>) -> Result<Catalogs, anyhow::Error> {
+    let mut sources: HashMap<String, String> = DEFAULT_CATALOGS
+        .iter()
+        .map(|(locale, source)| (locale.to_string(), source.to_string()))
+        .collect();
+    let messages_directory = directory.map(|directory| directory.join("messages"));
+    if let Some(messages_directory) = messages_directory.filter(|d| d.is_dir()) {
+        for entry in std::fs::read_dir(&messages_directory)
+            .with_context(|| format!("Failed to read {}", messages_directory.display()))?
+        {
+            let path = entry?.path();
+            let locale = match (path.file_stem(), path.extension()) {
+                (Some(locale), Some(extension)) if extension == "json" => locale,
+                _ => continue,
+            };
+            let locale = SubscriberLocale::parse(locale.to_string_lossy().into_owned())
+                .map_err(anyhow::Error::msg)
+                .with_context(|| format!("Unexpected message catalog: {}", path.display()))?;
+            let source = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            sources.insert(locale.as_ref().to_owned(), source);
+        }
+    }
+    sources
+        .into_iter()
+        .map(|(locale, source)| {
+            let catalog = serde_json::from_str(&source).with_context(|| {
+                format!("The message catalog of the `{}` locale is invalid", locale)
+            })?;
+            Ok((locale, catalog))
+        })
+        .collect()
+}
+
+/// The `t` function available to the templates.
+fn translate(
+    catalogs: &Catalogs,
+    state: &State,
+    key: &str,
+    placeholders: Kwargs,
+) -> Result<String, minijinja::Error> {
+    let locale = state
+        .lookup("locale")
+        .and_then(|locale| locale.as_str().map(str::to_owned))
+        .and_then(|locale| SubscriberLocale::parse(locale).ok())
+        .unwrap_or_default();
+    let message = [
+        locale.as_ref(),
+        locale.language(),
+        SubscriberLocale::DEFAULT,
+    ]
+    .iter()
+    .find_map(|locale| catalogs.get(*locale).and_then(|catalog| catalog.get(key)))
+    .ok_or_else(|| {
+        minijinja::Error::new(
+            minijinja::ErrorKind::InvalidOperation,
+            format!("There is no `{}` message in the catalogs", key),
+        )
+    })?;
+    let mut message = message.clone();
+    for name in placeholders.args() {
+        let value: Value = placeholders.get(name)?;
+        message = message.replace(&format!("{{{}}}", name), &value.to_string());
+    }
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriberLocale;
+    use crate::email_templates::{
+        ConfirmationEmail, DataRequestEmail, EmailChangeEmail, EmailTemplate, EmailTemplates,
+        NewsletterEmail,
+    };
+    use claim::{assert_err, assert_ok};
+    use std::path::PathBuf;
+    use uuid::Uuid;
+
+    /// A directory holding the given templates.
+    fn template_directory(templates: &[(&str, &str)]) -> PathBuf {
+        let directory = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir_all(directory.join("messages")).unwrap();
+        for (name, source) in templates {
+            std::fs::write(directory.join(name), source).unwrap();
+        }
+        directory
+    }
+
+    fn locale(locale: &str) -> SubscriberLocale {
+        SubscriberLocale::parse(locale.into()).unwrap()
+    }
+
+    #[test]
+    fn the_built_in_templates_render() {
+        let templates = assert_ok!(EmailTemplates::load(None));
+        let context = ConfirmationEmail::example();
+
+        let email = assert_ok!(templates.render(&SubscriberLocale::default(), &context));
+
+        assert_eq!(email.subject, "Welcome!");
+        assert!(email.text_body.contains(context.confirmation_link));
+        assert!(email
+            .html_body
+            .contains(&format!("href=\"{}\"", context.confirmation_link)));
+        assert!(email.text_body.contains(context.unsubscribe_link));
+        assert!(email
+            .html_body
+            .contains(&format!("href=\"{}\"", context.unsubscribe_link)));
+        assert!(email.text_body.contains(context.preferences_link));
+    }
+
+    #[test]
+    fn email_change_confirmations_have_no_unsubscribe_link() {
+        let templates = assert_ok!(EmailTemplates::load(None));
+        let context = EmailChangeEmail::example();
+
+        let email = assert_ok!(templates.render(&SubscriberLocale::default(), &context));
+
+        assert!(email.text_body.contains(context.confirmation_link));
+        assert!(email.text_body.contains(context.new_email));
+        assert!(!email.text_body.contains("Unsubscribe"));
+    }
+
+    #[test]
+    fn data_requests_describe_what_the_link_does() {
+        let templates = assert_ok!(EmailTemplates::load(None));
+        let export = DataRequestEmail {
+            kind: "export",
+            ..DataRequestEmail::example()
+        };
+        let erase = DataRequestEmail::example();
+
+        let export = assert_ok!(templates.render(&SubscriberLocale::default(), &export));
+        let erase = assert_ok!(templates.render(&SubscriberLocale::default(), &erase));
+
+        assert_eq!(export.subject, "Download your data");
+        assert_eq!(erase.subject, "Confirm the erasure of your data");
+        assert!(erase
+            .text_body
+            .contains(DataRequestEmail::example().confirmation_link));
+        assert!(!erase.text_body.contains("Unsubscribe"));
+    }
+
+    #[test]
+    fn values_are_escaped_in_html_templates_only() {
+        let templates = EmailTemplates::load(None).unwrap();
+        let context = ConfirmationEmail {
+            name: "<script>alert('Hi')</script>",
+            ..ConfirmationEmail::example()
+        };
+
+        let email = templates
+            .render(&SubscriberLocale::default(), &context)
+            .unwrap();
+
+        assert!(email
+            .html_body
+            .contains("&lt;script&gt;alert(&#x27;Hi&#x27;)&lt;/script&gt;"));
+        assert!(email.text_body.contains(context.name));
+    }
+
+    #[test]
+    fn newsletter_content_is_not_escaped() {
+        let templates = EmailTemplates::load(None).unwrap();
+        let context = NewsletterEmail::example();
+
+        let email = templates
+            .render(&SubscriberLocale::default(), &context)
+            .unwrap();
+
+        assert_eq!(email.subject, context.title);
+        assert!(email.html_body.starts_with(context.html_content));
+        assert!(email.text_body.starts_with(context.text_content));
+    }
+
+    #[test]
+    fn emails_are_rendered_in_the_locale_of_the_recipient() {
+        let templates = EmailTemplates::load(None).unwrap();
+
+        let email = templates
+            .render(&locale("fr-CA"), &ConfirmationEmail::example())
+            .unwrap();
+
+        assert_eq!(email.subject, "Bienvenue !");
+        assert!(email.text_body.contains("Se désinscrire"));
+    }
+
+    #[test]
+    fn unsupported_locales_fall_back_to_the_default_one() {
+        let templates = EmailTemplates::load(None).unwrap();
+        assert!(!templates.supports(&locale("de")));
+
+        let email = templates
+            .render(&locale("de"), &ConfirmationEmail::example())
+            .unwrap();
+
+        assert_eq!(email.subject, "Welcome!");
+    }
+
+    #[test]
+    fn catalogs_in_the_directory_add_locales_and_fall_back_to_the_default_one() {
+        let directory = template_directory(&[(
+            "messages/de.json",
+            r#"{ "confirmation.subject": "Willkommen!" }"#,
+        )]);
+
+        let templates = assert_ok!(EmailTemplates::load(Some(&directory)));
+        let email = templates
+            .render(&locale("de"), &ConfirmationEmail::example())
+            .unwrap();
+
+        assert!(templates.supports(&locale("de-AT")));
+        assert_eq!(email.subject, "Willkommen!");
+        assert!(email.text_body.contains("Unsubscribe"));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn templates_in_the_directory_override_the_built_in_ones() {
+        let directory = template_directory(&[("confirmation.subject.txt", "Hey {{ name }}")]);
+
+        let templates = assert_ok!(EmailTemplates::load(Some(&directory)));
+        let email = templates
+            .render(&SubscriberLocale::default(), &ConfirmationEmail::example())
+            .unwrap();
+
+        assert_eq!(email.subject, "Hey Ursula Le Guin");
+        // The other templates are still the built-in ones.
+        assert!(email.text_body.starts_with("Welcome, Ursula Le Guin!"));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn templates_with_a_syntax_error_are_rejected() {
+        let directory = template_directory(&[("confirmation.html", "Hey {{ name ")]);
+
+        assert_err!(EmailTemplates::load(Some(&directory)));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn templates_using_unknown_variables_are_rejected() {
+        let directory = template_directory(&[("confirmation.txt", "Hey {{ nickname }}")]);
+
+        assert_err!(EmailTemplates::load(Some(&directory)));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn templates_using_unknown_messages_are_rejected() {
+        let directory = template_directory(&[("confirmation.txt", r#"{{ t("nonsense") }}"#)]);
+
+        assert_err!(EmailTemplates::load(Some(&directory)));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,167 @@
+//! src/file_email_client.rs
+use crate::domain::SubscriberEmail;
+use crate::email_client::{EmailError, EmailSender, SendEmailResponse};
+use anyhow::Context;
+use chrono::Utc;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// Writes emails to disk - or to stdout - instead of delivering them.
+///
+/// Meant for local development: no email provider account required.
+pub struct FileEmailClient {
+    // Emails are printed to stdout when `None`.
+    directory: Option<PathBuf>,
+    sender: SubscriberEmail,
+}
+
+impl FileEmailClient {
+    pub fn new(directory: Option<PathBuf>, sender: SubscriberEmail) -> Self {
+        Self { directory, sender }
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailSender for FileEmailClient {
+    async fn send_email(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        unsubscribe_link: Option<&str>,
+    ) -> Result<SendEmailResponse, EmailError> {
+        let message_id = Uuid::new_v4();
+        let unsubscribe_headers = unsubscribe_link
+            .map(|link| {
+                format!(
+                    "List-Unsubscribe: <{}>\n\
+                    List-Unsubscribe-Post: List-Unsubscribe=One-Click\n",
+                    link
+                )
+            })
+            .unwrap_or_default();
+        let email = format!(
+            "Message-ID: <{}@localhost>\n\
+            From: {}\n\
+            To: {}\n\
+            Subject: {}\n\
+            {}\
+            \n\
+            ---------- text/plain ----------\n\
+            {}\n\
+            ---------- text/html -----------\n\
+            {}\n",
+            message_id,
+            self.sender.as_ref(),
+            recipient.as_ref(),
+            subject,
+            unsubscribe_headers,
+            text_content,
+            html_content
+        );
+        match &self.directory {
+            Some(directory) => {
+                tokio::fs::create_dir_all(directory)
+                    .await
+                    .context("Failed to create the email output directory")
+                    .map_err(EmailError::Transient)?;
+                // Timestamp first, so that emails are listed in the order
+                // they were sent.
+                let file_name = format!(
+                    "{}-{}.eml.txt",
+                    Utc::now().format("%Y%m%dT%H%M%S%.6f"),
+                    message_id
+                );
+                tokio::fs::write(directory.join(file_name), email)
+                    .await
+                    .context("Failed to write the email to disk")
+                    .map_err(EmailError::Transient)?;
+            }
+            None => println!("{}", email),
+        }
+        Ok(SendEmailResponse {
+            message_id: message_id.to_string(),
+            submitted_at: Utc::now(),
+            error_code: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{BatchEmail, EmailSender};
+    use crate::file_email_client::FileEmailClient;
+    use claim::assert_ok;
+    use fake::faker::internet::en::SafeEmail;
+    use fake::Fake;
+    use uuid::Uuid;
+
+    /// Generate a random subscriber email
+    fn email() -> SubscriberEmail {
+        SubscriberEmail::parse(SafeEmail().fake()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn send_email_writes_the_email_in_the_output_directory() {
+        // Arrange
+        let directory = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let email_client = FileEmailClient::new(Some(directory.clone()), email());
+        let recipient = email();
+
+        // Act
+        let outcome = email_client
+            .send_email(
+                &recipient,
+                "Newsletter title",
+                "<p>Newsletter body as HTML</p>",
+                "Newsletter body as plain text",
+                Some("https://my-api.com/subscriptions/unsubscribe?token=a-token"),
+            )
+            .await;
+
+        // Assert
+        let response = assert_ok!(outcome);
+        let files: Vec<_> = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 1);
+        let written = std::fs::read_to_string(&files[0]).unwrap();
+        assert!(written.contains(&format!("To: {}", recipient.as_ref())));
+        assert!(written.contains("Subject: Newsletter title"));
+        assert!(written.contains(&format!("Message-ID: <{}@localhost>", response.message_id)));
+        assert!(written.contains(
+            "List-Unsubscribe: <https://my-api.com/subscriptions/unsubscribe?token=a-token>"
+        ));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn send_batch_writes_one_file_per_email() {
+        // Arrange
+        let directory = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let email_client = FileEmailClient::new(Some(directory.clone()), email());
+        let recipients = [email(), email()];
+        let batch: Vec<_> = recipients
+            .iter()
+            .map(|recipient| BatchEmail {
+                recipient,
+                subject: "Newsletter title",
+                html_content: "<p>Newsletter body as HTML</p>",
+                text_content: "Newsletter body as plain text",
+                unsubscribe_link: "https://my-api.com/subscriptions/unsubscribe?token=a-token",
+            })
+            .collect();
+
+        // Act
+        let outcomes = email_client.send_batch(&batch).await;
+
+        // Assert
+        assert_eq!(outcomes.len(), 2);
+        assert!(outcomes.iter().all(Result::is_ok));
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 2);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,204 @@
+//! src/flash_messages.rs
+use actix_web::cookie::{time::Duration, Cookie, CookieJar, Key};
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{FromRequest, HttpMessage, HttpRequest};
+use std::cell::RefCell;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+const FLASH_MESSAGES_COOKIE_NAME: &str = "_flash";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Info,
+    Error,
+}
+
+/// A one-shot message, displayed by the next page the user visits -
+/// e.g. to explain why we redirected them back to the login form.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct FlashMessage {
+    level: Level,
+    content: String,
+}
+
+impl FlashMessage {
+    pub fn info<S: Into<String>>(content: S) -> Self {
+        Self {
+            level: Level::Info,
+            content: content.into(),
+        }
+    }
+
+    pub fn error<S: Into<String>>(content: S) -> Self {
+        Self {
+            level: Level::Error,
+            content: content.into(),
+        }
+    }
+
+    pub fn level(&self) -> Level {
+        self.level
+    }
+
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
+    /// Attach the message to the response to `request`.
+    pub fn send(self, request: &HttpRequest) {
+        match request.extensions().get::<OutgoingFlashMessages>() {
+            Some(outgoing) => outgoing.0.borrow_mut().push(self),
+            None => tracing::error!(
+                "Failed to send a flash message: \
+                `FlashMessagesFramework` is not registered as a middleware."
+            ),
+        }
+    }
+}
+
+/// The flash messages sent along with the previous response.
+#[derive(Clone, Default)]
+pub struct IncomingFlashMessages(Vec<FlashMessage>);
+
+impl IncomingFlashMessages {
+    pub fn iter(&self) -> impl Iterator<Item = &FlashMessage> {
+        self.0.iter()
+    }
+}
+
+impl FromRequest for IncomingFlashMessages {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(request: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Ok(request
+            .extensions()
+            .get::<IncomingFlashMessages>()
+            .cloned()
+            .unwrap_or_default()))
+    }
+}
+
+#[derive(Clone, Default)]
+struct OutgoingFlashMessages(Rc<RefCell<Vec<FlashMessage>>>);
+
+/// Carry flash messages from one response to the next request in a signed
+/// cookie, which is removed once its messages have been delivered.
+pub struct FlashMessagesFramework {
+    key: Key,
+}
+
+impl FlashMessagesFramework {
+    pub fn new(key: Key) -> Self {
+        Self { key }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for FlashMessagesFramework
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = FlashMessagesMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(FlashMessagesMiddleware {
+            service: Rc::new(service),
+            key: self.key.clone(),
+        }))
+    }
+}
+
+pub struct FlashMessagesMiddleware<S> {
+    service: Rc<S>,
+    key: Key,
+}
+
+impl<S, B> Service<ServiceRequest> for FlashMessagesMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let key = self.key.clone();
+        Box::pin(async move {
+            let incoming = read_flash_messages(&request, &key);
+            let has_incoming = !incoming.0.is_empty();
+            let outgoing = OutgoingFlashMessages::default();
+            request.extensions_mut().insert(incoming);
+            request.extensions_mut().insert(outgoing.clone());
+
+            let mut response = service.call(request).await?;
+            let outgoing = outgoing.0.take();
+            let cookie = if !outgoing.is_empty() {
+                Some(flash_messages_cookie(&outgoing, &key)?)
+            } else if has_incoming {
+                // The messages have been delivered: remove them.
+                Some(
+                    Cookie::build(FLASH_MESSAGES_COOKIE_NAME, "")
+                        .path("/")
+                        .max_age(Duration::ZERO)
+                        .finish(),
+                )
+            } else {
+                None
+            };
+            if let Some(cookie) = cookie {
+                response.response_mut().add_cookie(&cookie)?;
+            }
+            Ok(response)
+        })
+    }
+}
+
+fn read_flash_messages(request: &ServiceRequest, key: &Key) -> IncomingFlashMessages {
+    let cookie = match request.cookie(FLASH_MESSAGES_COOKIE_NAME) {
+        Some(cookie) => cookie,
+        None => return IncomingFlashMessages::default(),
+    };
+    let mut jar = CookieJar::new();
+    jar.add_original(cookie);
+    // Tampered or malformed cookies are ignored.
+    let messages = jar
+        .signed(key)
+        .get(FLASH_MESSAGES_COOKIE_NAME)
+        .and_then(|cookie| base64::decode_config(cookie.value(), base64::URL_SAFE_NO_PAD).ok())
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .unwrap_or_default();
+    IncomingFlashMessages(messages)
+}
+
+fn flash_messages_cookie(
+    messages: &[FlashMessage],
+    key: &Key,
+) -> Result<Cookie<'static>, serde_json::Error> {
+    // Cookie values cannot contain arbitrary characters: we encode the
+    // JSON representation of the messages.
+    let value = base64::encode_config(serde_json::to_vec(messages)?, base64::URL_SAFE_NO_PAD);
+    let mut jar = CookieJar::new();
+    jar.signed_mut(key).add(
+        Cookie::build(FLASH_MESSAGES_COOKIE_NAME, value)
+            .path("/")
+            .http_only(true)
+            .finish(),
+    );
+    Ok(jar
+        .get(FLASH_MESSAGES_COOKIE_NAME)
+        .expect("The flash messages cookie was just added to the jar")
+        .clone())
+}
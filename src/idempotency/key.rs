@@ -0,0 +1,51 @@
+//! src/idempotency/key.rs
+
+#[derive(Debug)]
+pub struct IdempotencyKey(String);
+
+impl IdempotencyKey {
+    /// Returns an instance of `IdempotencyKey` if the input satisfies all
+    /// our validation constraints on idempotency keys.
+    pub fn parse(s: String) -> Result<IdempotencyKey, String> {
+        if s.is_empty() {
+            return Err("The idempotency key cannot be empty.".into());
+        }
+        // Keys are chosen by the caller: we need an upper bound to keep
+        // the storage requirements in check.
+        let max_length = 50;
+        if s.len() > max_length {
+            return Err(format!(
+                "The idempotency key must be shorter than {} characters.",
+                max_length
+            ));
+        }
+        Ok(Self(s))
+    }
+}
+
+impl AsRef<str> for IdempotencyKey {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::idempotency::IdempotencyKey;
+    use claim::{assert_err, assert_ok};
+
+    #[test]
+    fn empty_keys_are_rejected() {
+        assert_err!(IdempotencyKey::parse("".into()));
+    }
+
+    #[test]
+    fn a_50_characters_long_key_is_valid() {
+        assert_ok!(IdempotencyKey::parse("a".repeat(50)));
+    }
+
+    #[test]
+    fn keys_longer_than_50_characters_are_rejected() {
+        assert_err!(IdempotencyKey::parse("a".repeat(51)));
+    }
+}
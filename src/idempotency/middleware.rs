@@ -0,0 +1,149 @@
+//! src/idempotency/middleware.rs
+use super::persistence::{save_response, try_processing, NextAction};
+use super::IdempotencyKey;
+use crate::authentication::AuthenticatedUser;
+use crate::routes::error_chain_fmt;
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::StatusCode;
+use actix_web::{web, FromRequest, ResponseError};
+use sqlx::PgPool;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+/// Replay the stored response, instead of processing the request again,
+/// when a state-changing request comes with an `Idempotency-Key` header
+/// that has already been used.
+///
+/// Requests without the header go through untouched.
+///
+/// Keys are only meaningful for authenticated requests: wrap the resources
+/// that need it rather than the whole application.
+pub struct Idempotency;
+
+impl<S, B> Transform<S, ServiceRequest> for Idempotency
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    type Transform = IdempotencyMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(IdempotencyMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct IdempotencyMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for IdempotencyMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        Box::pin(async move {
+            let idempotency_key = match get_idempotency_key(&request)? {
+                Some(key) => key,
+                None => return Ok(service.call(request).await?.map_into_boxed_body()),
+            };
+            let pool = request
+                .app_data::<web::Data<PgPool>>()
+                .ok_or_else(|| anyhow::anyhow!("The database pool is not registered"))
+                .map_err(IdempotencyError::UnexpectedError)?
+                .clone();
+            // Keys are scoped to the user issuing the request and to the
+            // route it targets.
+            let username = AuthenticatedUser::extract(request.request())
+                .await?
+                .username;
+            let route = request.path().to_owned();
+            let transaction = match try_processing(&pool, &idempotency_key, &username, &route)
+                .await
+                .map_err(IdempotencyError::UnexpectedError)?
+            {
+                NextAction::StartProcessing(transaction) => transaction,
+                NextAction::ReturnSavedResponse(saved_response) => {
+                    return Ok(request.into_response(saved_response));
+                }
+                NextAction::RequestInProgress => {
+                    return Err(IdempotencyError::RequestInProgress.into());
+                }
+            };
+            let response = service.call(request).await?.map_into_boxed_body();
+            // Server errors are not stored: dropping the transaction
+            // releases the key and the request can be retried.
+            if response.status().is_server_error() {
+                return Ok(response);
+            }
+            let (request, response) = response.into_parts();
+            let response =
+                save_response(transaction, &idempotency_key, &username, &route, response)
+                    .await
+                    .map_err(IdempotencyError::UnexpectedError)?;
+            Ok(ServiceResponse::new(request, response))
+        })
+    }
+}
+
+fn get_idempotency_key(
+    request: &ServiceRequest,
+) -> Result<Option<IdempotencyKey>, IdempotencyError> {
+    // Safe methods have no side-effects to guard against.
+    if request.method().is_safe() {
+        return Ok(None);
+    }
+    let header_value = match request.headers().get("Idempotency-Key") {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let key = header_value
+        .to_str()
+        .map_err(|_| IdempotencyError::InvalidKey("The idempotency key is not valid UTF8.".into()))?
+        .to_owned();
+    IdempotencyKey::parse(key)
+        .map(Some)
+        .map_err(IdempotencyError::InvalidKey)
+}
+
+#[derive(thiserror::Error)]
+pub enum IdempotencyError {
+    #[error("{0}")]
+    InvalidKey(String),
+    #[error("A request with the same idempotency key is still being processed.")]
+    RequestInProgress,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for IdempotencyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for IdempotencyError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            IdempotencyError::InvalidKey(_) => StatusCode::BAD_REQUEST,
+            IdempotencyError::RequestInProgress => StatusCode::CONFLICT,
+            IdempotencyError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
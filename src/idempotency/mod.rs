@@ -0,0 +1,8 @@
+//! src/idempotency/mod.rs
+
+mod key;
+mod middleware;
+mod persistence;
+
+pub use key::IdempotencyKey;
+pub use middleware::Idempotency;
@@ -0,0 +1,166 @@
+//! src/idempotency/persistence.rs
+use super::IdempotencyKey;
+use actix_web::body::to_bytes;
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use sqlx::postgres::{PgHasArrayType, PgTypeInfo};
+use sqlx::{PgPool, Postgres, Transaction};
+
+// How long a duplicate request waits for the original one to complete
+// before giving up.
+const LOCK_TIMEOUT: &str = "10s";
+// Postgres' error code for `lock_not_available`.
+const LOCK_NOT_AVAILABLE: &str = "55P03";
+
+#[derive(Debug, sqlx::Type)]
+#[sqlx(type_name = "header_pair")]
+struct HeaderPairRecord {
+    name: String,
+    value: Vec<u8>,
+}
+
+impl PgHasArrayType for HeaderPairRecord {
+    fn array_type_info() -> PgTypeInfo {
+        PgTypeInfo::with_name("_header_pair")
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
+pub enum NextAction {
+    // The transaction holds the lock on the idempotency key: it must be
+    // committed, together with the response, by `save_response`.
+    StartProcessing(Transaction<'static, Postgres>),
+    ReturnSavedResponse(HttpResponse),
+    // Another request with the same key is still being processed.
+    RequestInProgress,
+}
+
+/// Claim `idempotency_key` for the current request.
+///
+/// A concurrent request using the same key blocks on the `INSERT` until
+/// the transaction of the first one is either committed - we then replay
+/// its response - or rolled back - we then process the request from
+/// scratch.
+#[tracing::instrument(name = "Claim an idempotency key", skip(pool))]
+pub async fn try_processing(
+    pool: &PgPool,
+    idempotency_key: &IdempotencyKey,
+    username: &str,
+    route: &str,
+) -> Result<NextAction, anyhow::Error> {
+    let mut transaction = pool.begin().await?;
+    sqlx::query(&format!("SET LOCAL lock_timeout = '{}'", LOCK_TIMEOUT))
+        .execute(&mut transaction)
+        .await?;
+    let outcome = sqlx::query!(
+        r#"INSERT INTO idempotency (username, route, idempotency_key, created_at)
+        VALUES ($1, $2, $3, now())
+        ON CONFLICT DO NOTHING"#,
+        username,
+        route,
+        idempotency_key.as_ref()
+    )
+    .execute(&mut transaction)
+    .await;
+    match outcome {
+        Ok(outcome) if outcome.rows_affected() > 0 => Ok(NextAction::StartProcessing(transaction)),
+        Ok(_) => {
+            // We are done with the transaction: release the connection
+            // before going back to the pool.
+            transaction.rollback().await?;
+            let saved_response = get_saved_response(pool, idempotency_key, username, route)
+                .await?
+                .ok_or_else(|| {
+                    anyhow::anyhow!("We expected a saved response, we didn't find it")
+                })?;
+            Ok(NextAction::ReturnSavedResponse(saved_response))
+        }
+        Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some(LOCK_NOT_AVAILABLE) => {
+            Ok(NextAction::RequestInProgress)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[tracing::instrument(name = "Get a saved response", skip(pool))]
+async fn get_saved_response(
+    pool: &PgPool,
+    idempotency_key: &IdempotencyKey,
+    username: &str,
+    route: &str,
+) -> Result<Option<HttpResponse>, anyhow::Error> {
+    let saved_response = sqlx::query!(
+        r#"SELECT
+            response_status_code AS "response_status_code!",
+            response_headers AS "response_headers!: Vec<HeaderPairRecord>",
+            response_body AS "response_body!"
+        FROM idempotency
+        WHERE username = $1 AND route = $2 AND idempotency_key = $3"#,
+        username,
+        route,
+        idempotency_key.as_ref()
+    )
+    .fetch_optional(pool)
+    .await?;
+    if let Some(r) = saved_response {
+        let status_code = StatusCode::from_u16(r.response_status_code.try_into()?)?;
+        let mut response = HttpResponse::build(status_code);
+        for HeaderPairRecord { name, value } in r.response_headers {
+            response.append_header((name, value));
+        }
+        // Let the caller know that nothing has been executed this time.
+        response.insert_header(("Idempotent-Replayed", "true"));
+        Ok(Some(response.body(r.response_body)))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Store `http_response` as the outcome of the request identified by
+/// `idempotency_key` and release the lock on the key.
+#[tracing::instrument(name = "Save a response", skip(transaction, http_response))]
+pub async fn save_response(
+    mut transaction: Transaction<'static, Postgres>,
+    idempotency_key: &IdempotencyKey,
+    username: &str,
+    route: &str,
+    http_response: HttpResponse,
+) -> Result<HttpResponse, anyhow::Error> {
+    let (response_head, body) = http_response.into_parts();
+    // `MessageBody::Error` is not `Send` + `Sync`,
+    // therefore it doesn't play nicely with `anyhow`
+    let body = to_bytes(body).await.map_err(|e| anyhow::anyhow!("{}", e))?;
+    let status_code = response_head.status().as_u16() as i16;
+    let headers = response_head
+        .headers()
+        .iter()
+        .map(|(name, value)| HeaderPairRecord {
+            name: name.as_str().to_owned(),
+            value: value.as_bytes().to_owned(),
+        })
+        .collect::<Vec<_>>();
+    // `query_unchecked!` because the macros cannot check custom types
+    // used as query parameters.
+    sqlx::query_unchecked!(
+        r#"UPDATE idempotency
+        SET
+            response_status_code = $4,
+            response_headers = $5,
+            response_body = $6
+        WHERE username = $1 AND route = $2 AND idempotency_key = $3"#,
+        username,
+        route,
+        idempotency_key.as_ref(),
+        status_code,
+        headers,
+        body.as_ref()
+    )
+    .execute(&mut transaction)
+    .await?;
+    transaction.commit().await?;
+
+    // We need `.map_into_boxed_body` to go from
+    // `HttpResponse<Bytes>` to `HttpResponse<BoxBody>`
+    let http_response = response_head.set_body(body).map_into_boxed_body();
+    Ok(http_response)
+}
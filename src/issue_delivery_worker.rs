@@ -0,0 +1,432 @@
+//! src/issue_delivery_worker.rs
+use crate::domain::{SubscriberEmail, SubscriberLocale, SubscriptionStatus};
+use crate::email_client::{BatchEmail, EmailError, EmailSender, SendEmailResponse, MAX_BATCH_SIZE};
+use crate::email_templates::{EmailTemplates, NewsletterEmail, RenderedEmail};
+use crate::routes::{parse_status, preferences_link, unsubscribe_link};
+use crate::startup::HmacSecret;
+use anyhow::Context;
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::Span;
+use uuid::Uuid;
+
+// How long the worker waits before polling an empty queue again.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+// Delay before the first retry - it doubles with every failed attempt.
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(30);
+// Upper bound to the delay between two attempts.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+// How many times we try to deliver an issue to a subscriber before
+// moving the task to the dead-letter state.
+pub const MAX_ATTEMPTS: i16 = 5;
+// How long a worker has to deliver the tasks it claimed before they are
+// handed to another one.
+const LEASE: Duration = Duration::from_secs(10 * 60);
+
+/// Deliver newsletter issues from the queue until `shutdown` is signalled.
+pub async fn run_worker_until_stopped(
+    pool: PgPool,
+    email_client: Arc<dyn EmailSender>,
+    templates: Arc<EmailTemplates>,
+    base_url: String,
+    hmac_secret: HmacSecret,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<(), anyhow::Error> {
+    while !*shutdown.borrow() {
+        let wait = match try_execute_task(
+            &pool,
+            email_client.as_ref(),
+            &templates,
+            &base_url,
+            &hmac_secret,
+        )
+        .await
+        {
+            Ok(ExecutionOutcome::TaskCompleted) => continue,
+            Ok(ExecutionOutcome::EmptyQueue) => POLL_INTERVAL,
+            // Most likely the database is unreachable: back off for a bit.
+            Err(_) => Duration::from_secs(1),
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = shutdown.changed() => {}
+        }
+    }
+    Ok(())
+}
+
+pub enum ExecutionOutcome {
+    TaskCompleted,
+    EmptyQueue,
+}
+
+#[tracing::instrument(skip_all, fields(batch_size = tracing::field::Empty), err)]
+pub async fn try_execute_task(
+    pool: &PgPool,
+    email_client: &dyn EmailSender,
+    templates: &EmailTemplates,
+    base_url: &str,
+    hmac_secret: &HmacSecret,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    // No transaction is held open while we talk to the email provider: if
+    // committing failed after the batch went out, every email in it would
+    // be sent again.
+    let tasks = claim_tasks(pool).await?;
+    if tasks.is_empty() {
+        return Ok(ExecutionOutcome::EmptyQueue);
+    }
+    Span::current().record("batch_size", tasks.len());
+    let mut ready = Vec::with_capacity(tasks.len());
+    let mut skipped = Vec::new();
+    let mut failed = Vec::new();
+    for task in tasks {
+        match prepare_email(pool, &task, templates, base_url, hmac_secret).await {
+            Ok(Some(email)) => ready.push((task, email)),
+            // They left the list after the issue was published.
+            Ok(None) => skipped.push(task),
+            // Trying again would fail the same way: we set the task aside,
+            // or it would be picked up again and again, ahead of every
+            // other one.
+            Err(e) => {
+                tracing::error!(
+                    error.cause_chain = ?e,
+                    error.message = %e,
+                    newsletter_issue_id = %task.newsletter_issue_id,
+                    subscriber_id = %task.subscriber_id,
+                    "Failed to prepare the issue for a subscriber. Giving up.",
+                );
+                failed.push((task, format!("{:#}", e)));
+            }
+        }
+    }
+    let batch = ready
+        .iter()
+        .map(|(_, email)| BatchEmail {
+            recipient: &email.recipient,
+            subject: &email.rendered.subject,
+            html_content: &email.rendered.html_body,
+            text_content: &email.rendered.text_body,
+            unsubscribe_link: &email.unsubscribe_link,
+        })
+        .collect::<Vec<_>>();
+    let outcomes = if batch.is_empty() {
+        Vec::new()
+    } else {
+        email_client.send_batch(&batch).await
+    };
+
+    let mut transaction = pool.begin().await?;
+    for task in &skipped {
+        mark_as(&mut transaction, task, "skipped").await?;
+    }
+    for (task, error) in &failed {
+        move_to_dead_letter(&mut transaction, task, error).await?;
+    }
+    if outcomes.len() != ready.len() {
+        tracing::error!(
+            "The email client returned {} outcomes for a batch of {} emails.",
+            outcomes.len(),
+            ready.len()
+        );
+    }
+    // Outcomes come back in the order of the batch.
+    let mut outcomes = outcomes.into_iter();
+    for (task, email) in &ready {
+        // We cannot tell whether emails without an outcome went out: they
+        // fail for good, rather than being sent again once the lease runs out.
+        let outcome = outcomes.next().unwrap_or_else(|| {
+            Err(EmailError::Permanent(anyhow::anyhow!(
+                "The email client returned no outcome for this email."
+            )))
+        });
+        record_outcome(&mut transaction, task, &email.recipient, outcome).await?;
+    }
+    transaction.commit().await?;
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+/// Mark the task as delivered, or schedule another attempt if the failure
+/// might go away.
+#[tracing::instrument(
+    skip_all,
+    fields(
+        newsletter_issue_id = %task.newsletter_issue_id,
+        subscriber_id = %task.subscriber_id,
+        subscriber_email = %recipient.as_ref()
+    )
+)]
+async fn record_outcome(
+    transaction: &mut Transaction<'_, Postgres>,
+    task: &Task,
+    recipient: &SubscriberEmail,
+    outcome: Result<SendEmailResponse, EmailError>,
+) -> Result<(), sqlx::Error> {
+    match outcome {
+        Ok(_) => mark_as(transaction, task, "delivered").await,
+        Err(e) => {
+            let attempts = task.n_retries + 1;
+            if e.is_transient() && attempts < MAX_ATTEMPTS {
+                tracing::warn!(
+                    error.cause_chain = ?e,
+                    error.message = %e,
+                    "Failed to deliver issue to a confirmed subscriber. \
+                    It will be retried later.",
+                );
+                schedule_retry(transaction, task, &e.to_string()).await
+            } else {
+                tracing::error!(
+                    error.cause_chain = ?e,
+                    error.message = %e,
+                    "Failed to deliver issue to a confirmed subscriber. \
+                    Giving up after {} attempt(s).",
+                    attempts
+                );
+                move_to_dead_letter(transaction, task, &e.to_string()).await
+            }
+        }
+    }
+}
+
+/// An issue, ready to be sent to one of its recipients.
+struct IssueEmail {
+    recipient: SubscriberEmail,
+    unsubscribe_link: String,
+    rendered: RenderedEmail,
+}
+
+/// Render the issue of `task` for its subscriber - `None` if they are no
+/// longer a confirmed member of the list it was published to.
+#[tracing::instrument(
+    skip_all,
+    fields(
+        newsletter_issue_id = %task.newsletter_issue_id,
+        subscriber_id = %task.subscriber_id
+    )
+)]
+async fn prepare_email(
+    pool: &PgPool,
+    task: &Task,
+    templates: &EmailTemplates,
+    base_url: &str,
+    hmac_secret: &HmacSecret,
+) -> Result<Option<IssueEmail>, anyhow::Error> {
+    let issue = get_issue(pool, task.newsletter_issue_id)
+        .await
+        .context("Failed to load the newsletter issue.")?;
+    let subscriber = get_subscriber(pool, task.subscriber_id, issue.list_id)
+        .await
+        .context("Failed to load the subscriber.")?;
+    if parse_status(&subscriber.status)? != SubscriptionStatus::Confirmed {
+        return Ok(None);
+    }
+    let recipient = SubscriberEmail::parse(subscriber.email)
+        .map_err(anyhow::Error::msg)
+        .context("The stored contact details of the subscriber are invalid.")?;
+    let unsubscribe_link = unsubscribe_link(base_url, &subscriber.unsubscribe_token);
+    let preferences_link = preferences_link(
+        base_url,
+        task.subscriber_id,
+        subscriber.preferences_version,
+        hmac_secret,
+    );
+    // Locales are validated before they are stored.
+    let locale = SubscriberLocale::parse(subscriber.locale).unwrap_or_default();
+    let rendered = templates
+        .render(
+            &locale,
+            &NewsletterEmail {
+                title: &issue.title,
+                html_content: &issue.html_content,
+                text_content: &issue.text_content,
+                unsubscribe_link: &unsubscribe_link,
+                preferences_link: &preferences_link,
+                base_url,
+            },
+        )
+        .context("Failed to render the newsletter issue.")?;
+    Ok(Some(IssueEmail {
+        recipient,
+        unsubscribe_link,
+        rendered,
+    }))
+}
+
+/// How long to wait before the next attempt, after `n_retries` failed ones.
+fn retry_delay(n_retries: i16) -> Duration {
+    let factor = 2u32.saturating_pow(n_retries.max(0) as u32);
+    BASE_RETRY_DELAY
+        .checked_mul(factor)
+        .map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY))
+}
+
+struct Task {
+    newsletter_issue_id: Uuid,
+    subscriber_id: Uuid,
+    n_retries: i16,
+}
+
+/// Claim up to a batch worth of tasks that are due, for `LEASE`.
+#[tracing::instrument(skip_all)]
+async fn claim_tasks(pool: &PgPool) -> Result<Vec<Task>, sqlx::Error> {
+    let lease_expires_at =
+        Utc::now() + chrono::Duration::from_std(LEASE).expect("The lease is out of range.");
+    // `SKIP LOCKED` lets several workers (or application instances) drain
+    // the queue concurrently without claiming the same task twice.
+    // Tasks whose lease ran out were claimed by a worker that never
+    // reported back: they are up for grabs again.
+    sqlx::query_as!(
+        Task,
+        r#"UPDATE issue_delivery_queue
+        SET status = 'in_flight', execute_after = $2
+        WHERE (newsletter_issue_id, subscriber_id) IN (
+            SELECT newsletter_issue_id, subscriber_id
+            FROM issue_delivery_queue
+            WHERE status IN ('pending', 'in_flight') AND execute_after <= now()
+            ORDER BY execute_after
+            FOR UPDATE
+            SKIP LOCKED
+            LIMIT $1
+        )
+        RETURNING newsletter_issue_id, subscriber_id, n_retries"#,
+        MAX_BATCH_SIZE as i64,
+        lease_expires_at
+    )
+    .fetch_all(pool)
+    .await
+}
+
+#[tracing::instrument(skip_all)]
+async fn mark_as(
+    transaction: &mut Transaction<'_, Postgres>,
+    task: &Task,
+    status: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE issue_delivery_queue
+        SET status = $3
+        WHERE newsletter_issue_id = $1 AND subscriber_id = $2"#,
+        task.newsletter_issue_id,
+        task.subscriber_id,
+        status
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn schedule_retry(
+    transaction: &mut Transaction<'_, Postgres>,
+    task: &Task,
+    error: &str,
+) -> Result<(), sqlx::Error> {
+    let execute_after = Utc::now()
+        + chrono::Duration::from_std(retry_delay(task.n_retries))
+            .expect("The retry delay is out of range.");
+    sqlx::query!(
+        r#"UPDATE issue_delivery_queue
+        SET status = 'pending', n_retries = n_retries + 1, execute_after = $3, last_error = $4
+        WHERE newsletter_issue_id = $1 AND subscriber_id = $2"#,
+        task.newsletter_issue_id,
+        task.subscriber_id,
+        execute_after,
+        error
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn move_to_dead_letter(
+    transaction: &mut Transaction<'_, Postgres>,
+    task: &Task,
+    error: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE issue_delivery_queue
+        SET status = 'dead_letter', n_retries = n_retries + 1, last_error = $3
+        WHERE newsletter_issue_id = $1 AND subscriber_id = $2"#,
+        task.newsletter_issue_id,
+        task.subscriber_id,
+        error
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+struct Subscriber {
+    email: String,
+    status: String,
+    locale: String,
+    preferences_version: i32,
+    unsubscribe_token: String,
+}
+
+#[tracing::instrument(skip_all)]
+/// Load a subscriber, along with their membership of the list the issue
+/// was published to.
+async fn get_subscriber(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+) -> Result<Subscriber, sqlx::Error> {
+    sqlx::query_as!(
+        Subscriber,
+        r#"SELECT s.email, m.status, s.locale, s.preferences_version, u.unsubscribe_token
+        FROM subscriptions s
+        JOIN list_memberships m ON m.subscriber_id = s.id
+        JOIN unsubscribe_tokens u
+            ON u.subscriber_id = m.subscriber_id AND u.list_id = m.list_id
+        WHERE s.id = $1 AND m.list_id = $2"#,
+        subscriber_id,
+        list_id
+    )
+    .fetch_one(pool)
+    .await
+}
+
+struct NewsletterIssue {
+    list_id: Uuid,
+    title: String,
+    text_content: String,
+    html_content: String,
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_issue(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+) -> Result<NewsletterIssue, sqlx::Error> {
+    sqlx::query_as!(
+        NewsletterIssue,
+        r#"SELECT list_id, title, text_content, html_content
+        FROM newsletter_issues
+        WHERE newsletter_issue_id = $1"#,
+        newsletter_issue_id
+    )
+    .fetch_one(pool)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{retry_delay, BASE_RETRY_DELAY, MAX_RETRY_DELAY};
+
+    #[test]
+    fn the_retry_delay_doubles_with_every_attempt() {
+        assert_eq!(retry_delay(0), BASE_RETRY_DELAY);
+        assert_eq!(retry_delay(1), BASE_RETRY_DELAY * 2);
+        assert_eq!(retry_delay(3), BASE_RETRY_DELAY * 8);
+    }
+
+    #[test]
+    fn the_retry_delay_is_capped() {
+        assert_eq!(retry_delay(20), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(i16::MAX), MAX_RETRY_DELAY);
+    }
+}
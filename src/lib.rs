@@ -1,7 +1,22 @@
 //! src/lib.rs
+pub mod authentication;
 pub mod configuration;
 pub mod domain;
 pub mod email_client;
+pub mod email_outbox;
+pub mod email_templates;
+pub mod file_email_client;
+pub mod flash_messages;
+pub mod idempotency;
+pub mod issue_delivery_worker;
+pub mod mailing_lists;
+pub mod markdown;
+pub mod rate_limit;
 pub mod routes;
+pub mod session_state;
+pub mod session_store;
+pub mod smtp_email_client;
 pub mod startup;
+pub mod subscriber_data;
 pub mod telemetry;
+pub mod utils;
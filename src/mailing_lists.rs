@@ -0,0 +1,32 @@
+//! src/mailing_lists.rs
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// The slug of the list that requests which do not name one go to - the
+/// only list we had before supporting several of them.
+pub const DEFAULT_LIST: &str = "newsletter";
+
+/// One of the newsletters people can subscribe to.
+pub struct MailingList {
+    pub list_id: Uuid,
+    pub slug: String,
+    pub name: String,
+}
+
+/// Look up a list by its slug, falling back to [`DEFAULT_LIST`] if
+/// the request did not name one.
+#[tracing::instrument(name = "Get mailing list by slug", skip(pool))]
+pub async fn get_list_by_slug(
+    pool: &PgPool,
+    slug: Option<&str>,
+) -> Result<Option<MailingList>, sqlx::Error> {
+    let slug = slug.unwrap_or(DEFAULT_LIST);
+    let list = sqlx::query_as!(
+        MailingList,
+        r#"SELECT list_id, slug, name FROM lists WHERE slug = $1"#,
+        slug
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(list)
+}
@@ -0,0 +1,197 @@
+//! src/markdown.rs
+use pulldown_cmark::{Event, Options, Parser, Tag, TagEnd};
+
+fn parser(markdown: &str) -> Parser<'_> {
+    Parser::new_ext(
+        markdown,
+        Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS,
+    )
+}
+
+/// Render `markdown` to HTML that is safe to embed in an email.
+///
+/// Markdown lets authors write raw HTML: scripts, event handlers and the
+/// like are stripped from the output.
+pub fn to_html(markdown: &str) -> String {
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, parser(markdown));
+    ammonia::clean(&html)
+}
+
+/// Render `markdown` to plain text, for the text alternative of an email.
+///
+/// Formatting is dropped, raw HTML included. Links are replaced by their
+/// text followed by a `[n]` reference to a footnote holding their URL.
+pub fn to_plain_text(markdown: &str) -> String {
+    let mut text = String::new();
+    let mut footnotes: Vec<String> = Vec::new();
+    // Where the text of the links we are in starts, along with their URL
+    let mut links: Vec<(usize, String)> = Vec::new();
+    // The number of the next item of each list we are in (`None` for
+    // bullet lists)
+    let mut lists: Vec<Option<u64>> = Vec::new();
+    for event in parser(markdown) {
+        match event {
+            Event::Text(s) | Event::Code(s) => text.push_str(&s),
+            Event::SoftBreak | Event::HardBreak => text.push('\n'),
+            Event::Rule => {
+                text.push_str("---");
+                end_block(&mut text);
+            }
+            Event::TaskListMarker(checked) => text.push_str(if checked { "[x] " } else { "[ ] " }),
+            Event::Start(Tag::Link { dest_url, .. })
+            | Event::Start(Tag::Image { dest_url, .. }) => {
+                links.push((text.len(), dest_url.into_string()))
+            }
+            Event::End(TagEnd::Link) | Event::End(TagEnd::Image) => {
+                let (start, url) = links.pop().expect("Unbalanced link events");
+                // No need for a footnote if the URL is spelled out already.
+                if text[start..] != url {
+                    let number = match footnotes.iter().position(|u| *u == url) {
+                        Some(index) => index + 1,
+                        None => {
+                            footnotes.push(url);
+                            footnotes.len()
+                        }
+                    };
+                    text.push_str(&format!(" [{}]", number));
+                }
+            }
+            Event::Start(Tag::List(first_number)) => {
+                // Nested lists start on their own line.
+                if !lists.is_empty() && !text.ends_with('\n') {
+                    text.push('\n');
+                }
+                lists.push(first_number)
+            }
+            Event::End(TagEnd::List(_)) => {
+                lists.pop();
+                if lists.is_empty() {
+                    end_block(&mut text);
+                }
+            }
+            Event::Start(Tag::Item) => {
+                let depth = lists.len().saturating_sub(1);
+                text.push_str(&"  ".repeat(depth));
+                match lists.last_mut() {
+                    Some(Some(number)) => {
+                        text.push_str(&format!("{}. ", number));
+                        *number += 1;
+                    }
+                    _ => text.push_str("- "),
+                }
+            }
+            Event::End(TagEnd::Item) => end_line(&mut text),
+            // Items of loose lists are made of paragraphs.
+            Event::End(TagEnd::Paragraph) if !lists.is_empty() => end_line(&mut text),
+            Event::End(TagEnd::Paragraph)
+            | Event::End(TagEnd::Heading(_))
+            | Event::End(TagEnd::CodeBlock)
+            | Event::End(TagEnd::BlockQuote(_))
+            | Event::End(TagEnd::Table) => end_block(&mut text),
+            Event::End(TagEnd::TableRow) | Event::End(TagEnd::TableHead) => end_line(&mut text),
+            Event::End(TagEnd::TableCell) => text.push('\t'),
+            _ => {}
+        }
+    }
+    let mut text = text.trim_end().to_string();
+    for (index, url) in footnotes.iter().enumerate() {
+        text.push_str(if index == 0 { "\n\n" } else { "\n" });
+        text.push_str(&format!("[{}] {}", index + 1, url));
+    }
+    text
+}
+
+fn end_line(text: &mut String) {
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+}
+
+/// Leave a blank line between blocks.
+fn end_block(text: &mut String) {
+    end_line(text);
+    if !text.is_empty() && !text.ends_with("\n\n") {
+        text.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::markdown::{to_html, to_plain_text};
+
+    #[test]
+    fn markdown_is_rendered_to_html() {
+        let html = to_html("# Issue #1\n\nSome **bold** text.");
+
+        assert_eq!(
+            html,
+            "<h1>Issue #1</h1>\n<p>Some <strong>bold</strong> text.</p>\n"
+        );
+    }
+
+    #[test]
+    fn dangerous_html_is_stripped() {
+        let html = to_html(
+            "Hi! <script>alert('pwned')</script>\n\n\
+            <img src=\"x\" onerror=\"alert('pwned')\">\n\n\
+            [click me](javascript:alert('pwned'))",
+        );
+
+        assert!(!html.contains("<script"));
+        assert!(!html.contains("onerror"));
+        assert!(!html.contains("javascript:"));
+    }
+
+    #[test]
+    fn links_become_footnotes_in_plain_text() {
+        let text = to_plain_text(
+            "Read [our blog](https://example.com/blog) and \
+            [our docs](https://example.com/docs).\n\n\
+            Did we mention [the blog](https://example.com/blog)?",
+        );
+
+        assert_eq!(
+            text,
+            "Read our blog [1] and our docs [2].\n\n\
+            Did we mention the blog [1]?\n\n\
+            [1] https://example.com/blog\n\
+            [2] https://example.com/docs"
+        );
+    }
+
+    #[test]
+    fn autolinks_do_not_get_a_footnote() {
+        let text = to_plain_text("Go to <https://example.com>");
+
+        assert_eq!(text, "Go to https://example.com");
+    }
+
+    #[test]
+    fn formatting_is_dropped_in_plain_text() {
+        let text = to_plain_text(
+            "# Title\n\n\
+            Some **bold** and `code` <b>tags</b>.\n\n\
+            - one\n\
+            - two\n\n\
+            1. first\n\
+            2. second\n\n\
+            > Quoted\n\n\
+            ---\n\n\
+            The end",
+        );
+
+        assert_eq!(
+            text,
+            "Title\n\n\
+            Some bold and code tags.\n\n\
+            - one\n\
+            - two\n\n\
+            1. first\n\
+            2. second\n\n\
+            Quoted\n\n\
+            ---\n\n\
+            The end"
+        );
+    }
+}
@@ -0,0 +1,156 @@
+//! src/rate_limit/bucket.rs
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+
+/// The limit applied to one key: up to `capacity` requests in a burst,
+/// then one more every `refill_interval`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TokenBucket {
+    pub capacity: u32,
+    pub refill_interval: Duration,
+}
+
+/// How many tokens a bucket held at a given time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BucketState {
+    pub tokens: f64,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Whether a request can go through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    Allowed,
+    /// The bucket is empty: the next token shows up after the given delay.
+    Limited {
+        retry_after: Duration,
+    },
+}
+
+impl TokenBucket {
+    /// Take a token out of a bucket last seen in `state` - a full one
+    /// if it was never used - returning what is left of it.
+    pub fn take(&self, state: Option<BucketState>, now: DateTime<Utc>) -> (BucketState, Decision) {
+        let capacity = f64::from(self.capacity);
+        let tokens = match state {
+            Some(state) => {
+                let elapsed = (now - state.updated_at).to_std().unwrap_or_default();
+                (state.tokens + self.tokens_for(elapsed)).min(capacity)
+            }
+            None => capacity,
+        };
+        if tokens >= 1.0 {
+            let state = BucketState {
+                tokens: tokens - 1.0,
+                updated_at: now,
+            };
+            (state, Decision::Allowed)
+        } else {
+            let retry_after = self.refill_interval.mul_f64(1.0 - tokens);
+            let state = BucketState {
+                tokens,
+                updated_at: now,
+            };
+            (state, Decision::Limited { retry_after })
+        }
+    }
+
+    /// When a bucket left in `state` is full again: from then on, it is no
+    /// different from one that was never used.
+    pub fn full_at(&self, state: &BucketState) -> DateTime<Utc> {
+        let missing = (f64::from(self.capacity) - state.tokens).max(0.0);
+        let delay = chrono::Duration::from_std(self.refill_interval.mul_f64(missing))
+            .unwrap_or(chrono::Duration::MAX);
+        state
+            .updated_at
+            .checked_add_signed(delay)
+            .unwrap_or(DateTime::<Utc>::MAX_UTC)
+    }
+
+    fn tokens_for(&self, elapsed: Duration) -> f64 {
+        if self.refill_interval.is_zero() {
+            f64::INFINITY
+        } else {
+            elapsed.as_secs_f64() / self.refill_interval.as_secs_f64()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BucketState, Decision, TokenBucket};
+    use chrono::{DateTime, Duration, Utc};
+
+    fn bucket() -> TokenBucket {
+        TokenBucket {
+            capacity: 2,
+            refill_interval: std::time::Duration::from_secs(10),
+        }
+    }
+
+    fn now() -> DateTime<Utc> {
+        "2022-06-14T10:00:00Z".parse().unwrap()
+    }
+
+    #[test]
+    fn new_buckets_allow_a_burst_up_to_their_capacity() {
+        let bucket = bucket();
+
+        let (state, first) = bucket.take(None, now());
+        let (state, second) = bucket.take(Some(state), now());
+        let (_, third) = bucket.take(Some(state), now());
+
+        assert_eq!(first, Decision::Allowed);
+        assert_eq!(second, Decision::Allowed);
+        assert_eq!(
+            third,
+            Decision::Limited {
+                retry_after: std::time::Duration::from_secs(10)
+            }
+        );
+    }
+
+    #[test]
+    fn empty_buckets_refill_over_time() {
+        let bucket = bucket();
+        let empty = BucketState {
+            tokens: 0.0,
+            updated_at: now(),
+        };
+
+        let (_, too_early) = bucket.take(Some(empty), now() + Duration::seconds(4));
+        let (_, in_time) = bucket.take(Some(empty), now() + Duration::seconds(10));
+
+        assert_eq!(
+            too_early,
+            Decision::Limited {
+                retry_after: std::time::Duration::from_secs(6)
+            }
+        );
+        assert_eq!(in_time, Decision::Allowed);
+    }
+
+    #[test]
+    fn buckets_never_hold_more_than_their_capacity() {
+        let bucket = bucket();
+        let empty = BucketState {
+            tokens: 0.0,
+            updated_at: now(),
+        };
+
+        let (state, _) = bucket.take(Some(empty), now() + Duration::days(1));
+
+        assert_eq!(state.tokens, 1.0);
+    }
+
+    #[test]
+    fn buckets_are_full_once_every_missing_token_is_refilled() {
+        let bucket = bucket();
+        let state = BucketState {
+            tokens: 0.5,
+            updated_at: now(),
+        };
+
+        assert_eq!(bucket.full_at(&state), now() + Duration::seconds(15));
+    }
+}
@@ -0,0 +1,231 @@
+//! src/rate_limit/middleware.rs
+use super::bucket::{Decision, TokenBucket};
+use super::store::RateLimitStore;
+use crate::routes::error_chain_fmt;
+use crate::startup::EmailHashSecret;
+use crate::subscriber_data::email_hash;
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{RETRY_AFTER, X_FORWARDED_FOR};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Reject requests with a `429 Too Many Requests` once a client has used
+/// up the requests it is allowed on a route.
+///
+/// Clients are told apart by their IP address and, when `per_email` is
+/// set, by the `email` field of the form they submit: nobody can get us
+/// to flood an inbox by spreading their requests over several addresses.
+#[derive(Clone)]
+pub struct RateLimit {
+    route: &'static str,
+    store: Arc<dyn RateLimitStore>,
+    per_ip: TokenBucket,
+    per_email: Option<TokenBucket>,
+    // Read the client IP from `X-Forwarded-For`, rather than from the
+    // connection.
+    trust_forwarded_for: bool,
+    // Email addresses are not kept in the clear in the bucket keys.
+    email_hash_secret: EmailHashSecret,
+}
+
+impl RateLimit {
+    /// `route` namespaces the buckets: each route is limited separately.
+    pub fn new(
+        route: &'static str,
+        store: Arc<dyn RateLimitStore>,
+        per_ip: TokenBucket,
+        per_email: Option<TokenBucket>,
+        trust_forwarded_for: bool,
+        email_hash_secret: EmailHashSecret,
+    ) -> Self {
+        Self {
+            route,
+            store,
+            per_ip,
+            per_email,
+            trust_forwarded_for,
+            email_hash_secret,
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            limit: Rc::new(self.clone()),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    limit: Rc<RateLimit>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut request: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let limit = Rc::clone(&self.limit);
+        Box::pin(async move {
+            let mut buckets = Vec::new();
+            if let Some(ip) = client_ip(&request, limit.trust_forwarded_for) {
+                buckets.push((format!("{}:ip:{}", limit.route, ip), limit.per_ip));
+            }
+            if let Some(per_email) = limit.per_email {
+                // Requests without a valid email are rejected by the
+                // handler: the IP limit is enough for them.
+                if let Some(email) = get_form_email(&mut request).await? {
+                    buckets.push((
+                        format!(
+                            "{}:email:{}",
+                            limit.route,
+                            email_hash(&email, &limit.email_hash_secret)
+                        ),
+                        per_email,
+                    ));
+                }
+            }
+            let decision = limit
+                .store
+                .take_all(&buckets)
+                .await
+                .map_err(RateLimitError::UnexpectedError)?;
+            if let Decision::Limited { retry_after } = decision {
+                return Err(RateLimitError::TooManyRequests { retry_after }.into());
+            }
+            Ok(service.call(request).await?.map_into_boxed_body())
+        })
+    }
+}
+
+fn client_ip(request: &ServiceRequest, trust_forwarded_for: bool) -> Option<String> {
+    if trust_forwarded_for {
+        // Clients can send an `X-Forwarded-For` header of their own: our
+        // proxy appends the address it got the request from to it. Only
+        // that last entry can be trusted.
+        let last_hop = request
+            .headers()
+            .get_all(X_FORWARDED_FOR)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|hop| !hop.is_empty())
+            .last();
+        if let Some(ip) = last_hop {
+            return Some(ip.to_owned());
+        }
+    }
+    request.connection_info().peer_addr().map(str::to_owned)
+}
+
+#[derive(serde::Deserialize)]
+struct EmailField {
+    email: String,
+}
+
+/// Read the `email` field of a form submission, leaving the body in place
+/// for the handler.
+async fn get_form_email(request: &mut ServiceRequest) -> Result<Option<String>, actix_web::Error> {
+    let body = request.extract::<web::Bytes>().await?;
+    let email = serde_urlencoded::from_bytes::<EmailField>(&body)
+        .ok()
+        .map(|field| field.email);
+    request.set_payload(body.into());
+    Ok(email)
+}
+
+#[derive(thiserror::Error)]
+pub enum RateLimitError {
+    #[error("Too many requests: try again later.")]
+    TooManyRequests { retry_after: Duration },
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for RateLimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for RateLimitError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            RateLimitError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            RateLimitError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if let RateLimitError::TooManyRequests { retry_after } = self {
+            // In whole seconds, rounded up: retrying any earlier would fail.
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response.insert_header((RETRY_AFTER, seconds.max(1)));
+        }
+        response.body(self.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::client_ip;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn the_last_forwarded_hop_is_the_client_ip() {
+        let request = TestRequest::default()
+            .insert_header(("X-Forwarded-For", "1.1.1.1, 10.0.0.1"))
+            .to_srv_request();
+
+        assert_eq!(client_ip(&request, true).as_deref(), Some("10.0.0.1"));
+    }
+
+    #[test]
+    fn repeated_forwarded_headers_are_read_in_order() {
+        let request = TestRequest::default()
+            .append_header(("X-Forwarded-For", "1.1.1.1"))
+            .append_header(("X-Forwarded-For", "2.2.2.2, 10.0.0.1"))
+            .to_srv_request();
+
+        assert_eq!(client_ip(&request, true).as_deref(), Some("10.0.0.1"));
+    }
+
+    #[test]
+    fn forwarded_headers_are_ignored_unless_trusted() {
+        let request = TestRequest::default()
+            .insert_header(("X-Forwarded-For", "10.0.0.1"))
+            .peer_addr("192.168.0.1:4242".parse().unwrap())
+            .to_srv_request();
+
+        assert_eq!(client_ip(&request, false).as_deref(), Some("192.168.0.1"));
+    }
+}
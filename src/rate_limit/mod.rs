@@ -0,0 +1,9 @@
+//! src/rate_limit/mod.rs
+
+mod bucket;
+mod middleware;
+mod store;
+
+pub use bucket::{BucketState, Decision, TokenBucket};
+pub use middleware::RateLimit;
+pub use store::{InMemoryRateLimitStore, PostgresRateLimitStore, RateLimitStore};
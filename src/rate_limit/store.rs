@@ -0,0 +1,255 @@
+//! src/rate_limit/store.rs
+use super::bucket::{BucketState, Decision, TokenBucket};
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Where the token buckets live.
+#[async_trait::async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Take a token out of each of the buckets stored under the given keys.
+    ///
+    /// Either every bucket has a token to spare, or none of them is
+    /// touched: a request turned down on one key does not eat into the
+    /// others.
+    async fn take_all(&self, buckets: &[(String, TokenBucket)]) -> Result<Decision, anyhow::Error>;
+}
+
+/// Combine the decisions taken on each bucket: the request has to wait for
+/// the slowest one to refill.
+fn combine(decisions: impl Iterator<Item = Decision>) -> Decision {
+    decisions.fold(Decision::Allowed, |combined, decision| {
+        match (combined, decision) {
+            (Decision::Allowed, decision) => decision,
+            (combined, Decision::Allowed) => combined,
+            (Decision::Limited { retry_after: a }, Decision::Limited { retry_after: b }) => {
+                Decision::Limited {
+                    retry_after: a.max(b),
+                }
+            }
+        }
+    })
+}
+
+// Past this many buckets, the in-memory store drops those that filled up
+// again before adding new ones - then, if that is not enough, the least
+// recently used ones, a batch at a time.
+const MAX_IN_MEMORY_BUCKETS: usize = 10_000;
+const IN_MEMORY_EVICTION_BATCH_SIZE: usize = 100;
+
+/// Keeps the buckets in the memory of the process: each instance of the
+/// application enforces the limits on its own.
+#[derive(Default)]
+pub struct InMemoryRateLimitStore {
+    buckets: Mutex<HashMap<String, (BucketState, DateTime<Utc>)>>,
+}
+
+impl InMemoryRateLimitStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait::async_trait]
+impl RateLimitStore for InMemoryRateLimitStore {
+    async fn take_all(&self, buckets: &[(String, TokenBucket)]) -> Result<Decision, anyhow::Error> {
+        let now = Utc::now();
+        let mut stored = self
+            .buckets
+            .lock()
+            .map_err(|_| anyhow::anyhow!("The rate limit buckets are poisoned."))?;
+        let taken = buckets
+            .iter()
+            .map(|(key, bucket)| {
+                let state = stored.get(key).map(|(state, _)| *state);
+                let (state, decision) = bucket.take(state, now);
+                (key, bucket, state, decision)
+            })
+            .collect::<Vec<_>>();
+        let decision = combine(taken.iter().map(|(_, _, _, decision)| *decision));
+        if decision != Decision::Allowed {
+            return Ok(decision);
+        }
+        let new_keys = taken
+            .iter()
+            .filter(|(key, ..)| !stored.contains_key(*key))
+            .count();
+        if stored.len() + new_keys > MAX_IN_MEMORY_BUCKETS {
+            stored.retain(|_, (_, full_at)| *full_at > now);
+        }
+        let excess = (stored.len() + new_keys).saturating_sub(MAX_IN_MEMORY_BUCKETS);
+        if excess > 0 {
+            // A flood of new keys would otherwise grow the map without
+            // bound. The clients we forget about get a full bucket back.
+            let mut by_last_use = stored
+                .iter()
+                .filter(|(key, _)| !taken.iter().any(|(taken_key, ..)| taken_key == key))
+                .map(|(key, (state, _))| (state.updated_at, key.clone()))
+                .collect::<Vec<_>>();
+            by_last_use.sort_unstable();
+            for (_, key) in by_last_use
+                .into_iter()
+                .take(excess.max(IN_MEMORY_EVICTION_BATCH_SIZE))
+            {
+                stored.remove(&key);
+            }
+        }
+        for (key, bucket, state, _) in taken {
+            stored.insert(key.to_owned(), (state, bucket.full_at(&state)));
+        }
+        Ok(decision)
+    }
+}
+
+// How many buckets that filled up again are dropped on each request.
+const STALE_BUCKETS_BATCH_SIZE: i64 = 100;
+
+/// Keeps the buckets in Postgres: the limits hold across every instance of
+/// the application.
+pub struct PostgresRateLimitStore {
+    pool: PgPool,
+}
+
+impl PostgresRateLimitStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait::async_trait]
+impl RateLimitStore for PostgresRateLimitStore {
+    #[tracing::instrument(name = "Take rate limit tokens", skip(self))]
+    async fn take_all(&self, buckets: &[(String, TokenBucket)]) -> Result<Decision, anyhow::Error> {
+        let now = Utc::now();
+        let mut transaction = self.pool.begin().await?;
+        // Rows are locked in the same order by every request, so that two
+        // of them sharing keys cannot deadlock.
+        let mut buckets = buckets.iter().collect::<Vec<_>>();
+        buckets.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut taken = Vec::with_capacity(buckets.len());
+        for (key, bucket) in buckets {
+            // Concurrent requests for a new key all find the row we insert
+            // here, and queue up on its lock.
+            sqlx::query!(
+                r#"INSERT INTO rate_limit_buckets (key, tokens, updated_at, full_at)
+                VALUES ($1, $2, $3, $3)
+                ON CONFLICT (key) DO NOTHING"#,
+                key,
+                f64::from(bucket.capacity),
+                now
+            )
+            .execute(&mut transaction)
+            .await?;
+            let row = sqlx::query!(
+                r#"SELECT tokens, updated_at FROM rate_limit_buckets
+                WHERE key = $1
+                FOR UPDATE"#,
+                key
+            )
+            .fetch_one(&mut transaction)
+            .await?;
+            let state = BucketState {
+                tokens: row.tokens,
+                updated_at: row.updated_at,
+            };
+            let (state, decision) = bucket.take(Some(state), now);
+            taken.push((key, bucket, state, decision));
+        }
+        let decision = combine(taken.iter().map(|(_, _, _, decision)| *decision));
+        if decision == Decision::Allowed {
+            for (key, bucket, state, _) in taken {
+                sqlx::query!(
+                    r#"UPDATE rate_limit_buckets
+                    SET tokens = $2, updated_at = $3, full_at = $4
+                    WHERE key = $1"#,
+                    key,
+                    state.tokens,
+                    state.updated_at,
+                    bucket.full_at(&state)
+                )
+                .execute(&mut transaction)
+                .await?;
+            }
+        }
+        // Full buckets are no different from missing ones: we drop them,
+        // skipping those another request is working on.
+        sqlx::query!(
+            r#"DELETE FROM rate_limit_buckets
+            WHERE key IN (
+                SELECT key FROM rate_limit_buckets
+                WHERE full_at <= $1
+                LIMIT $2
+                FOR UPDATE SKIP LOCKED
+            )"#,
+            now,
+            STALE_BUCKETS_BATCH_SIZE
+        )
+        .execute(&mut transaction)
+        .await?;
+        transaction.commit().await?;
+        Ok(decision)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InMemoryRateLimitStore, RateLimitStore, MAX_IN_MEMORY_BUCKETS};
+    use crate::rate_limit::{Decision, TokenBucket};
+    use claim::assert_matches;
+
+    fn bucket() -> TokenBucket {
+        TokenBucket {
+            capacity: 1,
+            refill_interval: std::time::Duration::from_secs(60),
+        }
+    }
+
+    fn keys(keys: &[&str]) -> Vec<(String, TokenBucket)> {
+        keys.iter().map(|key| (key.to_string(), bucket())).collect()
+    }
+
+    #[tokio::test]
+    async fn in_memory_buckets_are_kept_per_key() {
+        let store = InMemoryRateLimitStore::new();
+
+        let first = store.take_all(&keys(&["a"])).await.unwrap();
+        let second = store.take_all(&keys(&["a"])).await.unwrap();
+        let other_key = store.take_all(&keys(&["b"])).await.unwrap();
+
+        assert_eq!(first, Decision::Allowed);
+        assert_matches!(second, Decision::Limited { .. });
+        assert_eq!(other_key, Decision::Allowed);
+    }
+
+    #[tokio::test]
+    async fn no_token_is_taken_when_one_of_the_buckets_is_empty() {
+        let store = InMemoryRateLimitStore::new();
+        store.take_all(&keys(&["a"])).await.unwrap();
+
+        let limited = store.take_all(&keys(&["a", "b"])).await.unwrap();
+        let other_key = store.take_all(&keys(&["b"])).await.unwrap();
+
+        assert_matches!(limited, Decision::Limited { .. });
+        assert_eq!(other_key, Decision::Allowed);
+    }
+
+    #[tokio::test]
+    async fn the_in_memory_store_holds_a_bounded_number_of_buckets() {
+        let store = InMemoryRateLimitStore::new();
+
+        // None of these buckets fills up again while the test runs.
+        for i in 0..MAX_IN_MEMORY_BUCKETS + 500 {
+            let key = i.to_string();
+            store.take_all(&keys(&[&key])).await.unwrap();
+        }
+        let latest = store
+            .take_all(&keys(&[&(MAX_IN_MEMORY_BUCKETS + 499).to_string()]))
+            .await
+            .unwrap();
+
+        assert!(store.buckets.lock().unwrap().len() <= MAX_IN_MEMORY_BUCKETS);
+        // The most recently used buckets are kept.
+        assert_matches!(latest, Decision::Limited { .. });
+    }
+}
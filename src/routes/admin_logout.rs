@@ -0,0 +1,11 @@
+//! src/routes/admin_logout.rs
+use crate::flash_messages::FlashMessage;
+use crate::session_state::TypedSession;
+use crate::utils::see_other;
+use actix_web::{HttpRequest, HttpResponse};
+
+pub async fn log_out(request: HttpRequest, session: TypedSession) -> HttpResponse {
+    session.log_out();
+    FlashMessage::info("You have successfully logged out.").send(&request);
+    see_other("/login")
+}
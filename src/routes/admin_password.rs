@@ -0,0 +1,167 @@
+//! src/routes/admin_password.rs
+use crate::authentication::{self, validate_credentials, AuthError, Credentials, UserId};
+use crate::flash_messages::{FlashMessage, IncomingFlashMessages};
+use crate::routes::{error_chain_fmt, get_username};
+use crate::session_state::TypedSession;
+use crate::session_store::delete_user_sessions;
+use crate::utils::see_other;
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+use std::fmt::Write;
+
+// Bounds on the length of a password, in characters.
+const MIN_PASSWORD_LENGTH: usize = 12;
+const MAX_PASSWORD_LENGTH: usize = 128;
+
+pub async fn change_password_form(flash_messages: IncomingFlashMessages) -> HttpResponse {
+    let mut messages_html = String::new();
+    for message in flash_messages.iter() {
+        writeln!(
+            messages_html,
+            "<p><i>{}</i></p>",
+            htmlescape::encode_minimal(message.content())
+        )
+        .unwrap();
+    }
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Change Password</title>
+</head>
+<body>
+    {}
+    <form action="/admin/password" method="post">
+        <label>Current password
+            <input
+                type="password"
+                placeholder="Enter current password"
+                name="current_password"
+            >
+        </label>
+        <br>
+        <label>New password
+            <input
+                type="password"
+                placeholder="Enter new password"
+                name="new_password"
+            >
+        </label>
+        <br>
+        <label>Confirm new password
+            <input
+                type="password"
+                placeholder="Type the new password again"
+                name="new_password_check"
+            >
+        </label>
+        <br>
+        <button type="submit">Change password</button>
+    </form>
+    <p><a href="/admin/dashboard">&lt;- Back</a></p>
+</body>
+</html>"#,
+            messages_html
+        ))
+}
+
+#[derive(serde::Deserialize)]
+pub struct ChangePasswordFormData {
+    current_password: Secret<String>,
+    new_password: Secret<String>,
+    new_password_check: Secret<String>,
+}
+
+#[tracing::instrument(
+    name = "Change the password of an admin",
+    skip(request, form, pool, session, user_id),
+    fields(user_id = %*user_id)
+)]
+pub async fn change_password(
+    request: HttpRequest,
+    form: web::Form<ChangePasswordFormData>,
+    pool: web::Data<PgPool>,
+    session: TypedSession,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, ChangePasswordError> {
+    let user_id = **user_id;
+    if form.new_password.expose_secret() != form.new_password_check.expose_secret() {
+        FlashMessage::error(
+            "You entered two different new passwords - the field values must match.",
+        )
+        .send(&request);
+        return Ok(see_other("/admin/password"));
+    }
+    if let Err(e) = validate_password_length(&form.new_password) {
+        FlashMessage::error(e).send(&request);
+        return Ok(see_other("/admin/password"));
+    }
+    let username = get_username(user_id, &pool).await?;
+    let credentials = Credentials {
+        username,
+        password: form.0.current_password,
+    };
+    if let Err(e) = validate_credentials(credentials, &pool).await {
+        return match e {
+            AuthError::InvalidCredentials(_) => {
+                FlashMessage::error("The current password is incorrect.").send(&request);
+                Ok(see_other("/admin/password"))
+            }
+            AuthError::UnexpectedError(_) => Err(anyhow::Error::new(e).into()),
+        };
+    }
+    authentication::change_password(user_id, form.0.new_password, &pool).await?;
+    // Anybody who got hold of the old password is logged out as well:
+    // we drop every session of the user and move the current one to a
+    // brand new key.
+    delete_user_sessions(&pool, user_id)
+        .await
+        .context("Failed to invalidate the other sessions of the user.")?;
+    session.renew();
+    FlashMessage::info("Your password has been changed.").send(&request);
+    Ok(see_other("/admin/password"))
+}
+
+fn validate_password_length(password: &Secret<String>) -> Result<(), String> {
+    let length = password.expose_secret().chars().count();
+    if length < MIN_PASSWORD_LENGTH {
+        Err(format!(
+            "The new password must be at least {} characters long.",
+            MIN_PASSWORD_LENGTH
+        ))
+    } else if length > MAX_PASSWORD_LENGTH {
+        Err(format!(
+            "The new password must be at most {} characters long.",
+            MAX_PASSWORD_LENGTH
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum ChangePasswordError {
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for ChangePasswordError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for ChangePasswordError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ChangePasswordError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
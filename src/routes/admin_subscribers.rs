@@ -0,0 +1,155 @@
+//! src/routes/admin_subscribers.rs
+use crate::authentication::UserId;
+use crate::flash_messages::{FlashMessage, IncomingFlashMessages};
+use crate::routes::error_chain_fmt;
+use crate::startup::EmailHashSecret;
+use crate::subscriber_data::{
+    erase_subscriber, export_subscriber_data, get_subscriber_id_by_email, SubscriberData,
+};
+use crate::utils::see_other;
+use actix_web::http::header::{ContentDisposition, ContentType, DispositionParam, DispositionType};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
+use sqlx::PgPool;
+use std::fmt::Write;
+
+pub async fn subscribers_form(flash_messages: IncomingFlashMessages) -> HttpResponse {
+    let mut messages_html = String::new();
+    for message in flash_messages.iter() {
+        writeln!(
+            messages_html,
+            "<p><i>{}</i></p>",
+            htmlescape::encode_minimal(message.content())
+        )
+        .unwrap();
+    }
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Subscriber data</title>
+</head>
+<body>
+    {}
+    <form action="/admin/subscribers/export" method="get">
+        <label>Email
+            <input type="email" placeholder="Enter the subscriber's email" name="email">
+        </label>
+        <button type="submit">Export their data</button>
+    </form>
+    <form action="/admin/subscribers/erase" method="post">
+        <label>Email
+            <input type="email" placeholder="Enter the subscriber's email" name="email">
+        </label>
+        <button type="submit">Erase their data</button>
+    </form>
+    <p><a href="/admin/dashboard">&lt;- Back</a></p>
+</body>
+</html>"#,
+            messages_html
+        ))
+}
+
+#[derive(serde::Deserialize)]
+pub struct SubscriberEmailData {
+    email: String,
+}
+
+#[tracing::instrument(
+    name = "Export subscriber data on behalf of an admin",
+    skip(parameters, pool),
+    fields(user_id=%*user_id)
+)]
+pub async fn export_subscriber(
+    parameters: web::Query<SubscriberEmailData>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, SubscriberDataError> {
+    let subscriber_id = get_subscriber_id_by_email(&pool, &parameters.email)
+        .await
+        .context("Failed to look up the subscriber by email.")?
+        .ok_or(SubscriberDataError::UnknownSubscriber)?;
+    let data = export_subscriber_data(&pool, subscriber_id)
+        .await
+        .context("Failed to export the subscriber data.")?
+        .ok_or(SubscriberDataError::UnknownSubscriber)?;
+    Ok(subscriber_data_response(&data))
+}
+
+/// Serve the data we hold about a subscriber as a JSON download.
+pub(crate) fn subscriber_data_response(data: &SubscriberData) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename("subscriber-data.json".into())],
+        })
+        .json(data)
+}
+
+#[tracing::instrument(
+    name = "Erase subscriber data on behalf of an admin",
+    skip(request, form, pool, email_hash_secret),
+    fields(user_id=%*user_id)
+)]
+pub async fn erase_subscriber_data(
+    request: HttpRequest,
+    form: web::Form<SubscriberEmailData>,
+    pool: web::Data<PgPool>,
+    email_hash_secret: web::Data<EmailHashSecret>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, SubscriberDataError> {
+    let subscriber_id = match get_subscriber_id_by_email(&pool, &form.email)
+        .await
+        .context("Failed to look up the subscriber by email.")?
+    {
+        Some(subscriber_id) => subscriber_id,
+        None => {
+            FlashMessage::error(format!(
+                "There is no subscriber with the address {}.",
+                form.email
+            ))
+            .send(&request);
+            return Ok(see_other("/admin/subscribers"));
+        }
+    };
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    erase_subscriber(&mut transaction, subscriber_id, &email_hash_secret)
+        .await
+        .context("Failed to erase the subscriber.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to erase a subscriber.")?;
+    FlashMessage::info(format!("The data of {} has been erased.", form.email)).send(&request);
+    Ok(see_other("/admin/subscribers"))
+}
+
+#[derive(thiserror::Error)]
+pub enum SubscriberDataError {
+    #[error("There is no subscriber with this address.")]
+    UnknownSubscriber,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for SubscriberDataError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for SubscriberDataError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            SubscriberDataError::UnknownSubscriber => StatusCode::NOT_FOUND,
+            SubscriberDataError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
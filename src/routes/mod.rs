@@ -1,10 +1,34 @@
 //! src/routes/mod.rs
 
+mod admin_dashboard;
+mod admin_logout;
+mod admin_password;
+mod admin_subscribers;
 mod health_check;
+mod login;
+mod newsletters;
 mod subscriptions;
 // New module!
 mod subscriptions_confirm;
+mod subscriptions_confirm_email_change;
+mod subscriptions_data_request;
+mod subscriptions_preferences;
+mod subscriptions_resend_confirmation;
+mod subscriptions_unsubscribe;
+mod webhooks_postmark;
 
+pub use admin_dashboard::*;
+pub use admin_logout::*;
+pub use admin_password::*;
+pub use admin_subscribers::*;
 pub use health_check::*;
+pub use login::*;
+pub use newsletters::*;
 pub use subscriptions::*;
 pub use subscriptions_confirm::*;
+pub use subscriptions_confirm_email_change::*;
+pub use subscriptions_data_request::*;
+pub use subscriptions_preferences::*;
+pub use subscriptions_resend_confirmation::*;
+pub use subscriptions_unsubscribe::*;
+pub use webhooks_postmark::*;
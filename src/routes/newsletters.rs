@@ -0,0 +1,181 @@
+//! src/routes/newsletters.rs
+use crate::authentication::AuthenticatedUser;
+use crate::domain::{SendFrequency, SubscriptionStatus};
+use crate::mailing_lists::{get_list_by_slug, DEFAULT_LIST};
+use crate::markdown;
+use crate::routes::error_chain_fmt;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+#[derive(serde::Deserialize)]
+pub struct BodyData {
+    title: String,
+    /// The slug of the list to publish to - the default list if omitted.
+    list: Option<String>,
+    content: Content,
+}
+
+/// The body of an issue: either written in Markdown, or provided in both
+/// formats we send.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+pub enum Content {
+    Markdown { markdown: String },
+    Rendered { html: String, text: String },
+}
+
+impl Content {
+    /// The HTML and plain text bodies of the issue.
+    fn render(self) -> (String, String) {
+        match self {
+            Content::Markdown { markdown } => (
+                markdown::to_html(&markdown),
+                markdown::to_plain_text(&markdown),
+            ),
+            Content::Rendered { html, text } => (html, text),
+        }
+    }
+}
+
+// Publishing only records the issue and queues one delivery task per
+// confirmed member of the list: the actual sending is carried out in the background
+// by the issue delivery worker, hence the `202 Accepted`.
+#[tracing::instrument(
+    name = "Publish a newsletter issue",
+    skip(body, pool, user),
+    fields(newsletter_title = %body.title, user_id = %user.user_id)
+)]
+pub async fn publish_newsletter(
+    body: web::Json<BodyData>,
+    pool: web::Data<PgPool>,
+    // Only authenticated users can publish
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, PublishError> {
+    let BodyData {
+        title,
+        list,
+        content,
+    } = body.0;
+    let list = get_list_by_slug(&pool, list.as_deref())
+        .await
+        .context("Failed to look up the mailing list.")?
+        .ok_or_else(|| {
+            PublishError::ValidationError(format!(
+                "There is no `{}` mailing list.",
+                list.as_deref().unwrap_or(DEFAULT_LIST)
+            ))
+        })?;
+    let (html_content, text_content) = content.render();
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let issue_id = insert_newsletter_issue(
+        &mut transaction,
+        list.list_id,
+        &title,
+        &text_content,
+        &html_content,
+    )
+    .await
+    .context("Failed to store newsletter issue details")?;
+    enqueue_delivery_tasks(&mut transaction, issue_id, list.list_id)
+        .await
+        .context("Failed to enqueue delivery tasks")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to publish a newsletter issue")?;
+    Ok(HttpResponse::Accepted().finish())
+}
+
+#[derive(thiserror::Error)]
+pub enum PublishError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for PublishError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for PublishError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PublishError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            PublishError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[tracing::instrument(skip_all)]
+async fn insert_newsletter_issue(
+    transaction: &mut Transaction<'_, Postgres>,
+    list_id: Uuid,
+    title: &str,
+    text_content: &str,
+    html_content: &str,
+) -> Result<Uuid, sqlx::Error> {
+    let newsletter_issue_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"INSERT INTO newsletter_issues (
+            newsletter_issue_id,
+            list_id,
+            title,
+            text_content,
+            html_content,
+            published_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6)"#,
+        newsletter_issue_id,
+        list_id,
+        title,
+        text_content,
+        html_content,
+        Utc::now()
+    )
+    .execute(transaction)
+    .await?;
+    Ok(newsletter_issue_id)
+}
+
+/// Queue a delivery for each confirmed member of the list, held back
+/// until the next slot of their send frequency.
+#[tracing::instrument(skip_all)]
+async fn enqueue_delivery_tasks(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+    list_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    let now = Utc::now();
+    sqlx::query!(
+        r#"INSERT INTO issue_delivery_queue (newsletter_issue_id, subscriber_id, execute_after)
+        SELECT $1, m.subscriber_id,
+            CASE s.send_frequency
+                WHEN $4 THEN $5::timestamptz
+                WHEN $6 THEN $7::timestamptz
+                ELSE now()
+            END
+        FROM list_memberships m
+        JOIN subscriptions s ON s.id = m.subscriber_id
+        WHERE m.list_id = $2 AND m.status = $3"#,
+        newsletter_issue_id,
+        list_id,
+        SubscriptionStatus::Confirmed.as_str(),
+        SendFrequency::Daily.as_str(),
+        SendFrequency::Daily.next_slot(now),
+        SendFrequency::Weekly.as_str(),
+        SendFrequency::Weekly.next_slot(now),
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
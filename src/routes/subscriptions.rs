@@ -1,19 +1,34 @@
 //! src/routes/subscriptions.rs
-use crate::domain::{NewSubscriber, SubscriberEmail, SubscriberName};
-use crate::email_client::EmailClient;
-use crate::startup::ApplicationBaseUrl;
+use crate::domain::{
+    IllegalTransition, NewSubscriber, SubscriberEmail, SubscriberLocale, SubscriberName,
+    SubscriptionStatus,
+};
+use crate::email_outbox::{enqueue_email, OutboxEmail, OutboxEmailKind};
+use crate::email_templates::{ConfirmationEmail, EmailTemplates};
+use crate::mailing_lists::{get_list_by_slug, MailingList, DEFAULT_LIST};
+use crate::routes::{get_preferences_version, preferences_link, unsubscribe_link};
+use crate::startup::{ApplicationBaseUrl, EmailHashSecret, HmacSecret};
+use crate::subscriber_data::is_erased;
 
-use actix_web::{web, HttpResponse};
+use actix_web::http::header::{AcceptLanguage, Preference};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
 use chrono::Utc;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
-use sqlx::PgPool;
+use sqlx::{PgPool, Postgres, Transaction};
 use uuid::Uuid;
 
 #[derive(serde::Deserialize)]
 pub struct FormData {
     email: String,
     name: String,
+    /// The language the subscriber wants to receive emails in, when they
+    /// picked one - `Accept-Language` is used otherwise.
+    locale: Option<String>,
+    /// The slug of the list to subscribe to - the default list if omitted.
+    list: Option<String>,
 }
 
 impl TryFrom<FormData> for NewSubscriber {
@@ -22,7 +37,16 @@ impl TryFrom<FormData> for NewSubscriber {
     fn try_from(value: FormData) -> Result<Self, Self::Error> {
         let name = SubscriberName::parse(value.name)?;
         let email = SubscriberEmail::parse(value.email)?;
-        Ok(Self { email, name })
+        let locale = value
+            .locale
+            .map(SubscriberLocale::parse)
+            .transpose()?
+            .unwrap_or_default();
+        Ok(Self {
+            email,
+            name,
+            locale,
+        })
     }
 }
 
@@ -45,7 +69,15 @@ impl TryFrom<FormData> for NewSubscriber {
     // the message associated to the function span
     // - if omitted, it defaults to the function name.
     name = "Adding a new subscriber",
-    skip(form, pool, email_client, base_url),
+    skip(
+        form,
+        accept_language,
+        pool,
+        base_url,
+        templates,
+        hmac_secret,
+        email_hash_secret
+    ),
     fields(
         subscriber_email = %form.email,
         subscriber_name= %form.name
@@ -53,96 +85,346 @@ impl TryFrom<FormData> for NewSubscriber {
 )]
 pub async fn subscribe(
     form: web::Form<FormData>,
+    accept_language: Option<web::Header<AcceptLanguage>>,
     pool: web::Data<PgPool>,
-    // Get the email client from the app context
-    email_client: web::Data<EmailClient>,
     // New parameter!
     base_url: web::Data<ApplicationBaseUrl>,
-) -> HttpResponse {
-    let new_subscriber = match form.0.try_into() {
-        Ok(form) => form,
-        Err(_) => return HttpResponse::BadRequest().finish(),
-    };
-    let subscriber_id = match insert_subscriber(&pool, &new_subscriber).await {
-        Ok(subscriber_id) => subscriber_id,
-        Err(_) => return HttpResponse::InternalServerError().finish(),
-    };
-    let subscription_token = generate_subscription_token();
-    if store_token(&pool, subscriber_id, &subscription_token)
+    templates: web::Data<EmailTemplates>,
+    hmac_secret: web::Data<HmacSecret>,
+    email_hash_secret: web::Data<EmailHashSecret>,
+) -> Result<HttpResponse, SubscribeError> {
+    let mut form = form.0;
+    let list = get_list_by_slug(&pool, form.list.as_deref())
+        .await
+        .context("Failed to look up the mailing list.")?
+        .ok_or_else(|| {
+            SubscribeError::ValidationError(format!(
+                "There is no `{}` mailing list.",
+                form.list.as_deref().unwrap_or(DEFAULT_LIST)
+            ))
+        })?;
+    if form.locale.is_none() {
+        form.locale = accept_language.and_then(|header| negotiate_locale(&header, &templates));
+    }
+    let new_subscriber: NewSubscriber = form.try_into().map_err(SubscribeError::ValidationError)?;
+    // The owner of the address had us erase their data: we do not bring
+    // it back on a whim.
+    if is_erased(&pool, new_subscriber.email.as_ref(), &email_hash_secret)
+        .await
+        .context("Failed to look up the erased addresses.")?
+    {
+        return Ok(HttpResponse::Ok().finish());
+    }
+    // Every write below goes through the same transaction: we never want
+    // to leave behind a subscriber that has no way to confirm.
+    // The confirmation email is part of it too - it is written to the outbox
+    // and delivered in the background, so that a slow or unavailable email
+    // provider cannot fail the request.
+    let mut transaction = pool
+        .begin()
         .await
-        .is_err()
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    // Concurrent submissions of the same address queue up on the row of
+    // the subscriber: the first one to get there creates it, the others
+    // find it once it is committed.
+    let (subscriber_id, existing_subscriber) =
+        match insert_subscriber(&mut transaction, &new_subscriber)
+            .await
+            .context("Failed to insert new subscriber in the database.")?
+        {
+            Some(subscriber_id) => (subscriber_id, None),
+            None => {
+                let subscriber = get_subscriber_by_email(&mut transaction, &new_subscriber.email)
+                    .await
+                    .context("Failed to look up the subscriber by email.")?
+                    .context("The address is taken, yet there is no subscriber for it.")?;
+                (subscriber.id, Some(subscriber))
+            }
+        };
+    // We stopped mailing addresses that bounced or reported us as spam:
+    // a confirmation email would not fare any better, whatever the list.
+    if existing_subscriber
+        .as_ref()
+        .is_some_and(|subscriber| subscriber.is_suppressed)
     {
-        return HttpResponse::InternalServerError().finish();
+        return Ok(HttpResponse::Ok().finish());
     }
-    if send_confirmation_email(
-        &email_client,
-        new_subscriber,
+    let membership = match &existing_subscriber {
+        Some(subscriber) => get_list_membership(&mut transaction, subscriber.id, list.list_id)
+            .await
+            .context("Failed to look up the list membership of the subscriber.")?,
+        None => None,
+    };
+    let pending =
+        match request_list_membership(&mut transaction, subscriber_id, list.list_id, membership)
+            .await?
+        {
+            Some(pending) => pending,
+            // Submitting the form again is not an error: there is nothing
+            // left to do for subscribers who already confirmed.
+            None => return Ok(HttpResponse::Ok().finish()),
+        };
+    if existing_subscriber.is_some() {
+        update_subscriber_locale(&mut transaction, subscriber_id, &new_subscriber.locale)
+            .await
+            .context("Failed to update the locale of the subscriber.")?;
+    }
+    enqueue_confirmation_email(
+        &mut transaction,
+        &templates,
+        subscriber_id,
+        &new_subscriber.email,
+        new_subscriber.name.as_ref(),
+        &new_subscriber.locale,
+        &list,
         &base_url.0,
-        &subscription_token,
+        &hmac_secret,
+        &pending,
     )
     .await
-    .is_err()
-    {
-        return HttpResponse::InternalServerError().finish();
+    .context("Failed to enqueue a confirmation email.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to store a new subscriber.")?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(thiserror::Error)]
+pub enum SubscribeError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for SubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for SubscribeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            SubscribeError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            SubscribeError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Format an error followed by its whole chain of causes.
+///
+/// Used to implement `Debug` for the errors returned by our request
+/// handlers: `TracingLogger` records the `Debug` representation of the
+/// error when a request fails, so the full chain ends up in the logs,
+/// exactly once.
+pub fn error_chain_fmt(
+    e: &impl std::error::Error,
+    f: &mut std::fmt::Formatter<'_>,
+) -> std::fmt::Result {
+    writeln!(f, "{}\n", e)?;
+    let mut current = e.source();
+    while let Some(cause) = current {
+        writeln!(f, "Caused by:\n\t{}", cause)?;
+        current = cause.source();
     }
-    HttpResponse::Ok().finish()
+    Ok(())
 }
 
 #[tracing::instrument(
     name = "Store subscription token in the database",
-    skip(subscription_token, pool)
+    skip(subscription_token, transaction)
 )]
 pub async fn store_token(
-    pool: &PgPool,
+    transaction: &mut Transaction<'_, Postgres>,
     subscriber_id: Uuid,
+    list_id: Uuid,
     subscription_token: &str,
 ) -> Result<(), sqlx::Error> {
     sqlx::query!(
-        r#"INSERT INTO subscription_tokens (subscription_token, subscriber_id)
-        VALUES ($1, $2)"#,
+        r#"INSERT INTO subscription_tokens (subscription_token, subscriber_id, list_id, issued_at)
+        VALUES ($1, $2, $3, $4)"#,
         subscription_token,
-        subscriber_id
+        subscriber_id,
+        list_id,
+        Utc::now()
     )
-    .execute(pool)
-    .await
-    .map_err(|e| {
-        tracing::error!("Failed to execute query: {:?}", e);
-        e
-    })?;
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+/// Replace all the outstanding subscription tokens of a list membership
+/// with a freshly generated one, which is returned.
+pub async fn rotate_subscription_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+) -> Result<String, sqlx::Error> {
+    delete_subscription_tokens(transaction, subscriber_id, list_id).await?;
+    let subscription_token = generate_subscription_token();
+    store_token(transaction, subscriber_id, list_id, &subscription_token).await?;
+    Ok(subscription_token)
+}
+
+#[tracing::instrument(name = "Delete subscription tokens", skip(transaction, subscriber_id))]
+pub async fn delete_subscription_tokens(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"DELETE FROM subscription_tokens WHERE subscriber_id = $1 AND list_id = $2"#,
+        subscriber_id,
+        list_id,
+    )
+    .execute(transaction)
+    .await?;
     Ok(())
 }
 
 #[tracing::instrument(
-    name = "Send a confirmation email to a new subscriber",
-    skip(email_client, new_subscriber, base_url, subscription_token)
+    name = "Store unsubscribe token in the database",
+    skip(unsubscribe_token, transaction)
 )]
-pub async fn send_confirmation_email(
-    email_client: &EmailClient,
-    new_subscriber: NewSubscriber,
+pub async fn store_unsubscribe_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+    unsubscribe_token: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"INSERT INTO unsubscribe_tokens (unsubscribe_token, subscriber_id, list_id)
+        VALUES ($1, $2, $3)"#,
+        unsubscribe_token,
+        subscriber_id,
+        list_id
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+/// The tokens a confirmation email for a list membership is built with.
+pub struct PendingMembership {
+    pub subscription_token: String,
+    pub unsubscribe_token: String,
+}
+
+/// Put a subscriber on a list, pending confirmation - or return `None` if
+/// they already confirmed their membership.
+///
+/// Pending members get a fresh confirmation link, while people who left
+/// the list have to go through double opt-in again.
+pub async fn request_list_membership(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+    membership: Option<ListMembership>,
+) -> Result<Option<PendingMembership>, anyhow::Error> {
+    let pending = match membership {
+        None => {
+            insert_list_membership(transaction, subscriber_id, list_id)
+                .await
+                .context("Failed to add the subscriber to the mailing list.")?;
+            let subscription_token = generate_subscription_token();
+            store_token(transaction, subscriber_id, list_id, &subscription_token)
+                .await
+                .context("Failed to store the confirmation token for a new subscriber.")?;
+            let unsubscribe_token = generate_subscription_token();
+            store_unsubscribe_token(transaction, subscriber_id, list_id, &unsubscribe_token)
+                .await
+                .context("Failed to store the unsubscribe token for a new subscriber.")?;
+            PendingMembership {
+                subscription_token,
+                unsubscribe_token,
+            }
+        }
+        Some(membership) if membership.status == SubscriptionStatus::Confirmed => return Ok(None),
+        Some(membership) => {
+            change_subscription_status(
+                transaction,
+                subscriber_id,
+                list_id,
+                SubscriptionStatus::PendingConfirmation,
+            )
+            .await
+            .context("Failed to mark the subscriber as pending confirmation.")?;
+            let subscription_token = rotate_subscription_token(transaction, subscriber_id, list_id)
+                .await
+                .context("Failed to rotate the confirmation token of a subscriber.")?;
+            PendingMembership {
+                subscription_token,
+                unsubscribe_token: membership.unsubscribe_token,
+            }
+        }
+    };
+    Ok(Some(pending))
+}
+
+#[tracing::instrument(
+    name = "Enqueue a confirmation email for a new subscriber",
+    skip(
+        transaction,
+        templates,
+        recipient,
+        name,
+        locale,
+        list,
+        base_url,
+        hmac_secret,
+        pending
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn enqueue_confirmation_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    templates: &EmailTemplates,
+    subscriber_id: Uuid,
+    recipient: &SubscriberEmail,
+    name: &str,
+    locale: &SubscriberLocale,
+    list: &MailingList,
     // New parameter!
     base_url: &str,
-    // New parameter!
-    subscription_token: &str,
-) -> Result<(), reqwest::Error> {
+    hmac_secret: &HmacSecret,
+    pending: &PendingMembership,
+) -> Result<(), anyhow::Error> {
     // Build a confirmation link with a dynamic root
     let confirmation_link = format!(
         "{}/subscriptions/confirm?subscription_token={}",
-        base_url, subscription_token
-    );
-    let plain_body = format!(
-        "Welcome to our newsletter!\nVisit {} to confirm your subscription.",
-        confirmation_link
-    );
-    let html_body = format!(
-        "Welcome to our newsletter!<br />\
-        Click <a href=\"{}\">here</a> to confirm your subscription.",
-        confirmation_link
+        base_url, pending.subscription_token
     );
-    // Send a (useless) email to the new subscriber.
-    // We are ignoring email delivery errors for now.
-    email_client
-        .send_email(new_subscriber.email, "Welcome!", &html_body, &plain_body)
-        .await
+    let unsubscribe_link = unsubscribe_link(base_url, &pending.unsubscribe_token);
+    let preferences_version = get_preferences_version(transaction, subscriber_id).await?;
+    let preferences_link =
+        preferences_link(base_url, subscriber_id, preferences_version, hmac_secret);
+    let email = templates.render(
+        locale,
+        &ConfirmationEmail {
+            name,
+            list_name: &list.name,
+            confirmation_link: &confirmation_link,
+            unsubscribe_link: &unsubscribe_link,
+            preferences_link: &preferences_link,
+            base_url,
+        },
+    )?;
+    enqueue_email(
+        transaction,
+        subscriber_id,
+        OutboxEmail {
+            kind: OutboxEmailKind::Confirmation,
+            recipient,
+            subject: &email.subject,
+            html_body: &email.html_body,
+            text_body: &email.text_body,
+            unsubscribe_link: Some(&unsubscribe_link),
+        },
+    )
+    .await?;
+    Ok(())
 }
 
 // `insert_subscriber` takes care of the
@@ -151,32 +433,216 @@ pub async fn send_confirmation_email(
 // we are not passing `web::Form` or `web::Data` wrappers as input types
 #[tracing::instrument(
     name = "Saving new subscriber details in the database",
-    skip(new_subscriber, pool)
+    skip(new_subscriber, transaction)
 )]
+/// Returns `None`, leaving the table untouched, if the address belongs to
+/// a subscriber already - waiting for any concurrent transaction inserting
+/// it to be done first.
 pub async fn insert_subscriber(
-    pool: &PgPool,
+    transaction: &mut Transaction<'_, Postgres>,
     new_subscriber: &NewSubscriber,
-) -> Result<Uuid, sqlx::Error> {
+) -> Result<Option<Uuid>, sqlx::Error> {
     let subscriber_id = Uuid::new_v4();
-    sqlx::query!(
-        r#"INSERT INTO subscriptions (id, email, name, subscribed_at, status)
-        VALUES ($1, $2, $3, $4, 'pending_confirmation')"#,
+    let outcome = sqlx::query!(
+        r#"INSERT INTO subscriptions (id, email, name, subscribed_at, locale)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (email) DO NOTHING"#,
         subscriber_id,
         new_subscriber.email.as_ref(),
         new_subscriber.name.as_ref(),
+        Utc::now(),
+        new_subscriber.locale.as_ref()
+    )
+    .execute(transaction)
+    .await?;
+    Ok((outcome.rows_affected() > 0).then_some(subscriber_id))
+}
+
+#[tracing::instrument(
+    name = "Add a subscriber to a mailing list",
+    skip(transaction, subscriber_id)
+)]
+pub async fn insert_list_membership(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"INSERT INTO list_memberships (subscriber_id, list_id, status, subscribed_at)
+        VALUES ($1, $2, $3, $4)"#,
+        subscriber_id,
+        list_id,
+        SubscriptionStatus::PendingConfirmation.as_str(),
         Utc::now()
     )
-    .execute(pool)
-    .await
-    .map_err(|e| {
-        tracing::error!("Failed to execute query: {:?}", e);
-        e
-    })?;
-    Ok(subscriber_id)
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+/// A subscriber identity, shared by all the lists they are on.
+pub struct ExistingSubscriber {
+    pub id: Uuid,
+    pub name: String,
+    pub locale: String,
+    /// Whether we stopped mailing the address on any of its lists - delivery
+    /// failures and complaints are about the address, not a single list.
+    pub is_suppressed: bool,
+}
+
+/// The subscriber's row stays locked until `transaction` ends: concurrent
+/// requests for the same address are handled one after the other.
+#[tracing::instrument(name = "Get subscriber by email", skip(transaction, email))]
+pub async fn get_subscriber_by_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &SubscriberEmail,
+) -> Result<Option<ExistingSubscriber>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT s.id, s.name, s.locale, EXISTS (
+            SELECT 1 FROM list_memberships m
+            WHERE m.subscriber_id = s.id AND m.status IN ('bounced', 'complained')
+        ) AS "is_suppressed!"
+        FROM subscriptions s
+        WHERE s.email = $1
+        FOR UPDATE OF s"#,
+        email.as_ref(),
+    )
+    .fetch_optional(transaction)
+    .await?;
+    Ok(row.map(|row| ExistingSubscriber {
+        id: row.id,
+        name: row.name,
+        locale: row.locale,
+        is_suppressed: row.is_suppressed,
+    }))
+}
+
+/// Where a subscriber stands with respect to one of our lists.
+pub struct ListMembership {
+    pub status: SubscriptionStatus,
+    pub unsubscribe_token: String,
+}
+
+#[tracing::instrument(name = "Get list membership", skip(transaction))]
+pub async fn get_list_membership(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+) -> Result<Option<ListMembership>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT m.status, u.unsubscribe_token
+        FROM list_memberships m
+        JOIN unsubscribe_tokens u
+            ON u.subscriber_id = m.subscriber_id AND u.list_id = m.list_id
+        WHERE m.subscriber_id = $1 AND m.list_id = $2"#,
+        subscriber_id,
+        list_id,
+    )
+    .fetch_optional(transaction)
+    .await?;
+    row.map(|row| {
+        Ok(ListMembership {
+            status: parse_status(&row.status)?,
+            unsubscribe_token: row.unsubscribe_token,
+        })
+    })
+    .transpose()
+}
+
+/// Move a list membership to `next`, provided its current status allows it.
+///
+/// The membership's row stays locked until `transaction` ends: concurrent
+/// changes cannot sneak in between the check and the update.
+#[tracing::instrument(name = "Change the status of a list membership", skip(transaction))]
+pub async fn change_subscription_status(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+    next: SubscriptionStatus,
+) -> Result<(), ChangeStatusError> {
+    let row = sqlx::query!(
+        r#"SELECT status FROM list_memberships
+        WHERE subscriber_id = $1 AND list_id = $2
+        FOR UPDATE"#,
+        subscriber_id,
+        list_id,
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+    let current = parse_status(&row.status)?;
+    if current.transition_to(next)? != current {
+        sqlx::query!(
+            r#"UPDATE list_memberships SET status = $3
+            WHERE subscriber_id = $1 AND list_id = $2"#,
+            subscriber_id,
+            list_id,
+            next.as_str()
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+    Ok(())
+}
+
+#[derive(thiserror::Error)]
+pub enum ChangeStatusError {
+    #[error(transparent)]
+    IllegalTransition(#[from] IllegalTransition),
+    #[error("Failed to change the status of the subscriber.")]
+    DatabaseError(#[from] sqlx::Error),
+}
+
+impl std::fmt::Debug for ChangeStatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+/// Parse a status read from the `list_memberships` table.
+pub(crate) fn parse_status(status: &str) -> Result<SubscriptionStatus, sqlx::Error> {
+    status
+        .parse()
+        .map_err(|e: String| sqlx::Error::Decode(e.into()))
+}
+
+#[tracing::instrument(
+    name = "Update the locale of a subscriber",
+    skip(transaction, subscriber_id)
+)]
+pub async fn update_subscriber_locale(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    locale: &SubscriberLocale,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE subscriptions SET locale = $2 WHERE id = $1"#,
+        subscriber_id,
+        locale.as_ref()
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+/// The first of the languages listed in `Accept-Language`, by order of
+/// preference, that we have a message catalog for.
+fn negotiate_locale(
+    accept_language: &AcceptLanguage,
+    templates: &EmailTemplates,
+) -> Option<String> {
+    accept_language
+        .ranked()
+        .into_iter()
+        .filter_map(|preference| match preference {
+            Preference::Specific(tag) => SubscriberLocale::parse(tag.to_string()).ok(),
+            Preference::Any => None,
+        })
+        .find(|locale| templates.supports(locale))
+        .map(|locale| locale.as_ref().to_owned())
 }
 
 /// Generate a random 25-characters-long case-sensitive subscription token.
-fn generate_subscription_token() -> String {
+pub(crate) fn generate_subscription_token() -> String {
     let mut rng = thread_rng();
     std::iter::repeat_with(|| rng.sample(Alphanumeric))
         .map(char::from)
@@ -1,6 +1,12 @@
 //! src/routes/subscriptions_confirm.rs
 
-use actix_web::{web, HttpResponse};
+use crate::domain::{IllegalTransition, SubscriptionStatus};
+use crate::routes::{change_subscription_status, error_chain_fmt, ChangeStatusError};
+use crate::startup::SubscriptionTokenTtl;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
 use sqlx::PgPool;
 use uuid::Uuid;
 
@@ -9,53 +15,114 @@ pub struct Parameters {
     subscription_token: String,
 }
 
-#[tracing::instrument(name = "Confirm a pending subscriber", skip(parameters, pool))]
-pub async fn confirm(parameters: web::Query<Parameters>, pool: web::Data<PgPool>) -> HttpResponse {
-    let id = match get_subscriber_id_from_token(&pool, &parameters.subscription_token).await {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::InternalServerError().finish(),
-    };
-    match id {
-        // Non-existing token!
-        None => HttpResponse::Unauthorized().finish(),
-        Some(subscriber_id) => {
-            if confirm_subscriber(&pool, subscriber_id).await.is_err() {
-                return HttpResponse::InternalServerError().finish();
-            }
-            HttpResponse::Ok().finish()
+#[tracing::instrument(
+    name = "Confirm a pending subscriber",
+    skip(parameters, pool, token_ttl)
+)]
+pub async fn confirm(
+    parameters: web::Query<Parameters>,
+    pool: web::Data<PgPool>,
+    token_ttl: web::Data<SubscriptionTokenTtl>,
+) -> Result<HttpResponse, ConfirmError> {
+    let token = get_subscription_token(&pool, &parameters.subscription_token)
+        .await
+        .context("Failed to retrieve the subscription token.")?
+        .ok_or(ConfirmError::UnknownToken)?;
+    // The link is genuine but too old: the subscriber has to ask
+    // for a new one.
+    if token.is_expired(token_ttl.0) {
+        return Err(ConfirmError::ExpiredToken);
+    }
+    confirm_subscriber(&pool, token.subscriber_id, token.list_id)
+        .await
+        .map_err(|e| match e {
+            // e.g. they unsubscribed, then followed an old link.
+            ChangeStatusError::IllegalTransition(e) => ConfirmError::IllegalTransition(e),
+            e @ ChangeStatusError::DatabaseError(_) => ConfirmError::UnexpectedError(
+                anyhow::Error::new(e)
+                    .context("Failed to update the subscriber status to `confirmed`."),
+            ),
+        })?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(thiserror::Error)]
+pub enum ConfirmError {
+    #[error("There is no subscriber associated with the provided token.")]
+    UnknownToken,
+    #[error("The subscription token has expired.")]
+    ExpiredToken,
+    #[error("The subscription can no longer be confirmed.")]
+    IllegalTransition(#[source] IllegalTransition),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for ConfirmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for ConfirmError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ConfirmError::UnknownToken => StatusCode::UNAUTHORIZED,
+            ConfirmError::ExpiredToken => StatusCode::GONE,
+            ConfirmError::IllegalTransition(_) => StatusCode::CONFLICT,
+            ConfirmError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+pub struct StoredSubscriptionToken {
+    pub subscriber_id: Uuid,
+    /// The list the subscriber asked to join - each token confirms a
+    /// single membership.
+    pub list_id: Uuid,
+    pub issued_at: DateTime<Utc>,
+}
+
+impl StoredSubscriptionToken {
+    pub fn is_expired(&self, ttl: std::time::Duration) -> bool {
+        match chrono::Duration::from_std(ttl) {
+            Ok(ttl) => self.issued_at + ttl < Utc::now(),
+            // A TTL too large to be represented never expires.
+            Err(_) => false,
         }
     }
 }
 
 #[tracing::instrument(name = "Mark subscriber as confirmed", skip(subscriber_id, pool))]
-pub async fn confirm_subscriber(pool: &PgPool, subscriber_id: Uuid) -> Result<(), sqlx::Error> {
-    sqlx::query!(
-        r#"UPDATE subscriptions SET status = 'confirmed' WHERE id = $1"#,
+pub async fn confirm_subscriber(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+) -> Result<(), ChangeStatusError> {
+    let mut transaction = pool.begin().await?;
+    change_subscription_status(
+        &mut transaction,
         subscriber_id,
+        list_id,
+        SubscriptionStatus::Confirmed,
     )
-    .execute(pool)
-    .await
-    .map_err(|e| {
-        tracing::error!("Failed to execute query: {:?}", e);
-        e
-    })?;
+    .await?;
+    transaction.commit().await?;
     Ok(())
 }
 
-#[tracing::instrument(name = "Get subscriber_id from token", skip(subscription_token, pool))]
-pub async fn get_subscriber_id_from_token(
+#[tracing::instrument(name = "Get subscription token", skip(subscription_token, pool))]
+pub async fn get_subscription_token(
     pool: &PgPool,
     subscription_token: &str,
-) -> Result<Option<Uuid>, sqlx::Error> {
-    let result = sqlx::query!(
-        r#"SELECT subscriber_id FROM subscription_tokens WHERE subscription_token = $1"#,
+) -> Result<Option<StoredSubscriptionToken>, sqlx::Error> {
+    let result = sqlx::query_as!(
+        StoredSubscriptionToken,
+        r#"SELECT subscriber_id, list_id, issued_at FROM subscription_tokens
+        WHERE subscription_token = $1"#,
         subscription_token,
     )
     .fetch_optional(pool)
-    .await
-    .map_err(|e| {
-        tracing::error!("Failed to execute query: {:?}", e);
-        e
-    })?;
-    Ok(result.map(|r| r.subscriber_id))
+    .await?;
+    Ok(result)
 }
@@ -0,0 +1,187 @@
+//! src/routes/subscriptions_confirm_email_change.rs
+
+use crate::routes::error_chain_fmt;
+use crate::startup::{EmailHashSecret, SubscriptionTokenTtl};
+use crate::subscriber_data::is_erased;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+#[derive(serde::Deserialize)]
+pub struct EmailChangeParameters {
+    token: String,
+}
+
+#[tracing::instrument(
+    name = "Confirm a change of email address",
+    skip(parameters, pool, token_ttl, email_hash_secret)
+)]
+pub async fn confirm_email_change(
+    parameters: web::Query<EmailChangeParameters>,
+    pool: web::Data<PgPool>,
+    token_ttl: web::Data<SubscriptionTokenTtl>,
+    email_hash_secret: web::Data<EmailHashSecret>,
+) -> Result<HttpResponse, ConfirmEmailChangeError> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let request = get_email_change_request(&mut transaction, &parameters.token)
+        .await
+        .context("Failed to retrieve the email change request.")?
+        .ok_or(ConfirmEmailChangeError::UnknownToken)?;
+    if request.is_expired(token_ttl.0) {
+        return Err(ConfirmEmailChangeError::ExpiredToken);
+    }
+    // The owner of the address had us erase their data: as when
+    // subscribing, we do not bring it back on a whim.
+    if is_erased(&pool, &request.new_email, &email_hash_secret)
+        .await
+        .context("Failed to look up the erased addresses.")?
+    {
+        drop_email_change_requests(&mut transaction, request.subscriber_id)
+            .await
+            .context("Failed to drop the email change request.")?;
+        transaction
+            .commit()
+            .await
+            .context("Failed to commit SQL transaction to drop an email change request.")?;
+        return Ok(HttpResponse::Ok().finish());
+    }
+    // e.g. the address subscribed on its own since the change was requested.
+    if is_email_taken(&mut transaction, request.subscriber_id, &request.new_email)
+        .await
+        .context("Failed to check whether the new email address is taken.")?
+    {
+        return Err(ConfirmEmailChangeError::EmailTaken);
+    }
+    change_email(&mut transaction, request.subscriber_id, &request.new_email)
+        .await
+        .context("Failed to change the email address of the subscriber.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to change an email address.")?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(thiserror::Error)]
+pub enum ConfirmEmailChangeError {
+    #[error("There is no email change request associated with the provided token.")]
+    UnknownToken,
+    #[error("The email change token has expired.")]
+    ExpiredToken,
+    #[error("The new email address is already used by another subscriber.")]
+    EmailTaken,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for ConfirmEmailChangeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for ConfirmEmailChangeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ConfirmEmailChangeError::UnknownToken => StatusCode::UNAUTHORIZED,
+            ConfirmEmailChangeError::ExpiredToken => StatusCode::GONE,
+            ConfirmEmailChangeError::EmailTaken => StatusCode::CONFLICT,
+            ConfirmEmailChangeError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+struct EmailChangeRequest {
+    subscriber_id: Uuid,
+    new_email: String,
+    requested_at: DateTime<Utc>,
+}
+
+impl EmailChangeRequest {
+    fn is_expired(&self, ttl: std::time::Duration) -> bool {
+        match chrono::Duration::from_std(ttl) {
+            Ok(ttl) => self.requested_at + ttl < Utc::now(),
+            // A TTL too large to be represented never expires.
+            Err(_) => false,
+        }
+    }
+}
+
+#[tracing::instrument(name = "Get email change request", skip(transaction, token))]
+async fn get_email_change_request(
+    transaction: &mut Transaction<'_, Postgres>,
+    token: &str,
+) -> Result<Option<EmailChangeRequest>, sqlx::Error> {
+    sqlx::query_as!(
+        EmailChangeRequest,
+        r#"SELECT subscriber_id, new_email, requested_at FROM email_change_requests
+        WHERE email_change_token = $1
+        FOR UPDATE"#,
+        token
+    )
+    .fetch_optional(transaction)
+    .await
+}
+
+#[tracing::instrument(name = "Check whether an email is taken", skip(transaction, email))]
+async fn is_email_taken(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    email: &str,
+) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT EXISTS (
+            SELECT 1 FROM subscriptions WHERE email = $1 AND id <> $2
+        ) AS "taken!""#,
+        email,
+        subscriber_id
+    )
+    .fetch_one(transaction)
+    .await?;
+    Ok(row.taken)
+}
+
+/// Switch the subscriber to their new address, dropping any other change
+/// they requested in the meantime.
+#[tracing::instrument(name = "Change the email of a subscriber", skip(transaction, email))]
+async fn change_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    email: &str,
+) -> Result<(), sqlx::Error> {
+    // Soft bounces were counted against the previous address, and the
+    // preferences links we sent it must stop working.
+    sqlx::query!(
+        r#"UPDATE subscriptions
+        SET email = $2, soft_bounce_count = 0, preferences_version = preferences_version + 1
+        WHERE id = $1"#,
+        subscriber_id,
+        email
+    )
+    .execute(&mut *transaction)
+    .await?;
+    drop_email_change_requests(transaction, subscriber_id).await
+}
+
+#[tracing::instrument(
+    name = "Drop the email change requests of a subscriber",
+    skip(transaction)
+)]
+async fn drop_email_change_requests(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"DELETE FROM email_change_requests WHERE subscriber_id = $1"#,
+        subscriber_id
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
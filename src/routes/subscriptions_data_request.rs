@@ -0,0 +1,306 @@
+//! src/routes/subscriptions_data_request.rs
+//!
+//! Data subject requests made by subscribers themselves: they only go
+//! through once the subscriber follows the link we email them.
+
+use crate::domain::{SubscriberEmail, SubscriberLocale};
+use crate::email_outbox::{enqueue_email, OutboxEmail, OutboxEmailKind};
+use crate::email_templates::{DataRequestEmail, EmailTemplates};
+use crate::routes::{error_chain_fmt, generate_subscription_token, subscriber_data_response};
+use crate::startup::EmailHashSecret;
+use crate::subscriber_data::{erase_subscriber, export_subscriber_data};
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// How long the link we email can be followed for.
+const DATA_REQUEST_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// What a subscriber asked us to do with their data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataRequestKind {
+    Export,
+    Erase,
+}
+
+impl DataRequestKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DataRequestKind::Export => "export",
+            DataRequestKind::Erase => "erase",
+        }
+    }
+
+    fn parse(kind: &str) -> Result<Self, String> {
+        match kind {
+            "export" => Ok(DataRequestKind::Export),
+            "erase" => Ok(DataRequestKind::Erase),
+            other => Err(format!("{} is not a data request kind.", other)),
+        }
+    }
+}
+
+/// Record a data request, and send the subscriber the link that carries
+/// it out to `recipient`, their current address.
+///
+/// Only the most recent request of each kind can be carried out.
+#[tracing::instrument(
+    name = "Request access to subscriber data",
+    skip(transaction, templates, name, locale, recipient, base_url)
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn request_data(
+    transaction: &mut Transaction<'_, Postgres>,
+    templates: &EmailTemplates,
+    subscriber_id: Uuid,
+    name: &str,
+    locale: &SubscriberLocale,
+    recipient: &SubscriberEmail,
+    kind: DataRequestKind,
+    base_url: &str,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"DELETE FROM data_request_tokens WHERE subscriber_id = $1 AND kind = $2"#,
+        subscriber_id,
+        kind.as_str()
+    )
+    .execute(&mut *transaction)
+    .await?;
+    let data_request_token = generate_subscription_token();
+    sqlx::query!(
+        r#"INSERT INTO data_request_tokens (data_request_token, subscriber_id, kind, requested_at)
+        VALUES ($1, $2, $3, $4)"#,
+        data_request_token,
+        subscriber_id,
+        kind.as_str(),
+        Utc::now()
+    )
+    .execute(&mut *transaction)
+    .await?;
+    let confirmation_link = format!("{}{}", base_url, data_request_path(&data_request_token));
+    let email = templates.render(
+        locale,
+        &DataRequestEmail {
+            name,
+            kind: kind.as_str(),
+            confirmation_link: &confirmation_link,
+            base_url,
+        },
+    )?;
+    enqueue_email(
+        transaction,
+        subscriber_id,
+        OutboxEmail {
+            kind: OutboxEmailKind::DataRequest,
+            recipient,
+            subject: &email.subject,
+            html_body: &email.html_body,
+            text_body: &email.text_body,
+            unsubscribe_link: None,
+        },
+    )
+    .await?;
+    Ok(())
+}
+
+fn data_request_path(data_request_token: &str) -> String {
+    format!("/subscriptions/data-request?token={}", data_request_token)
+}
+
+#[derive(serde::Deserialize)]
+pub struct DataRequestParameters {
+    token: String,
+}
+
+// Following the link does not use it up: mail scanners fetch the links
+// they find in emails. We ask the subscriber to confirm instead, with a
+// form posting to the very same URL.
+#[tracing::instrument(name = "Show the data request form", skip(parameters, pool))]
+pub async fn data_request_form(
+    parameters: web::Query<DataRequestParameters>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, DataRequestError> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let request = get_data_request(&mut transaction, &parameters.token)
+        .await
+        .context("Failed to retrieve the data request.")?
+        .ok_or(DataRequestError::UnknownToken)?;
+    if request.is_expired() {
+        return Err(DataRequestError::ExpiredToken);
+    }
+    let (question, button) = match request.kind()? {
+        DataRequestKind::Export => (
+            "Do you want to download the data we hold about you?",
+            "Download my data",
+        ),
+        DataRequestKind::Erase => (
+            "Do you want us to erase the data we hold about you? \
+            This cannot be undone.",
+            "Erase my data for good",
+        ),
+    };
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Your data</title>
+</head>
+<body>
+    <p>{question}</p>
+    <form action="{action}" method="post">
+        <button type="submit">{button}</button>
+    </form>
+</body>
+</html>"#,
+            question = question,
+            action = htmlescape::encode_minimal(&data_request_path(&parameters.token)),
+            button = button,
+        )))
+}
+
+/// Carry out the data request the token was issued for, using the token
+/// up.
+#[tracing::instrument(
+    name = "Carry out a data request",
+    skip(parameters, pool, email_hash_secret)
+)]
+pub async fn complete_data_request(
+    parameters: web::Query<DataRequestParameters>,
+    pool: web::Data<PgPool>,
+    email_hash_secret: web::Data<EmailHashSecret>,
+) -> Result<HttpResponse, DataRequestError> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let request = get_data_request(&mut transaction, &parameters.token)
+        .await
+        .context("Failed to retrieve the data request.")?
+        .ok_or(DataRequestError::UnknownToken)?;
+    if request.is_expired() {
+        return Err(DataRequestError::ExpiredToken);
+    }
+    delete_data_request(&mut transaction, &parameters.token)
+        .await
+        .context("Failed to use up the data request token.")?;
+    match request.kind()? {
+        DataRequestKind::Export => {
+            transaction
+                .commit()
+                .await
+                .context("Failed to commit SQL transaction to use up a data request token.")?;
+            let data = export_subscriber_data(&pool, request.subscriber_id)
+                .await
+                .context("Failed to export the subscriber data.")?
+                .ok_or(DataRequestError::UnknownToken)?;
+            Ok(subscriber_data_response(&data))
+        }
+        DataRequestKind::Erase => {
+            erase_subscriber(&mut transaction, request.subscriber_id, &email_hash_secret)
+                .await
+                .context("Failed to erase the subscriber.")?;
+            transaction
+                .commit()
+                .await
+                .context("Failed to commit SQL transaction to erase a subscriber.")?;
+            // There is no preferences page to go back to.
+            Ok(HttpResponse::Ok().content_type(ContentType::html()).body(
+                r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Your data has been erased</title>
+</head>
+<body>
+    <p>We erased all the data we held about you.</p>
+</body>
+</html>"#,
+            ))
+        }
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum DataRequestError {
+    #[error("There is no data request associated with the provided token.")]
+    UnknownToken,
+    #[error("The data request token has expired.")]
+    ExpiredToken,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for DataRequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for DataRequestError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            DataRequestError::UnknownToken => StatusCode::UNAUTHORIZED,
+            DataRequestError::ExpiredToken => StatusCode::GONE,
+            DataRequestError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+struct DataRequest {
+    subscriber_id: Uuid,
+    kind: String,
+    requested_at: DateTime<Utc>,
+}
+
+impl DataRequest {
+    fn kind(&self) -> Result<DataRequestKind, anyhow::Error> {
+        // Kinds are constrained by the database.
+        DataRequestKind::parse(&self.kind).map_err(anyhow::Error::msg)
+    }
+
+    fn is_expired(&self) -> bool {
+        let ttl = chrono::Duration::from_std(DATA_REQUEST_TTL).expect("The TTL is out of range.");
+        self.requested_at + ttl < Utc::now()
+    }
+}
+
+#[tracing::instrument(name = "Get data request", skip(transaction, token))]
+async fn get_data_request(
+    transaction: &mut Transaction<'_, Postgres>,
+    token: &str,
+) -> Result<Option<DataRequest>, sqlx::Error> {
+    sqlx::query_as!(
+        DataRequest,
+        r#"SELECT subscriber_id, kind, requested_at FROM data_request_tokens
+        WHERE data_request_token = $1
+        FOR UPDATE"#,
+        token
+    )
+    .fetch_optional(transaction)
+    .await
+}
+
+#[tracing::instrument(name = "Delete data request", skip(transaction, token))]
+async fn delete_data_request(
+    transaction: &mut Transaction<'_, Postgres>,
+    token: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"DELETE FROM data_request_tokens WHERE data_request_token = $1"#,
+        token
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
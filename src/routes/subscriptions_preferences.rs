@@ -0,0 +1,815 @@
+//! src/routes/subscriptions_preferences.rs
+use crate::domain::SubscriptionStatus;
+use crate::domain::{SendFrequency, SubscriberEmail, SubscriberLocale, SubscriberName};
+use crate::email_outbox::{enqueue_email, OutboxEmail, OutboxEmailKind};
+use crate::email_templates::{EmailChangeEmail, EmailTemplates};
+use crate::flash_messages::{FlashMessage, IncomingFlashMessages};
+use crate::mailing_lists::MailingList;
+use crate::routes::{
+    change_subscription_status, enqueue_confirmation_email, error_chain_fmt,
+    generate_subscription_token, parse_status, request_data, request_list_membership,
+    DataRequestKind, ListMembership,
+};
+use crate::startup::{ApplicationBaseUrl, HmacSecret};
+use crate::utils::see_other;
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use secrecy::ExposeSecret;
+use sqlx::{PgConnection, PgPool, Postgres, Transaction};
+use std::collections::HashSet;
+use std::fmt::Write;
+use uuid::Uuid;
+
+/// Identifies the subscriber a preferences link was sent to.
+///
+/// Links are signed with a key derived from our HMAC secret: knowing the id
+/// of a subscriber is not enough to change their settings. They stop
+/// working once the subscriber's `preferences_version` moves past the one
+/// they were signed for.
+#[derive(serde::Deserialize)]
+pub struct PreferencesParameters {
+    subscriber_id: Uuid,
+    version: i32,
+    tag: String,
+}
+
+impl PreferencesParameters {
+    fn verify(&self, hmac_secret: &HmacSecret) -> Result<Uuid, PreferencesError> {
+        let tag = hex::decode(&self.tag).map_err(|_| PreferencesError::InvalidLink)?;
+        preferences_mac(self.subscriber_id, self.version, hmac_secret)
+            .verify_slice(&tag)
+            .map_err(|_| PreferencesError::InvalidLink)?;
+        Ok(self.subscriber_id)
+    }
+
+    /// The subscriber the link was sent to, unless it has been revoked.
+    fn current(&self, subscriber: Option<Subscriber>) -> Result<Subscriber, PreferencesError> {
+        subscriber
+            .filter(|subscriber| subscriber.preferences_version == self.version)
+            .ok_or(PreferencesError::InvalidLink)
+    }
+}
+
+fn preferences_mac(
+    subscriber_id: Uuid,
+    version: i32,
+    hmac_secret: &HmacSecret,
+) -> Hmac<sha2::Sha256> {
+    // A key of its own: tags cannot be replayed as anything else we sign
+    // with the HMAC secret (e.g. cookies).
+    let mut key = Hmac::<sha2::Sha256>::new_from_slice(hmac_secret.0.expose_secret().as_bytes())
+        .expect("HMAC accepts keys of any length.");
+    key.update(b"preferences-link");
+    let mut mac = Hmac::<sha2::Sha256>::new_from_slice(&key.finalize().into_bytes())
+        .expect("HMAC accepts keys of any length.");
+    mac.update(subscriber_id.as_bytes());
+    mac.update(&version.to_be_bytes());
+    mac
+}
+
+/// The path of the preferences page of a subscriber, query included.
+fn preferences_path(subscriber_id: Uuid, version: i32, hmac_secret: &HmacSecret) -> String {
+    let tag = hex::encode(
+        preferences_mac(subscriber_id, version, hmac_secret)
+            .finalize()
+            .into_bytes(),
+    );
+    format!(
+        "/subscriptions/preferences?subscriber_id={}&version={}&tag={}",
+        subscriber_id, version, tag
+    )
+}
+
+/// Build the signed link a subscriber can follow to manage their settings.
+///
+/// `version` is the current `preferences_version` of the subscriber.
+pub fn preferences_link(
+    base_url: &str,
+    subscriber_id: Uuid,
+    version: i32,
+    hmac_secret: &HmacSecret,
+) -> String {
+    format!(
+        "{}{}",
+        base_url,
+        preferences_path(subscriber_id, version, hmac_secret)
+    )
+}
+
+/// The `preferences_version` of a subscriber, to sign links for.
+#[tracing::instrument(
+    name = "Get the preferences version of a subscriber",
+    skip(transaction)
+)]
+pub async fn get_preferences_version(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<i32, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT preferences_version FROM subscriptions WHERE id = $1"#,
+        subscriber_id
+    )
+    .fetch_one(transaction)
+    .await?;
+    Ok(row.preferences_version)
+}
+
+#[tracing::instrument(
+    name = "Show the preferences of a subscriber",
+    skip(parameters, pool, hmac_secret, flash_messages)
+)]
+pub async fn preferences_form(
+    parameters: web::Query<PreferencesParameters>,
+    pool: web::Data<PgPool>,
+    hmac_secret: web::Data<HmacSecret>,
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, PreferencesError> {
+    let subscriber_id = parameters.verify(&hmac_secret)?;
+    let mut connection = pool
+        .acquire()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let subscriber = get_subscriber(&mut connection, subscriber_id)
+        .await
+        .context("Failed to retrieve the subscriber.")?;
+    let subscriber = parameters.current(subscriber)?;
+    let lists = get_list_choices(&mut connection, subscriber_id)
+        .await
+        .context("Failed to retrieve the lists of the subscriber.")?;
+    let action = htmlescape::encode_minimal(&preferences_path(
+        subscriber_id,
+        subscriber.preferences_version,
+        &hmac_secret,
+    ));
+    // The other actions of the page are signed with the same query string.
+    let action_for = |path: &str| action.replacen("/subscriptions/preferences", path, 1);
+
+    let mut messages_html = String::new();
+    for message in flash_messages.iter() {
+        writeln!(
+            messages_html,
+            "<p><i>{}</i></p>",
+            htmlescape::encode_minimal(message.content())
+        )
+        .unwrap();
+    }
+    if is_suppressed(&lists) {
+        messages_html.push_str(
+            "<p><i>We stopped sending emails to this address: \
+            they could not be delivered.</i></p>",
+        );
+    }
+    let mut lists_html = String::new();
+    for choice in &lists {
+        let status = choice.membership.as_ref().map(|m| m.status);
+        writeln!(
+            lists_html,
+            r#"<label><input type="checkbox" name="list" value="{}"{}> {}{}</label><br>"#,
+            htmlescape::encode_minimal(&choice.list.slug),
+            if choice.is_active() { " checked" } else { "" },
+            htmlescape::encode_minimal(&choice.list.name),
+            if status == Some(SubscriptionStatus::PendingConfirmation) {
+                " (waiting for your confirmation)"
+            } else {
+                ""
+            }
+        )
+        .unwrap();
+    }
+    let mut frequencies_html = String::new();
+    for frequency in SendFrequency::ALL {
+        writeln!(
+            frequencies_html,
+            r#"<option value="{0}"{1}>{0}</option>"#,
+            frequency,
+            if frequency == subscriber.send_frequency {
+                " selected"
+            } else {
+                ""
+            }
+        )
+        .unwrap();
+    }
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Your preferences</title>
+</head>
+<body>
+    {messages_html}
+    <form action="{action}" method="post">
+        <label>Name
+            <input type="text" name="name" value="{name}">
+        </label>
+        <br>
+        <label>Email
+            <input type="email" name="email" value="{email}">
+        </label>
+        <br>
+        <p>The lists you receive:</p>
+        {lists_html}
+        <label>How often
+            <select name="send_frequency">
+                {frequencies_html}
+            </select>
+        </label>
+        <br>
+        <button type="submit">Save my preferences</button>
+    </form>
+    <form action="{unsubscribe_action}" method="post">
+        <button type="submit">Unsubscribe from all our lists</button>
+    </form>
+    <form action="{export_action}" method="post">
+        <button type="submit">Download the data we hold about you</button>
+    </form>
+    <form action="{erase_action}" method="post">
+        <button type="submit">Erase your data for good</button>
+    </form>
+</body>
+</html>"#,
+            messages_html = messages_html,
+            action = action,
+            name = htmlescape::encode_minimal(&subscriber.name),
+            email = htmlescape::encode_minimal(&subscriber.email),
+            lists_html = lists_html,
+            frequencies_html = frequencies_html,
+            unsubscribe_action = action_for("/subscriptions/preferences/unsubscribe"),
+            export_action = action_for("/subscriptions/preferences/export"),
+            erase_action = action_for("/subscriptions/preferences/erase"),
+        )))
+}
+
+/// The fields of the preferences form.
+///
+/// Ticked lists are submitted as repeated `list` fields, which
+/// `serde_urlencoded` cannot collect into a struct: we gather the fields
+/// by hand.
+#[derive(Default)]
+struct PreferencesFormData {
+    name: String,
+    email: String,
+    send_frequency: String,
+    lists: HashSet<String>,
+}
+
+impl From<Vec<(String, String)>> for PreferencesFormData {
+    fn from(fields: Vec<(String, String)>) -> Self {
+        let mut form = Self::default();
+        for (name, value) in fields {
+            match name.as_str() {
+                "name" => form.name = value,
+                "email" => form.email = value,
+                "send_frequency" => form.send_frequency = value,
+                "list" => {
+                    form.lists.insert(value);
+                }
+                _ => {}
+            }
+        }
+        form
+    }
+}
+
+#[tracing::instrument(
+    name = "Update the preferences of a subscriber",
+    skip(request, parameters, form, pool, base_url, templates, hmac_secret)
+)]
+pub async fn update_preferences(
+    request: HttpRequest,
+    parameters: web::Query<PreferencesParameters>,
+    form: web::Form<Vec<(String, String)>>,
+    pool: web::Data<PgPool>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    templates: web::Data<EmailTemplates>,
+    hmac_secret: web::Data<HmacSecret>,
+) -> Result<HttpResponse, PreferencesError> {
+    let subscriber_id = parameters.verify(&hmac_secret)?;
+    let form = PreferencesFormData::from(form.0);
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let subscriber = get_subscriber(&mut transaction, subscriber_id)
+        .await
+        .context("Failed to retrieve the subscriber.")?;
+    let subscriber = parameters.current(subscriber)?;
+    let preferences_path =
+        preferences_path(subscriber_id, subscriber.preferences_version, &hmac_secret);
+    let validated = SubscriberName::parse(form.name).and_then(|name| {
+        let email = SubscriberEmail::parse(form.email)?;
+        let send_frequency: SendFrequency = form.send_frequency.parse()?;
+        Ok((name, email, send_frequency))
+    });
+    let (name, email, send_frequency) = match validated {
+        Ok(validated) => validated,
+        Err(e) => {
+            FlashMessage::error(e).send(&request);
+            return Ok(see_other(&preferences_path));
+        }
+    };
+    // Locales are validated before they are stored.
+    let locale = SubscriberLocale::parse(subscriber.locale).unwrap_or_default();
+
+    update_subscriber(&mut transaction, subscriber_id, &name, send_frequency)
+        .await
+        .context("Failed to update the subscriber.")?;
+    // The confirmation emails go to the current address: a new one is
+    // only used once it has been confirmed.
+    let current_email = SubscriberEmail::parse(subscriber.email).map_err(anyhow::Error::msg)?;
+    let lists = get_list_choices(&mut transaction, subscriber_id)
+        .await
+        .context("Failed to retrieve the lists of the subscriber.")?;
+    let is_suppressed = is_suppressed(&lists);
+    for choice in lists {
+        let wanted = form.lists.contains(&choice.list.slug);
+        if wanted && !choice.is_active() && !is_suppressed {
+            // Joining a list goes through double opt-in, as when
+            // subscribing from the form on our website.
+            if let Some(pending) = request_list_membership(
+                &mut transaction,
+                subscriber_id,
+                choice.list.list_id,
+                choice.membership,
+            )
+            .await?
+            {
+                enqueue_confirmation_email(
+                    &mut transaction,
+                    &templates,
+                    subscriber_id,
+                    &current_email,
+                    name.as_ref(),
+                    &locale,
+                    &choice.list,
+                    &base_url.0,
+                    &hmac_secret,
+                    &pending,
+                )
+                .await
+                .context("Failed to enqueue a confirmation email.")?;
+            }
+        } else if !wanted && choice.is_active() {
+            change_subscription_status(
+                &mut transaction,
+                subscriber_id,
+                choice.list.list_id,
+                SubscriptionStatus::Unsubscribed,
+            )
+            .await
+            .context("Failed to unsubscribe the subscriber from a list.")?;
+        }
+    }
+    let email_changed = email.as_ref() != current_email.as_ref();
+    if email_changed {
+        request_email_change(
+            &mut transaction,
+            &templates,
+            subscriber_id,
+            name.as_ref(),
+            &locale,
+            &email,
+            &base_url.0,
+        )
+        .await
+        .context("Failed to request a change of email address.")?;
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to update the preferences.")?;
+    FlashMessage::info("Your preferences have been saved.").send(&request);
+    if email_changed {
+        FlashMessage::info(format!(
+            "We sent a confirmation link to {}: your address changes once you follow it.",
+            email.as_ref()
+        ))
+        .send(&request);
+    }
+    Ok(see_other(&preferences_path))
+}
+
+#[tracing::instrument(
+    name = "Unsubscribe a subscriber from all lists",
+    skip(request, parameters, pool, hmac_secret)
+)]
+pub async fn unsubscribe_from_all_lists(
+    request: HttpRequest,
+    parameters: web::Query<PreferencesParameters>,
+    pool: web::Data<PgPool>,
+    hmac_secret: web::Data<HmacSecret>,
+) -> Result<HttpResponse, PreferencesError> {
+    let subscriber_id = parameters.verify(&hmac_secret)?;
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let subscriber = get_subscriber(&mut transaction, subscriber_id)
+        .await
+        .context("Failed to retrieve the subscriber.")?;
+    let subscriber = parameters.current(subscriber)?;
+    let lists = get_list_choices(&mut transaction, subscriber_id)
+        .await
+        .context("Failed to retrieve the lists of the subscriber.")?;
+    for choice in lists.iter().filter(|choice| choice.is_active()) {
+        change_subscription_status(
+            &mut transaction,
+            subscriber_id,
+            choice.list.list_id,
+            SubscriptionStatus::Unsubscribed,
+        )
+        .await
+        .context("Failed to unsubscribe the subscriber from a list.")?;
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to unsubscribe from all lists.")?;
+    FlashMessage::info("You have been unsubscribed from all our lists.").send(&request);
+    Ok(see_other(&preferences_path(
+        subscriber_id,
+        subscriber.preferences_version,
+        &hmac_secret,
+    )))
+}
+
+#[tracing::instrument(
+    name = "Request an export of subscriber data on their behalf",
+    skip(request, parameters, pool, base_url, templates, hmac_secret)
+)]
+pub async fn request_my_data_export(
+    request: HttpRequest,
+    parameters: web::Query<PreferencesParameters>,
+    pool: web::Data<PgPool>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    templates: web::Data<EmailTemplates>,
+    hmac_secret: web::Data<HmacSecret>,
+) -> Result<HttpResponse, PreferencesError> {
+    request_my_data(
+        request,
+        parameters,
+        pool,
+        base_url,
+        templates,
+        hmac_secret,
+        DataRequestKind::Export,
+    )
+    .await
+}
+
+#[tracing::instrument(
+    name = "Request the erasure of subscriber data on their behalf",
+    skip(request, parameters, pool, base_url, templates, hmac_secret)
+)]
+pub async fn request_my_data_erasure(
+    request: HttpRequest,
+    parameters: web::Query<PreferencesParameters>,
+    pool: web::Data<PgPool>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    templates: web::Data<EmailTemplates>,
+    hmac_secret: web::Data<HmacSecret>,
+) -> Result<HttpResponse, PreferencesError> {
+    request_my_data(
+        request,
+        parameters,
+        pool,
+        base_url,
+        templates,
+        hmac_secret,
+        DataRequestKind::Erase,
+    )
+    .await
+}
+
+/// Email the subscriber a link to carry out their data request.
+///
+/// The preferences link never expires and may have been forwarded: only
+/// whoever can read the inbox of the subscriber, right now, gets to
+/// download or erase their data.
+async fn request_my_data(
+    request: HttpRequest,
+    parameters: web::Query<PreferencesParameters>,
+    pool: web::Data<PgPool>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    templates: web::Data<EmailTemplates>,
+    hmac_secret: web::Data<HmacSecret>,
+    kind: DataRequestKind,
+) -> Result<HttpResponse, PreferencesError> {
+    let subscriber_id = parameters.verify(&hmac_secret)?;
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let subscriber = get_subscriber(&mut transaction, subscriber_id)
+        .await
+        .context("Failed to retrieve the subscriber.")?;
+    let subscriber = parameters.current(subscriber)?;
+    let email = SubscriberEmail::parse(subscriber.email).map_err(anyhow::Error::msg)?;
+    // Locales are validated before they are stored.
+    let locale = SubscriberLocale::parse(subscriber.locale).unwrap_or_default();
+    request_data(
+        &mut transaction,
+        &templates,
+        subscriber_id,
+        &subscriber.name,
+        &locale,
+        &email,
+        kind,
+        &base_url.0,
+    )
+    .await
+    .context("Failed to request access to the subscriber data.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to request access to subscriber data.")?;
+    FlashMessage::info(format!(
+        "We sent a link to {}: follow it within the hour to {} your data.",
+        email.as_ref(),
+        match kind {
+            DataRequestKind::Export => "download",
+            DataRequestKind::Erase => "erase",
+        }
+    ))
+    .send(&request);
+    Ok(see_other(&preferences_path(
+        subscriber_id,
+        subscriber.preferences_version,
+        &hmac_secret,
+    )))
+}
+
+#[derive(thiserror::Error)]
+pub enum PreferencesError {
+    #[error("The link to the preferences page is invalid.")]
+    InvalidLink,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for PreferencesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for PreferencesError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PreferencesError::InvalidLink => StatusCode::UNAUTHORIZED,
+            PreferencesError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+struct Subscriber {
+    name: String,
+    email: String,
+    locale: String,
+    send_frequency: SendFrequency,
+    preferences_version: i32,
+}
+
+/// Load the settings of a subscriber. Within a transaction, the row stays
+/// locked until it ends: concurrent updates are applied one at a time.
+#[tracing::instrument(name = "Get subscriber preferences", skip(connection))]
+async fn get_subscriber(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+) -> Result<Option<Subscriber>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT name, email, locale, send_frequency, preferences_version
+        FROM subscriptions WHERE id = $1
+        FOR UPDATE"#,
+        subscriber_id
+    )
+    .fetch_optional(connection)
+    .await?;
+    row.map(|row| {
+        Ok(Subscriber {
+            name: row.name,
+            email: row.email,
+            locale: row.locale,
+            send_frequency: row
+                .send_frequency
+                .parse()
+                .map_err(|e: String| sqlx::Error::Decode(e.into()))?,
+            preferences_version: row.preferences_version,
+        })
+    })
+    .transpose()
+}
+
+/// One of our lists, along with the subscriber's membership, if any.
+struct ListChoice {
+    list: MailingList,
+    membership: Option<ListMembership>,
+}
+
+impl ListChoice {
+    /// Whether the subscriber receives - or asked to receive - the list.
+    fn is_active(&self) -> bool {
+        matches!(
+            self.membership.as_ref().map(|m| m.status),
+            Some(SubscriptionStatus::PendingConfirmation | SubscriptionStatus::Confirmed)
+        )
+    }
+}
+
+fn is_suppressed(lists: &[ListChoice]) -> bool {
+    lists
+        .iter()
+        .filter_map(|choice| choice.membership.as_ref())
+        .any(|membership| membership.status.is_suppressed())
+}
+
+#[tracing::instrument(name = "Get the lists of a subscriber", skip(connection))]
+async fn get_list_choices(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+) -> Result<Vec<ListChoice>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"SELECT l.list_id, l.slug, l.name, m.status AS "status?", u.unsubscribe_token AS "unsubscribe_token?"
+        FROM lists l
+        LEFT JOIN list_memberships m
+            ON m.list_id = l.list_id AND m.subscriber_id = $1
+        LEFT JOIN unsubscribe_tokens u
+            ON u.list_id = l.list_id AND u.subscriber_id = $1
+        ORDER BY l.name"#,
+        subscriber_id
+    )
+    .fetch_all(connection)
+    .await?;
+    rows.into_iter()
+        .map(|row| {
+            let membership = match (row.status, row.unsubscribe_token) {
+                (Some(status), Some(unsubscribe_token)) => Some(ListMembership {
+                    status: parse_status(&status)?,
+                    unsubscribe_token,
+                }),
+                _ => None,
+            };
+            Ok(ListChoice {
+                list: MailingList {
+                    list_id: row.list_id,
+                    slug: row.slug,
+                    name: row.name,
+                },
+                membership,
+            })
+        })
+        .collect()
+}
+
+#[tracing::instrument(name = "Update a subscriber", skip(transaction, name))]
+async fn update_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    name: &SubscriberName,
+    send_frequency: SendFrequency,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE subscriptions SET name = $2, send_frequency = $3 WHERE id = $1"#,
+        subscriber_id,
+        name.as_ref(),
+        send_frequency.as_str()
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+/// Record the new address a subscriber asked for, and send its owner a
+/// link to confirm it.
+///
+/// Only the most recent request of a subscriber can be confirmed.
+#[tracing::instrument(
+    name = "Request a change of email address",
+    skip(transaction, templates, name, locale, new_email, base_url)
+)]
+async fn request_email_change(
+    transaction: &mut Transaction<'_, Postgres>,
+    templates: &EmailTemplates,
+    subscriber_id: Uuid,
+    name: &str,
+    locale: &SubscriberLocale,
+    new_email: &SubscriberEmail,
+    base_url: &str,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"DELETE FROM email_change_requests WHERE subscriber_id = $1"#,
+        subscriber_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    let email_change_token = generate_subscription_token();
+    sqlx::query!(
+        r#"INSERT INTO email_change_requests (email_change_token, subscriber_id, new_email, requested_at)
+        VALUES ($1, $2, $3, $4)"#,
+        email_change_token,
+        subscriber_id,
+        new_email.as_ref(),
+        Utc::now()
+    )
+    .execute(&mut *transaction)
+    .await?;
+    let confirmation_link = format!(
+        "{}/subscriptions/confirm-email-change?token={}",
+        base_url, email_change_token
+    );
+    let email = templates.render(
+        locale,
+        &EmailChangeEmail {
+            name,
+            new_email: new_email.as_ref(),
+            confirmation_link: &confirmation_link,
+            base_url,
+        },
+    )?;
+    enqueue_email(
+        transaction,
+        subscriber_id,
+        OutboxEmail {
+            kind: OutboxEmailKind::EmailChange,
+            recipient: new_email,
+            subject: &email.subject,
+            html_body: &email.html_body,
+            text_body: &email.text_body,
+            unsubscribe_link: None,
+        },
+    )
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{preferences_path, PreferencesParameters};
+    use crate::startup::HmacSecret;
+    use actix_web::web;
+    use claim::{assert_err, assert_ok};
+    use secrecy::Secret;
+    use uuid::Uuid;
+
+    fn secret(secret: &str) -> HmacSecret {
+        HmacSecret(Secret::new(secret.into()))
+    }
+
+    fn parameters(path: &str) -> PreferencesParameters {
+        let query = path.split_once('?').unwrap().1;
+        web::Query::<PreferencesParameters>::from_query(query)
+            .unwrap()
+            .into_inner()
+    }
+
+    #[test]
+    fn signed_links_are_verified() {
+        let subscriber_id = Uuid::new_v4();
+        let path = preferences_path(subscriber_id, 0, &secret("secret"));
+
+        assert_eq!(
+            assert_ok!(parameters(&path).verify(&secret("secret"))),
+            subscriber_id
+        );
+    }
+
+    #[test]
+    fn links_signed_with_another_secret_are_rejected() {
+        let path = preferences_path(Uuid::new_v4(), 0, &secret("another-secret"));
+
+        assert_err!(parameters(&path).verify(&secret("secret")));
+    }
+
+    #[test]
+    fn links_signed_for_another_subscriber_are_rejected() {
+        let path = preferences_path(Uuid::new_v4(), 0, &secret("secret"));
+        let mut parameters = parameters(&path);
+        parameters.subscriber_id = Uuid::new_v4();
+
+        assert_err!(parameters.verify(&secret("secret")));
+    }
+
+    #[test]
+    fn links_signed_for_another_version_are_rejected() {
+        let path = preferences_path(Uuid::new_v4(), 0, &secret("secret"));
+        let mut parameters = parameters(&path);
+        parameters.version = 1;
+
+        assert_err!(parameters.verify(&secret("secret")));
+    }
+
+    #[test]
+    fn malformed_tags_are_rejected() {
+        let mut parameters = parameters(&preferences_path(Uuid::new_v4(), 0, &secret("secret")));
+        parameters.tag = "not-hex".into();
+
+        assert_err!(parameters.verify(&secret("secret")));
+    }
+}
@@ -0,0 +1,95 @@
+//! src/routes/subscriptions_resend_confirmation.rs
+use crate::domain::{SubscriberEmail, SubscriberLocale, SubscriptionStatus};
+use crate::email_templates::EmailTemplates;
+use crate::mailing_lists::{get_list_by_slug, DEFAULT_LIST};
+use crate::routes::{
+    enqueue_confirmation_email, get_list_membership, get_subscriber_by_email,
+    rotate_subscription_token, PendingMembership, SubscribeError,
+};
+use crate::startup::{ApplicationBaseUrl, HmacSecret};
+use actix_web::{web, HttpResponse};
+use anyhow::Context;
+use sqlx::PgPool;
+
+#[derive(serde::Deserialize)]
+pub struct ResendConfirmationFormData {
+    email: String,
+    /// The slug of the list to confirm - the default list if omitted.
+    list: Option<String>,
+}
+
+// We return a 200 whether or not a pending subscription exists for the
+// address we were given: the endpoint must not be usable to find out who
+// is on our mailing list.
+#[tracing::instrument(
+    name = "Resend a confirmation email",
+    skip(form, pool, base_url, templates, hmac_secret),
+    fields(subscriber_email = %form.email)
+)]
+pub async fn resend_confirmation(
+    form: web::Form<ResendConfirmationFormData>,
+    pool: web::Data<PgPool>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    templates: web::Data<EmailTemplates>,
+    hmac_secret: web::Data<HmacSecret>,
+) -> Result<HttpResponse, SubscribeError> {
+    let form = form.0;
+    let email = SubscriberEmail::parse(form.email).map_err(SubscribeError::ValidationError)?;
+    let list = get_list_by_slug(&pool, form.list.as_deref())
+        .await
+        .context("Failed to look up the mailing list.")?
+        .ok_or_else(|| {
+            SubscribeError::ValidationError(format!(
+                "There is no `{}` mailing list.",
+                form.list.as_deref().unwrap_or(DEFAULT_LIST)
+            ))
+        })?;
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let subscriber = match get_subscriber_by_email(&mut transaction, &email)
+        .await
+        .context("Failed to look up the subscriber by email.")?
+    {
+        Some(subscriber) => subscriber,
+        None => return Ok(HttpResponse::Ok().finish()),
+    };
+    let membership = match get_list_membership(&mut transaction, subscriber.id, list.list_id)
+        .await
+        .context("Failed to look up the list membership of the subscriber.")?
+    {
+        Some(membership) if membership.status == SubscriptionStatus::PendingConfirmation => {
+            membership
+        }
+        _ => return Ok(HttpResponse::Ok().finish()),
+    };
+    // Outstanding tokens are replaced: only the most recent link works.
+    let subscription_token =
+        rotate_subscription_token(&mut transaction, subscriber.id, list.list_id)
+            .await
+            .context("Failed to rotate the confirmation token of a subscriber.")?;
+    enqueue_confirmation_email(
+        &mut transaction,
+        &templates,
+        subscriber.id,
+        &email,
+        &subscriber.name,
+        // Locales are validated before they are stored.
+        &SubscriberLocale::parse(subscriber.locale).unwrap_or_default(),
+        &list,
+        &base_url.0,
+        &hmac_secret,
+        &PendingMembership {
+            subscription_token,
+            unsubscribe_token: membership.unsubscribe_token,
+        },
+    )
+    .await
+    .context("Failed to enqueue a confirmation email.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to rotate a confirmation token.")?;
+    Ok(HttpResponse::Ok().finish())
+}
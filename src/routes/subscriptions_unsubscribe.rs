@@ -0,0 +1,143 @@
+//! src/routes/subscriptions_unsubscribe.rs
+
+use crate::domain::SubscriptionStatus;
+use crate::routes::{change_subscription_status, error_chain_fmt, ChangeStatusError};
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(serde::Deserialize)]
+pub struct UnsubscribeParameters {
+    token: String,
+}
+
+/// Build the link a subscriber can follow to leave the mailing list the
+/// token was issued for.
+pub fn unsubscribe_link(base_url: &str, unsubscribe_token: &str) -> String {
+    format!(
+        "{}/subscriptions/unsubscribe?token={}",
+        base_url, unsubscribe_token
+    )
+}
+
+// Following the link does not unsubscribe anybody: mail scanners fetch
+// the links they find in emails. We ask the subscriber to confirm instead,
+// with a form posting to the very same URL.
+#[tracing::instrument(name = "Show the unsubscribe form", skip(parameters, pool))]
+pub async fn unsubscribe_form(
+    parameters: web::Query<UnsubscribeParameters>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    get_membership_from_unsubscribe_token(&pool, &parameters.token)
+        .await
+        .context("Failed to retrieve the list membership associated with the provided token.")?
+        .ok_or(UnsubscribeError::UnknownToken)?;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Unsubscribe</title>
+</head>
+<body>
+    <p>Do you want to stop receiving our emails?</p>
+    <form action="{action}" method="post">
+        <button type="submit">Unsubscribe</button>
+    </form>
+</body>
+</html>"#,
+            action = htmlescape::encode_minimal(&unsubscribe_link("", &parameters.token)),
+        )))
+}
+
+// Submitted by the form above, and by mail clients honouring
+// `List-Unsubscribe-Post` (RFC 8058) on behalf of the subscriber.
+#[tracing::instrument(name = "Unsubscribe a subscriber", skip(parameters, pool))]
+pub async fn unsubscribe(
+    parameters: web::Query<UnsubscribeParameters>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    let membership = get_membership_from_unsubscribe_token(&pool, &parameters.token)
+        .await
+        .context("Failed to retrieve the list membership associated with the provided token.")?
+        .ok_or(UnsubscribeError::UnknownToken)?;
+    match unsubscribe_subscriber(&pool, membership.subscriber_id, membership.list_id).await {
+        // Subscribers who bounced or complained are not mailed anymore:
+        // there is nothing left to do.
+        Ok(()) | Err(ChangeStatusError::IllegalTransition(_)) => Ok(HttpResponse::Ok().finish()),
+        Err(e) => Err(anyhow::Error::new(e)
+            .context("Failed to update the subscriber status to `unsubscribed`.")
+            .into()),
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum UnsubscribeError {
+    #[error("There is no subscriber associated with the provided token.")]
+    UnknownToken,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for UnsubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for UnsubscribeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            UnsubscribeError::UnknownToken => StatusCode::UNAUTHORIZED,
+            UnsubscribeError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[tracing::instrument(name = "Mark subscriber as unsubscribed", skip(subscriber_id, pool))]
+pub async fn unsubscribe_subscriber(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+) -> Result<(), ChangeStatusError> {
+    let mut transaction = pool.begin().await?;
+    change_subscription_status(
+        &mut transaction,
+        subscriber_id,
+        list_id,
+        SubscriptionStatus::Unsubscribed,
+    )
+    .await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+/// The list membership an unsubscribe token was issued for.
+pub struct UnsubscribeTokenMembership {
+    pub subscriber_id: Uuid,
+    pub list_id: Uuid,
+}
+
+#[tracing::instrument(
+    name = "Get list membership from unsubscribe token",
+    skip(unsubscribe_token, pool)
+)]
+pub async fn get_membership_from_unsubscribe_token(
+    pool: &PgPool,
+    unsubscribe_token: &str,
+) -> Result<Option<UnsubscribeTokenMembership>, sqlx::Error> {
+    let result = sqlx::query_as!(
+        UnsubscribeTokenMembership,
+        r#"SELECT subscriber_id, list_id FROM unsubscribe_tokens
+        WHERE unsubscribe_token = $1"#,
+        unsubscribe_token,
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(result)
+}
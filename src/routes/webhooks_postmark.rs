@@ -0,0 +1,305 @@
+//! src/routes/webhooks_postmark.rs
+use crate::authentication::basic_authentication;
+use crate::configuration::PostmarkWebhookSettings;
+use crate::domain::SubscriptionStatus;
+use crate::routes::{change_subscription_status, error_chain_fmt, ChangeStatusError};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
+use secrecy::ExposeSecret;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+/// The events Postmark posts to our webhook, tagged by `RecordType`.
+#[derive(serde::Deserialize)]
+#[serde(tag = "RecordType")]
+pub enum PostmarkEvent {
+    Bounce(BounceEvent),
+    SpamComplaint(SpamComplaintEvent),
+    Delivery(DeliveryEvent),
+    // Opens, clicks, ...: we do not subscribe to them, but we should not
+    // fail if someone turns them on.
+    #[serde(other)]
+    Other,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct BounceEvent {
+    /// The kind of bounce - e.g. `HardBounce` or `SoftBounce`.
+    #[serde(rename = "Type")]
+    bounce_type: String,
+    email: String,
+    #[serde(rename = "MessageID")]
+    message_id: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SpamComplaintEvent {
+    email: String,
+    #[serde(rename = "MessageID")]
+    message_id: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct DeliveryEvent {
+    recipient: String,
+    #[serde(rename = "MessageID")]
+    message_id: Option<String>,
+}
+
+/// How a bounce affects the subscriber it was sent to.
+#[derive(Debug, PartialEq)]
+enum BounceKind {
+    /// The address does not work, and never will.
+    Hard,
+    /// The address might work later: mailbox full, DNS errors, ...
+    Soft,
+    /// Auto-responders, challenge emails and the like.
+    Ignored,
+}
+
+impl BounceKind {
+    // See https://postmarkapp.com/developer/api/bounce-api#bounce-types
+    fn of(bounce_type: &str) -> Self {
+        match bounce_type {
+            "HardBounce" | "BadEmailAddress" | "ManuallyDeactivated" => BounceKind::Hard,
+            "SoftBounce" | "Transient" | "DnsError" => BounceKind::Soft,
+            _ => BounceKind::Ignored,
+        }
+    }
+}
+
+// Postmark retries deliveries that fail with a 5xx, while a 200 is returned
+// for events about addresses we do not know: there is nothing to fix.
+#[tracing::instrument(name = "Handle a Postmark webhook", skip_all)]
+pub async fn handle_postmark_webhook(
+    request: HttpRequest,
+    event: web::Json<PostmarkEvent>,
+    pool: web::Data<PgPool>,
+    settings: web::Data<PostmarkWebhookSettings>,
+) -> Result<HttpResponse, PostmarkWebhookError> {
+    authenticate(&request, &settings).map_err(PostmarkWebhookError::AuthError)?;
+    match event.0 {
+        PostmarkEvent::Bounce(bounce) => {
+            tracing::info!(
+                subscriber_email = %bounce.email,
+                message_id = ?bounce.message_id,
+                bounce_type = %bounce.bounce_type,
+                "Received a bounce"
+            );
+            match BounceKind::of(&bounce.bounce_type) {
+                BounceKind::Hard => suppress(&pool, &bounce.email, SubscriptionStatus::Bounced)
+                    .await
+                    .context("Failed to mark a subscriber as bounced.")?,
+                BounceKind::Soft => {
+                    record_soft_bounce(&pool, &bounce.email, settings.soft_bounce_threshold)
+                        .await
+                        .context("Failed to record a soft bounce.")?
+                }
+                BounceKind::Ignored => {}
+            }
+        }
+        PostmarkEvent::SpamComplaint(complaint) => {
+            tracing::info!(
+                subscriber_email = %complaint.email,
+                message_id = ?complaint.message_id,
+                "Received a spam complaint"
+            );
+            suppress(&pool, &complaint.email, SubscriptionStatus::Complained)
+                .await
+                .context("Failed to mark a subscriber as complained.")?;
+        }
+        PostmarkEvent::Delivery(delivery) => {
+            tracing::info!(
+                subscriber_email = %delivery.recipient,
+                message_id = ?delivery.message_id,
+                "Received a delivery"
+            );
+            reset_soft_bounces(&pool, &delivery.recipient)
+                .await
+                .context("Failed to reset the soft bounce count.")?;
+        }
+        PostmarkEvent::Other => {}
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Check the credentials Postmark sent, either with HTTP Basic auth or in
+/// the `X-Webhook-Secret` header.
+fn authenticate(
+    request: &HttpRequest,
+    settings: &PostmarkWebhookSettings,
+) -> Result<(), anyhow::Error> {
+    let expected_secret = settings.secret.expose_secret();
+    if let Some(secret) = request.headers().get("X-Webhook-Secret") {
+        let secret = secret
+            .to_str()
+            .context("The 'X-Webhook-Secret' header was not a valid UTF8 string.")?;
+        if !constant_time_eq(secret, expected_secret) {
+            anyhow::bail!("Invalid webhook secret.");
+        }
+        return Ok(());
+    }
+    let credentials = basic_authentication(request.headers())?;
+    // Both comparisons run, so that timing does not tell which one failed.
+    let valid_username = constant_time_eq(&credentials.username, &settings.username);
+    let valid_secret = constant_time_eq(credentials.password.expose_secret(), expected_secret);
+    if !(valid_username && valid_secret) {
+        anyhow::bail!("Invalid webhook credentials.");
+    }
+    Ok(())
+}
+
+/// Compare two strings in a time that only depends on their length.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |difference, (x, y)| difference | (x ^ y))
+            == 0
+}
+
+#[derive(thiserror::Error)]
+pub enum PostmarkWebhookError {
+    #[error("Authentication failed.")]
+    AuthError(#[source] anyhow::Error),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for PostmarkWebhookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for PostmarkWebhookError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PostmarkWebhookError::AuthError(_) => StatusCode::UNAUTHORIZED,
+            PostmarkWebhookError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Stop mailing the subscriber behind `email`, if we know them, on every
+/// list they are on.
+#[tracing::instrument(name = "Suppress a subscriber", skip(pool))]
+async fn suppress(
+    pool: &PgPool,
+    email: &str,
+    status: SubscriptionStatus,
+) -> Result<(), anyhow::Error> {
+    let mut transaction = pool.begin().await?;
+    if let Some(subscriber_id) = get_subscriber_id_by_email(&mut transaction, email).await? {
+        change_status(&mut transaction, subscriber_id, status).await?;
+    }
+    transaction.commit().await?;
+    Ok(())
+}
+
+/// Count the soft bounce, suppressing subscribers who reached `threshold`.
+#[tracing::instrument(name = "Record a soft bounce", skip(pool))]
+async fn record_soft_bounce(
+    pool: &PgPool,
+    email: &str,
+    threshold: i32,
+) -> Result<(), anyhow::Error> {
+    let mut transaction = pool.begin().await?;
+    let subscriber = sqlx::query!(
+        r#"UPDATE subscriptions
+        SET soft_bounce_count = soft_bounce_count + 1
+        WHERE lower(email) = lower($1)
+        RETURNING id, soft_bounce_count"#,
+        email,
+    )
+    .fetch_optional(&mut transaction)
+    .await?;
+    if let Some(subscriber) = subscriber {
+        if subscriber.soft_bounce_count >= threshold {
+            change_status(&mut transaction, subscriber.id, SubscriptionStatus::Bounced).await?;
+        }
+    }
+    transaction.commit().await?;
+    Ok(())
+}
+
+/// A successful delivery ends a run of soft bounces: only the bounces in a
+/// row count towards the threshold.
+#[tracing::instrument(name = "Reset soft bounces", skip(pool))]
+async fn reset_soft_bounces(pool: &PgPool, email: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE subscriptions SET soft_bounce_count = 0 WHERE lower(email) = lower($1)"#,
+        email,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Move all the list memberships of a subscriber to `status`: bounces and
+/// complaints are about an address, not about a list.
+async fn change_status(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    status: SubscriptionStatus,
+) -> Result<(), anyhow::Error> {
+    let list_ids = sqlx::query!(
+        r#"SELECT list_id FROM list_memberships WHERE subscriber_id = $1"#,
+        subscriber_id,
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+    for row in list_ids {
+        match change_subscription_status(transaction, subscriber_id, row.list_id, status).await {
+            Ok(()) => {}
+            // A complaint is final: it is never downgraded to a bounce.
+            Err(ChangeStatusError::IllegalTransition(e)) => {
+                tracing::info!(error.message = %e, "Ignoring a delivery failure");
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+#[tracing::instrument(name = "Get subscriber id by email", skip(transaction))]
+async fn get_subscriber_id_by_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &str,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    // Postmark does not preserve the case of the address we sent to.
+    let row = sqlx::query!(
+        r#"SELECT id FROM subscriptions WHERE lower(email) = lower($1)"#,
+        email
+    )
+    .fetch_optional(transaction)
+    .await?;
+    Ok(row.map(|row| row.id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{constant_time_eq, BounceKind};
+
+    #[test]
+    fn bounce_types_are_classified() {
+        assert_eq!(BounceKind::of("HardBounce"), BounceKind::Hard);
+        assert_eq!(BounceKind::of("BadEmailAddress"), BounceKind::Hard);
+        assert_eq!(BounceKind::of("SoftBounce"), BounceKind::Soft);
+        assert_eq!(BounceKind::of("Transient"), BounceKind::Soft);
+        assert_eq!(BounceKind::of("AutoResponder"), BounceKind::Ignored);
+        assert_eq!(BounceKind::of("SomethingNew"), BounceKind::Ignored);
+    }
+
+    #[test]
+    fn strings_are_compared_by_value() {
+        assert!(constant_time_eq("secret", "secret"));
+        assert!(!constant_time_eq("secret", "secreT"));
+        assert!(!constant_time_eq("secret", "secret2"));
+        assert!(!constant_time_eq("", "secret"));
+    }
+}
@@ -0,0 +1,51 @@
+//! src/session_state.rs
+use actix_session::{Session, SessionExt, SessionGetError, SessionInsertError};
+use actix_web::dev::Payload;
+use actix_web::dev::ServiceRequest;
+use actix_web::{FromRequest, HttpRequest};
+use std::future::{ready, Ready};
+use uuid::Uuid;
+
+/// A strongly-typed view over the session: handlers cannot misspell keys
+/// or store a value of the wrong type.
+pub struct TypedSession(Session);
+
+impl TypedSession {
+    pub(crate) const USER_ID_KEY: &'static str = "user_id";
+
+    /// Generate a new session key, to prevent session fixation attacks.
+    pub fn renew(&self) {
+        self.0.renew();
+    }
+
+    pub fn insert_user_id(&self, user_id: Uuid) -> Result<(), SessionInsertError> {
+        self.0.insert(Self::USER_ID_KEY, user_id)
+    }
+
+    pub fn get_user_id(&self) -> Result<Option<Uuid>, SessionGetError> {
+        self.0.get(Self::USER_ID_KEY)
+    }
+
+    /// Delete the session, both client-side and server-side.
+    pub fn log_out(self) {
+        self.0.purge()
+    }
+}
+
+impl From<&ServiceRequest> for TypedSession {
+    fn from(request: &ServiceRequest) -> Self {
+        TypedSession(request.get_session())
+    }
+}
+
+impl FromRequest for TypedSession {
+    // We return the same error returned by the
+    // implementation of `FromRequest` for `Session`.
+    type Error = <Session as FromRequest>::Error;
+    // No I/O involved: the future is ready the first time it is polled.
+    type Future = Ready<Result<TypedSession, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Ok(TypedSession(req.get_session())))
+    }
+}
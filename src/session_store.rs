@@ -0,0 +1,164 @@
+//! src/session_store.rs
+use crate::session_state::TypedSession;
+use actix_session::storage::{LoadError, SaveError, SessionKey, SessionStore, UpdateError};
+use actix_web::cookie::time::Duration;
+use anyhow::Context;
+use chrono::Utc;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use uuid::Uuid;
+
+type SessionState = HashMap<String, String>;
+
+/// Stores the server-side state of user sessions in Postgres.
+#[derive(Clone)]
+pub struct PostgresSessionStore {
+    pool: PgPool,
+}
+
+impl PostgresSessionStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+impl SessionStore for PostgresSessionStore {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+        let row = sqlx::query!(
+            r#"SELECT state FROM sessions
+            WHERE session_key = $1 AND expires_at > now()"#,
+            session_key.as_ref()
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .context("Failed to load the session state.")
+        .map_err(LoadError::Other)?;
+        row.map(|row| serde_json::from_value(row.state))
+            .transpose()
+            .context("Failed to deserialize the session state.")
+            .map_err(LoadError::Deserialization)
+    }
+
+    async fn save(
+        &self,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, SaveError> {
+        let state = serde_json::to_value(session_state)
+            .context("Failed to serialize the session state.")
+            .map_err(SaveError::Serialization)?;
+        let session_key = generate_session_key();
+        // New sessions are rare enough (one per login) to be a good time
+        // to get rid of the expired ones.
+        sqlx::query!(r#"DELETE FROM sessions WHERE expires_at <= now()"#)
+            .execute(&self.pool)
+            .await
+            .context("Failed to delete expired sessions.")
+            .map_err(SaveError::Other)?;
+        sqlx::query!(
+            r#"INSERT INTO sessions (session_key, state, expires_at)
+            VALUES ($1, $2, $3)"#,
+            session_key,
+            state,
+            expires_at(ttl)
+        )
+        .execute(&self.pool)
+        .await
+        .context("Failed to store the session state.")
+        .map_err(SaveError::Other)?;
+        session_key
+            .try_into()
+            .context("Generated an invalid session key.")
+            .map_err(SaveError::Other)
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        let state = serde_json::to_value(&session_state)
+            .context("Failed to serialize the session state.")
+            .map_err(UpdateError::Serialization)?;
+        let outcome = sqlx::query!(
+            r#"UPDATE sessions
+            SET state = $2, expires_at = $3
+            WHERE session_key = $1 AND expires_at > now()"#,
+            session_key.as_ref(),
+            state,
+            expires_at(ttl)
+        )
+        .execute(&self.pool)
+        .await
+        .context("Failed to update the session state.")
+        .map_err(UpdateError::Other)?;
+        if outcome.rows_affected() == 0 {
+            // The session expired in the meantime: start a new one.
+            return self
+                .save(session_state, ttl)
+                .await
+                .map_err(|e| UpdateError::Other(e.into()));
+        }
+        Ok(session_key)
+    }
+
+    async fn update_ttl(
+        &self,
+        session_key: &SessionKey,
+        ttl: &Duration,
+    ) -> Result<(), anyhow::Error> {
+        sqlx::query!(
+            r#"UPDATE sessions SET expires_at = $2 WHERE session_key = $1"#,
+            session_key.as_ref(),
+            expires_at(ttl)
+        )
+        .execute(&self.pool)
+        .await
+        .context("Failed to extend the session expiry.")?;
+        Ok(())
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        sqlx::query!(
+            r#"DELETE FROM sessions WHERE session_key = $1"#,
+            session_key.as_ref()
+        )
+        .execute(&self.pool)
+        .await
+        .context("Failed to delete the session.")?;
+        Ok(())
+    }
+}
+
+/// Delete every session `user_id` is logged in with.
+#[tracing::instrument(name = "Delete the sessions of a user", skip(pool))]
+pub async fn delete_user_sessions(pool: &PgPool, user_id: Uuid) -> Result<(), anyhow::Error> {
+    // Session values are stored in their JSON representation.
+    let user_id = serde_json::to_string(&user_id)?;
+    sqlx::query!(
+        r#"DELETE FROM sessions WHERE state ->> $1 = $2"#,
+        TypedSession::USER_ID_KEY,
+        user_id
+    )
+    .execute(pool)
+    .await
+    .context("Failed to delete the sessions of the user.")?;
+    Ok(())
+}
+
+/// Generate a random 64-characters-long session key.
+fn generate_session_key() -> String {
+    let mut rng = thread_rng();
+    std::iter::repeat_with(|| rng.sample(Alphanumeric))
+        .map(char::from)
+        .take(64)
+        .collect()
+}
+
+fn expires_at(ttl: &Duration) -> chrono::DateTime<Utc> {
+    Utc::now() + chrono::Duration::seconds(ttl.whole_seconds())
+}
@@ -0,0 +1,308 @@
+//! src/smtp_email_client.rs
+use crate::domain::SubscriberEmail;
+use crate::email_client::{EmailError, EmailSender, RetryPolicy, SendEmailResponse};
+use chrono::Utc;
+use lettre::message::header::{Header, HeaderName, HeaderValue};
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use secrecy::{ExposeSecret, Secret};
+use uuid::Uuid;
+
+/// Sends emails through an SMTP relay.
+pub struct SmtpEmailClient {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    sender: SubscriberEmail,
+    retry_policy: RetryPolicy,
+}
+
+impl SmtpEmailClient {
+    /// Build a client for the relay listening on `host:port`.
+    ///
+    /// Set `require_tls` to `false` only for relays on a trusted network
+    /// (e.g. a local SMTP stand-in): credentials would travel in clear text.
+    pub fn new(
+        host: &str,
+        port: u16,
+        credentials: Option<(String, Secret<String>)>,
+        require_tls: bool,
+        sender: SubscriberEmail,
+        timeout: std::time::Duration,
+        retry_policy: RetryPolicy,
+    ) -> Result<Self, lettre::transport::smtp::Error> {
+        let mut builder = if require_tls {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+        }
+        .port(port)
+        .timeout(Some(timeout));
+        if let Some((username, password)) = credentials {
+            builder = builder.credentials(Credentials::new(
+                username,
+                password.expose_secret().to_owned(),
+            ));
+        }
+        Ok(Self {
+            transport: builder.build(),
+            sender,
+            retry_policy,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailSender for SmtpEmailClient {
+    async fn send_email(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        unsubscribe_link: Option<&str>,
+    ) -> Result<SendEmailResponse, EmailError> {
+        // SMTP relays do not hand out identifiers: we set the `Message-ID`
+        // header ourselves, so that we know what to look for in their logs.
+        let message_id = message_id(&self.sender);
+        // Building the message again would fail in the same way.
+        let message = build_message(
+            &message_id,
+            &self.sender,
+            recipient,
+            subject,
+            unsubscribe_link,
+            text_content.to_owned(),
+            html_content.to_owned(),
+        )
+        .map_err(EmailError::Permanent)?;
+        self.retry_policy
+            .retry(|| async {
+                self.transport
+                    .send(message.clone())
+                    .await
+                    .map(|_| SendEmailResponse {
+                        message_id: message_id.clone(),
+                        submitted_at: Utc::now(),
+                        error_code: 0,
+                    })
+                    .map_err(|e| {
+                        // 5xx replies: the relay will not accept the message.
+                        if e.is_permanent() {
+                            EmailError::Permanent(e.into())
+                        } else {
+                            EmailError::Transient(e.into())
+                        }
+                    })
+            })
+            .await
+    }
+}
+
+/// A globally unique `Message-ID`, as specified by RFC 5322.
+fn message_id(sender: &SubscriberEmail) -> String {
+    let domain = sender.as_ref().rsplit('@').next().unwrap_or("localhost");
+    format!("<{}@{}>", Uuid::new_v4(), domain)
+}
+
+fn build_message(
+    message_id: &str,
+    sender: &SubscriberEmail,
+    recipient: &SubscriberEmail,
+    subject: &str,
+    unsubscribe_link: Option<&str>,
+    text_body: String,
+    html_body: String,
+) -> Result<Message, anyhow::Error> {
+    let mut builder = Message::builder()
+        .from(sender.as_ref().parse::<Mailbox>()?)
+        .to(recipient.as_ref().parse::<Mailbox>()?)
+        .subject(subject)
+        .message_id(Some(message_id.into()));
+    if let Some(unsubscribe_link) = unsubscribe_link {
+        builder = builder
+            .header(ListUnsubscribe(format!("<{}>", unsubscribe_link)))
+            .header(ListUnsubscribePost);
+    }
+    let message = builder.multipart(MultiPart::alternative_plain_html(text_body, html_body))?;
+    Ok(message)
+}
+
+/// The RFC 2369 `List-Unsubscribe` header.
+#[derive(Clone)]
+struct ListUnsubscribe(String);
+
+impl Header for ListUnsubscribe {
+    fn name() -> HeaderName {
+        HeaderName::new_from_ascii_str("List-Unsubscribe")
+    }
+
+    fn parse(s: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(Self(s.into()))
+    }
+
+    fn display(&self) -> HeaderValue {
+        HeaderValue::new(Self::name(), self.0.clone())
+    }
+}
+
+/// The RFC 8058 `List-Unsubscribe-Post` header, enabling one-click
+/// unsubscribe.
+#[derive(Clone)]
+struct ListUnsubscribePost;
+
+impl Header for ListUnsubscribePost {
+    fn name() -> HeaderName {
+        HeaderName::new_from_ascii_str("List-Unsubscribe-Post")
+    }
+
+    fn parse(_s: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(Self)
+    }
+
+    fn display(&self) -> HeaderValue {
+        HeaderValue::new(Self::name(), "List-Unsubscribe=One-Click".into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{EmailError, EmailSender, RetryPolicy, SendEmailResponse};
+    use crate::smtp_email_client::SmtpEmailClient;
+    use claim::{assert_err, assert_ok};
+    use fake::faker::internet::en::SafeEmail;
+    use fake::faker::lorem::en::{Paragraph, Sentence};
+    use fake::Fake;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// A bare-bones SMTP server standing in for a real relay.
+    ///
+    /// It accepts every message, unless told to reject recipients, and
+    /// records the `DATA` section of each of them.
+    struct SmtpStandIn {
+        port: u16,
+        messages: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl SmtpStandIn {
+        async fn start(reject_recipients: bool) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let messages = Arc::new(Mutex::new(Vec::new()));
+            let received = messages.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let received = received.clone();
+                    tokio::spawn(async move {
+                        let (reader, mut writer) = stream.into_split();
+                        let mut lines = BufReader::new(reader).lines();
+                        writer.write_all(b"220 localhost\r\n").await.unwrap();
+                        while let Ok(Some(line)) = lines.next_line().await {
+                            let command = line.to_uppercase();
+                            let reply: &[u8] = if command.starts_with("RCPT") && reject_recipients {
+                                b"550 No such user\r\n"
+                            } else if command.starts_with("DATA") {
+                                writer.write_all(b"354 Go ahead\r\n").await.unwrap();
+                                let mut data = String::new();
+                                while let Ok(Some(line)) = lines.next_line().await {
+                                    if line == "." {
+                                        break;
+                                    }
+                                    data.push_str(&line);
+                                    data.push('\n');
+                                }
+                                received.lock().unwrap().push(data);
+                                b"250 Ok\r\n"
+                            } else if command.starts_with("QUIT") {
+                                writer.write_all(b"221 Bye\r\n").await.unwrap();
+                                break;
+                            } else {
+                                b"250 Ok\r\n"
+                            };
+                            writer.write_all(reply).await.unwrap();
+                        }
+                    });
+                }
+            });
+            Self { port, messages }
+        }
+
+        fn received_messages(&self) -> Vec<String> {
+            self.messages.lock().unwrap().clone()
+        }
+    }
+
+    /// Generate a random subscriber email
+    fn email() -> SubscriberEmail {
+        SubscriberEmail::parse(SafeEmail().fake()).unwrap()
+    }
+
+    /// Get a test instance of `SmtpEmailClient`
+    fn smtp_email_client(port: u16) -> SmtpEmailClient {
+        SmtpEmailClient::new(
+            "127.0.0.1",
+            port,
+            None,
+            false,
+            email(),
+            std::time::Duration::from_millis(200),
+            RetryPolicy::no_retries(),
+        )
+        .unwrap()
+    }
+
+    async fn send_email(email_client: &SmtpEmailClient) -> Result<SendEmailResponse, EmailError> {
+        let subject: String = Sentence(1..2).fake();
+        let content: String = Paragraph(1..10).fake();
+        email_client
+            .send_email(
+                &email(),
+                &subject,
+                &content,
+                &content,
+                Some("https://my-api.com/subscriptions/unsubscribe?token=a-token"),
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn send_email_delivers_the_message_to_the_relay() {
+        // Arrange
+        let relay = SmtpStandIn::start(false).await;
+        let email_client = smtp_email_client(relay.port);
+
+        // Act
+        let outcome = send_email(&email_client).await;
+
+        // Assert
+        let response = assert_ok!(outcome);
+        let messages = relay.received_messages();
+        assert_eq!(messages.len(), 1);
+        let message = &messages[0];
+        assert!(message.contains(&format!("Message-ID: {}", response.message_id)));
+        assert!(message.contains(
+            "List-Unsubscribe: <https://my-api.com/subscriptions/unsubscribe?token=a-token>"
+        ));
+        assert!(message.contains("List-Unsubscribe-Post: List-Unsubscribe=One-Click"));
+        assert!(message.contains("Content-Type: text/plain"));
+        assert!(message.contains("Content-Type: text/html"));
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_if_the_relay_rejects_the_recipient() {
+        // Arrange
+        let relay = SmtpStandIn::start(true).await;
+        let email_client = smtp_email_client(relay.port);
+
+        // Act
+        let outcome = send_email(&email_client).await;
+
+        // Assert
+        let error = assert_err!(outcome);
+        assert!(!error.is_transient());
+        assert!(relay.received_messages().is_empty());
+    }
+}
@@ -1,14 +1,35 @@
 //! src/startup.rs
-use crate::configuration::{DatabaseSettings, Settings};
+use crate::authentication::RejectAnonymousUsers;
+use crate::configuration::{
+    DatabaseSettings, PostmarkWebhookSettings, RateLimitSettings, Settings,
+};
+use crate::email_templates::EmailTemplates;
+use crate::flash_messages::FlashMessagesFramework;
+use crate::idempotency::Idempotency;
+use crate::session_store::PostgresSessionStore;
 use crate::{
-    email_client::EmailClient,
-    routes::{confirm, health_check, subscribe},
+    email_outbox, issue_delivery_worker,
+    routes::{
+        admin_dashboard, change_password, change_password_form, complete_data_request, confirm,
+        confirm_email_change, data_request_form, erase_subscriber_data, export_subscriber,
+        handle_postmark_webhook, health_check, log_out, login, login_form, preferences_form,
+        publish_newsletter, request_my_data_erasure, request_my_data_export, resend_confirmation,
+        subscribe, subscribers_form, unsubscribe, unsubscribe_form, unsubscribe_from_all_lists,
+        update_preferences,
+    },
 };
+use actix_session::config::{CookieContentSecurity, PersistentSession};
+use actix_session::SessionMiddleware;
+use actix_web::cookie::{self, Key};
 use actix_web::dev::Server;
 use actix_web::web::Data;
 use actix_web::{web, App, HttpServer};
+use secrecy::Secret;
 use sqlx::{postgres::PgPoolOptions, PgPool};
 use std::net::TcpListener;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 use tracing_actix_web::TracingLogger;
 
 pub fn get_connection_pool(configuration: &DatabaseSettings) -> PgPool {
@@ -17,54 +38,201 @@ pub fn get_connection_pool(configuration: &DatabaseSettings) -> PgPool {
         .connect_lazy_with(configuration.with_db())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     listener: TcpListener,
     db_pool: PgPool,
-    email_client: EmailClient,
     // New parameter!
     base_url: String,
+    subscription_token_ttl: std::time::Duration,
+    hmac_secret: Secret<String>,
+    email_hash_secret: Secret<String>,
+    secret_key: Key,
+    session_ttl: std::time::Duration,
+    email_templates: Arc<EmailTemplates>,
+    postmark_webhook: PostmarkWebhookSettings,
+    rate_limits: RateLimitSettings,
 ) -> Result<Server, std::io::Error> {
+    let hmac_secret = Data::new(HmacSecret(hmac_secret));
+    let email_hash_secret = EmailHashSecret(email_hash_secret);
+    let session_store = PostgresSessionStore::new(db_pool.clone());
+    let session_lifecycle = PersistentSession::default()
+        .session_ttl(cookie::time::Duration::seconds(session_ttl.as_secs() as i64));
     let db_pool = Data::new(db_pool);
-    let email_client = Data::new(email_client);
     let base_url = Data::new(ApplicationBaseUrl(base_url));
+    let subscription_token_ttl = Data::new(SubscriptionTokenTtl(subscription_token_ttl));
+    let email_templates = Data::from(email_templates);
+    let postmark_webhook = Data::new(postmark_webhook);
+    let rate_limit_store = rate_limits.store(db_pool.get_ref().clone());
+    let subscribe_rate_limit = rate_limits.subscribe.middleware(
+        "subscribe",
+        rate_limit_store.clone(),
+        rate_limits.trust_forwarded_for,
+        email_hash_secret.clone(),
+    );
+    let confirm_rate_limit = rate_limits.confirm.middleware(
+        "confirm",
+        rate_limit_store,
+        rate_limits.trust_forwarded_for,
+        email_hash_secret.clone(),
+    );
+    let email_hash_secret = Data::new(email_hash_secret);
     let server = HttpServer::new(move || {
         App::new()
+            .wrap(FlashMessagesFramework::new(secret_key.clone()))
+            .wrap(
+                SessionMiddleware::builder(session_store.clone(), secret_key.clone())
+                    .session_lifecycle(session_lifecycle.clone())
+                    .cookie_content_security(CookieContentSecurity::Signed)
+                    .cookie_http_only(true)
+                    .build(),
+            )
             .wrap(TracingLogger::default())
             .route("/health_check", web::get().to(health_check))
-            .route("/subscriptions", web::post().to(subscribe))
-            .route("/subscriptions/confirm", web::get().to(confirm))
+            .route("/login", web::get().to(login_form))
+            .route("/login", web::post().to(login))
+            .service(
+                // Every admin page requires a logged-in session.
+                web::scope("/admin")
+                    .wrap(RejectAnonymousUsers)
+                    .route("/dashboard", web::get().to(admin_dashboard))
+                    .route("/password", web::get().to(change_password_form))
+                    .route("/password", web::post().to(change_password))
+                    .route("/subscribers", web::get().to(subscribers_form))
+                    .route("/subscribers/export", web::get().to(export_subscriber))
+                    .route("/subscribers/erase", web::post().to(erase_subscriber_data))
+                    .route("/logout", web::post().to(log_out)),
+            )
+            // Both routes send emails, or act on them, for anyone who
+            // asks: we cap how often they can be called.
+            .service(
+                web::resource("/subscriptions")
+                    .wrap(subscribe_rate_limit.clone())
+                    .route(web::post().to(subscribe)),
+            )
+            .service(
+                web::resource("/subscriptions/confirm")
+                    .wrap(confirm_rate_limit.clone())
+                    .route(web::get().to(confirm)),
+            )
+            .service(
+                // Shares its buckets with `/subscriptions`: both routes
+                // send a confirmation email.
+                web::resource("/subscriptions/resend-confirmation")
+                    .wrap(subscribe_rate_limit.clone())
+                    .route(web::post().to(resend_confirmation)),
+            )
+            .route(
+                "/subscriptions/unsubscribe",
+                web::get().to(unsubscribe_form),
+            )
+            .route("/subscriptions/unsubscribe", web::post().to(unsubscribe))
+            .route(
+                "/subscriptions/preferences",
+                web::get().to(preferences_form),
+            )
+            .route(
+                "/subscriptions/preferences",
+                web::post().to(update_preferences),
+            )
+            .route(
+                "/subscriptions/preferences/unsubscribe",
+                web::post().to(unsubscribe_from_all_lists),
+            )
+            .route(
+                "/subscriptions/preferences/export",
+                web::post().to(request_my_data_export),
+            )
+            .route(
+                "/subscriptions/preferences/erase",
+                web::post().to(request_my_data_erasure),
+            )
+            .route(
+                "/subscriptions/data-request",
+                web::get().to(data_request_form),
+            )
+            .route(
+                "/subscriptions/data-request",
+                web::post().to(complete_data_request),
+            )
+            .route(
+                "/subscriptions/confirm-email-change",
+                web::get().to(confirm_email_change),
+            )
+            .service(
+                web::resource("/newsletters")
+                    .wrap(Idempotency)
+                    .route(web::post().to(publish_newsletter)),
+            )
+            .route(
+                "/webhooks/postmark",
+                web::post().to(handle_postmark_webhook),
+            )
             .app_data(db_pool.clone())
-            .app_data(email_client.clone())
             .app_data(base_url.clone())
+            .app_data(subscription_token_ttl.clone())
+            .app_data(email_templates.clone())
+            .app_data(postmark_webhook.clone())
+            .app_data(hmac_secret.clone())
+            .app_data(email_hash_secret.clone())
     })
     .listen(listener)?
     .run();
     Ok(server)
 }
 
-// A new type to hold the newly built server and its port
+// A new type to hold the newly built server, its port and the
+// background workers running alongside it
 pub struct Application {
     port: u16,
     server: Server,
+    // Flipped to `true` to ask the background workers to stop
+    shutdown: watch::Sender<bool>,
+    workers: Vec<(&'static str, JoinHandle<Result<(), anyhow::Error>>)>,
 }
 impl Application {
     // We have converted the `build` function into a constructor for
     // `Application`.
     pub async fn build(configuration: Settings) -> Result<Self, std::io::Error> {
         let connection_pool = get_connection_pool(&configuration.database);
+        let secret_key = configuration
+            .application
+            .secret_key()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        // A broken template would only surface when the first email is
+        // sent: refuse to start instead.
+        let email_templates = configuration
+            .email_templates
+            .templates()
+            .map(Arc::new)
+            .map_err(|e| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?}", e))
+            })?;
 
-        // Build a new email client
-        let sender_email = configuration
-            .email_client
-            .sender()
-            .expect("Invalid sender email address.");
-        let timeout = configuration.email_client.timeout();
-        let email_client = EmailClient::new(
-            configuration.email_client.base_url,
-            sender_email,
-            configuration.email_client.authorization_token,
-            timeout,
-        );
+        // Start the background workers: they deliver the emails queued
+        // by the request handlers.
+        let (shutdown, shutdown_signal) = watch::channel(false);
+        let workers = vec![
+            (
+                "email outbox worker",
+                tokio::spawn(email_outbox::run_worker_until_stopped(
+                    connection_pool.clone(),
+                    configuration.email_client.clone().client(),
+                    shutdown_signal.clone(),
+                )),
+            ),
+            (
+                "issue delivery worker",
+                tokio::spawn(issue_delivery_worker::run_worker_until_stopped(
+                    connection_pool.clone(),
+                    configuration.email_client.clone().client(),
+                    email_templates.clone(),
+                    configuration.application.base_url.clone(),
+                    HmacSecret(configuration.application.hmac_secret.clone()),
+                    shutdown_signal,
+                )),
+            ),
+        ];
 
         let address = format!(
             "{}:{}",
@@ -72,16 +240,30 @@ impl Application {
         );
         let listener = TcpListener::bind(&address)?;
         let port = listener.local_addr().unwrap().port();
+        let subscription_token_ttl = configuration.application.subscription_token_ttl();
+        let session_ttl = configuration.application.session_ttl();
         let server = run(
             listener,
             connection_pool,
-            email_client,
             // New parameter!
             configuration.application.base_url,
+            subscription_token_ttl,
+            configuration.application.hmac_secret,
+            configuration.application.email_hash_secret,
+            secret_key,
+            session_ttl,
+            email_templates,
+            configuration.webhooks.postmark,
+            configuration.application.rate_limits,
         )?;
 
         // We "save" the bound port in one of `Application`'s fields
-        Ok(Self { port, server })
+        Ok(Self {
+            port,
+            server,
+            shutdown,
+            workers,
+        })
     }
     pub fn port(&self) -> u16 {
         self.port
@@ -90,7 +272,28 @@ impl Application {
     // A more expressive name that makes it clear that
     // this function only returns when the application is stopped.
     pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
-        self.server.await
+        let outcome = self.server.await;
+        // The server is gone: let the background workers finish what they
+        // are doing and wait for them to exit.
+        let _ = self.shutdown.send(true);
+        for (worker_name, worker) in self.workers {
+            match worker.await {
+                Ok(Ok(())) => tracing::info!("The {} has exited", worker_name),
+                Ok(Err(e)) => tracing::error!(
+                    error.cause_chain = ?e,
+                    error.message = %e,
+                    "The {} failed",
+                    worker_name
+                ),
+                Err(e) => tracing::error!(
+                    error.cause_chain = ?e,
+                    error.message = %e,
+                    "The {} task failed to complete",
+                    worker_name
+                ),
+            }
+        }
+        outcome
     }
 }
 
@@ -99,3 +302,17 @@ impl Application {
 // Retrieval from the context, in actix-web, is type-based: using
 // a raw `String` would expose us to conflicts.
 pub struct ApplicationBaseUrl(pub String);
+
+// How long a subscription token can be used to confirm a subscription
+// after it has been issued.
+pub struct SubscriptionTokenTtl(pub std::time::Duration);
+
+// The key we sign links with - e.g. the links to the preferences page.
+#[derive(Clone)]
+pub struct HmacSecret(pub Secret<String>);
+
+// The key of the digests we keep of email addresses - e.g. of erased
+// subscribers. It is not shared with anything else: knowing it is enough
+// to check whether an address was erased.
+#[derive(Clone)]
+pub struct EmailHashSecret(pub Secret<String>);
@@ -0,0 +1,303 @@
+//! src/subscriber_data.rs
+//!
+//! Data subject requests: exporting everything we hold about a subscriber,
+//! and erasing it.
+use crate::startup::EmailHashSecret;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use secrecy::ExposeSecret;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+/// Everything we hold about a subscriber.
+#[derive(serde::Serialize)]
+pub struct SubscriberData {
+    pub subscriber: SubscriberRecord,
+    /// When and how they consented to receive each of our lists.
+    pub list_memberships: Vec<ListMembershipRecord>,
+    pub subscription_tokens: Vec<SubscriptionTokenRecord>,
+    pub unsubscribe_tokens: Vec<UnsubscribeTokenRecord>,
+    pub email_change_requests: Vec<EmailChangeRequestRecord>,
+    /// The newsletter issues we delivered, or have yet to deliver, to them.
+    pub deliveries: Vec<DeliveryRecord>,
+    /// The transactional emails waiting to be sent to them.
+    pub pending_emails: Vec<PendingEmailRecord>,
+}
+
+#[derive(serde::Serialize)]
+pub struct SubscriberRecord {
+    pub id: Uuid,
+    pub email: String,
+    pub name: String,
+    pub subscribed_at: DateTime<Utc>,
+    pub locale: String,
+    pub send_frequency: String,
+    pub soft_bounce_count: i32,
+    pub confirmation_email_message_id: Option<String>,
+    pub confirmation_email_submitted_at: Option<DateTime<Utc>>,
+}
+
+#[derive(serde::Serialize)]
+pub struct ListMembershipRecord {
+    pub list: String,
+    pub status: String,
+    pub subscribed_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize)]
+pub struct SubscriptionTokenRecord {
+    pub list: String,
+    pub subscription_token: String,
+    pub issued_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize)]
+pub struct UnsubscribeTokenRecord {
+    pub list: String,
+    pub unsubscribe_token: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct EmailChangeRequestRecord {
+    pub new_email: String,
+    pub requested_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize)]
+pub struct DeliveryRecord {
+    pub newsletter_issue_id: Uuid,
+    pub title: String,
+    pub published_at: DateTime<Utc>,
+    pub status: String,
+    pub n_retries: i16,
+    pub execute_after: DateTime<Utc>,
+    pub last_error: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+pub struct PendingEmailRecord {
+    pub recipient_email: String,
+    pub subject: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[tracing::instrument(name = "Get subscriber id by email", skip(pool, email))]
+pub async fn get_subscriber_id_by_email(
+    pool: &PgPool,
+    email: &str,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    // Admins type the address as the subscriber wrote it to them, which
+    // need not be how they subscribed.
+    let row = sqlx::query!(
+        r#"SELECT id FROM subscriptions WHERE lower(email) = lower($1)"#,
+        email
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|row| row.id))
+}
+
+/// Gather everything we hold about a subscriber - `None` if there is no
+/// such subscriber.
+#[tracing::instrument(name = "Export subscriber data", skip(pool))]
+pub async fn export_subscriber_data(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<Option<SubscriberData>, sqlx::Error> {
+    // A single snapshot: the records cannot change between two queries.
+    let mut transaction = pool.begin().await?;
+    sqlx::query!("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ")
+        .execute(&mut transaction)
+        .await?;
+    let subscriber = sqlx::query_as!(
+        SubscriberRecord,
+        r#"SELECT id, email, name, subscribed_at, locale, send_frequency, soft_bounce_count,
+            confirmation_email_message_id, confirmation_email_submitted_at
+        FROM subscriptions WHERE id = $1"#,
+        subscriber_id
+    )
+    .fetch_optional(&mut transaction)
+    .await?;
+    let subscriber = match subscriber {
+        Some(subscriber) => subscriber,
+        None => return Ok(None),
+    };
+    let list_memberships = sqlx::query_as!(
+        ListMembershipRecord,
+        r#"SELECT l.slug AS "list!", m.status AS "status!", m.subscribed_at AS "subscribed_at!"
+        FROM list_memberships m
+        JOIN lists l ON l.list_id = m.list_id
+        WHERE m.subscriber_id = $1
+        ORDER BY l.slug"#,
+        subscriber_id
+    )
+    .fetch_all(&mut transaction)
+    .await?;
+    let subscription_tokens = sqlx::query_as!(
+        SubscriptionTokenRecord,
+        r#"SELECT l.slug AS "list!", t.subscription_token AS "subscription_token!",
+            t.issued_at AS "issued_at!"
+        FROM subscription_tokens t
+        JOIN lists l ON l.list_id = t.list_id
+        WHERE t.subscriber_id = $1
+        ORDER BY t.issued_at"#,
+        subscriber_id
+    )
+    .fetch_all(&mut transaction)
+    .await?;
+    let unsubscribe_tokens = sqlx::query_as!(
+        UnsubscribeTokenRecord,
+        r#"SELECT l.slug AS "list!", t.unsubscribe_token AS "unsubscribe_token!"
+        FROM unsubscribe_tokens t
+        JOIN lists l ON l.list_id = t.list_id
+        WHERE t.subscriber_id = $1
+        ORDER BY l.slug"#,
+        subscriber_id
+    )
+    .fetch_all(&mut transaction)
+    .await?;
+    let email_change_requests = sqlx::query_as!(
+        EmailChangeRequestRecord,
+        r#"SELECT new_email, requested_at FROM email_change_requests
+        WHERE subscriber_id = $1
+        ORDER BY requested_at"#,
+        subscriber_id
+    )
+    .fetch_all(&mut transaction)
+    .await?;
+    let deliveries = sqlx::query_as!(
+        DeliveryRecord,
+        r#"SELECT q.newsletter_issue_id AS "newsletter_issue_id!", i.title AS "title!",
+            i.published_at AS "published_at!", q.status AS "status!",
+            q.n_retries AS "n_retries!", q.execute_after AS "execute_after!", q.last_error
+        FROM issue_delivery_queue q
+        JOIN newsletter_issues i ON i.newsletter_issue_id = q.newsletter_issue_id
+        WHERE q.subscriber_id = $1
+        ORDER BY i.published_at"#,
+        subscriber_id
+    )
+    .fetch_all(&mut transaction)
+    .await?;
+    let pending_emails = sqlx::query_as!(
+        PendingEmailRecord,
+        r#"SELECT recipient_email, subject, created_at FROM email_outbox
+        WHERE subscriber_id = $1
+        ORDER BY created_at"#,
+        subscriber_id
+    )
+    .fetch_all(&mut transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(Some(SubscriberData {
+        subscriber,
+        list_memberships,
+        subscription_tokens,
+        unsubscribe_tokens,
+        email_change_requests,
+        deliveries,
+        pending_emails,
+    }))
+}
+
+/// Permanently delete a subscriber, along with every row that refers to
+/// them, leaving a tombstone of their address behind.
+///
+/// Returns `false` if there was no such subscriber.
+#[tracing::instrument(name = "Erase subscriber", skip(transaction, email_hash_secret))]
+pub async fn erase_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    email_hash_secret: &EmailHashSecret,
+) -> Result<bool, sqlx::Error> {
+    // Foreign keys cascade: tokens, memberships, queued deliveries and
+    // emails go along with the subscriber.
+    let row = sqlx::query!(
+        r#"DELETE FROM subscriptions WHERE id = $1 RETURNING email"#,
+        subscriber_id
+    )
+    .fetch_optional(&mut *transaction)
+    .await?;
+    let email = match row {
+        Some(row) => row.email,
+        None => return Ok(false),
+    };
+    sqlx::query!(
+        r#"INSERT INTO erased_subscribers (email_hash, erased_at)
+        VALUES ($1, $2)
+        ON CONFLICT (email_hash) DO UPDATE SET erased_at = EXCLUDED.erased_at"#,
+        email_hash(&email, email_hash_secret),
+        Utc::now()
+    )
+    .execute(&mut *transaction)
+    .await?;
+    Ok(true)
+}
+
+/// Whether the owner of `email` had us erase their data.
+#[tracing::instrument(
+    name = "Check whether an email was erased",
+    skip(pool, email, email_hash_secret)
+)]
+pub async fn is_erased(
+    pool: &PgPool,
+    email: &str,
+    email_hash_secret: &EmailHashSecret,
+) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT EXISTS (
+            SELECT 1 FROM erased_subscribers WHERE email_hash = $1
+        ) AS "erased!""#,
+        email_hash(email, email_hash_secret)
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(row.erased)
+}
+
+/// The tombstone of an address: recognises it whatever its case, but
+/// cannot be turned back into it.
+///
+/// The digest is keyed: without the secret, nobody can tell whether an
+/// address they picked was erased by hashing it themselves.
+pub(crate) fn email_hash(email: &str, secret: &EmailHashSecret) -> String {
+    let mut mac = Hmac::<sha2::Sha256>::new_from_slice(secret.0.expose_secret().as_bytes())
+        .expect("HMAC accepts keys of any length.");
+    mac.update(email.trim().to_lowercase().as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::email_hash;
+    use crate::startup::EmailHashSecret;
+    use secrecy::Secret;
+
+    fn secret(secret: &str) -> EmailHashSecret {
+        EmailHashSecret(Secret::new(secret.into()))
+    }
+
+    #[test]
+    fn tombstones_ignore_the_case_of_the_address() {
+        assert_eq!(
+            email_hash("Ursula_Le_Guin@Gmail.com", &secret("secret")),
+            email_hash("ursula_le_guin@gmail.com", &secret("secret"))
+        );
+    }
+
+    #[test]
+    fn tombstones_do_not_contain_the_address() {
+        let hash = email_hash("ursula_le_guin@gmail.com", &secret("secret"));
+
+        assert_eq!(hash.len(), 64);
+        assert!(!hash.contains("ursula"));
+        assert_ne!(hash, email_hash("ursula@example.com", &secret("secret")));
+    }
+
+    #[test]
+    fn tombstones_depend_on_the_secret() {
+        assert_ne!(
+            email_hash("ursula_le_guin@gmail.com", &secret("secret")),
+            email_hash("ursula_le_guin@gmail.com", &secret("another-secret"))
+        );
+    }
+}
@@ -0,0 +1,10 @@
+//! src/utils.rs
+use actix_web::http::header::LOCATION;
+use actix_web::HttpResponse;
+
+/// Redirect the client to `location`, switching to a `GET` request.
+pub fn see_other(location: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .insert_header((LOCATION, location))
+        .finish()
+}
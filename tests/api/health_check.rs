@@ -1,5 +1,8 @@
 //! tests/api/health_check.rs
 use crate::helpers::spawn_app;
+use newsletter::configuration::get_configuration;
+use newsletter::startup::Application;
+use secrecy::Secret;
 
 // `tokio::test` is the testing equivalent of `tokio::main`.
 // It also spares you from having to specify the `#[test]` attribute.
@@ -14,7 +17,7 @@ async fn health_check_works() {
     // Act
     let response = client
         // Use the returned application address
-        .get(&format!("{}/health_check", &app.address))
+        .get(format!("{}/health_check", &app.address))
         .send()
         .await
         .expect("Failed to execute request.");
@@ -22,3 +25,17 @@ async fn health_check_works() {
     assert!(response.status().is_success());
     assert_eq!(Some(0), response.content_length());
 }
+
+#[tokio::test]
+async fn the_application_refuses_to_start_with_a_short_hmac_secret() {
+    // Arrange
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    configuration.application.port = 0;
+    configuration.application.hmac_secret = Secret::new("too-short".into());
+
+    // Act
+    let outcome = Application::build(configuration).await;
+
+    // Assert
+    assert!(outcome.is_err());
+}
@@ -1,14 +1,25 @@
 //! tests/api/helpers.rs
+use chrono::Utc;
 use newsletter::{
-    configuration::{get_configuration, DatabaseSettings},
-    startup::{get_connection_pool, Application},
+    authentication::compute_password_hash,
+    configuration::{
+        get_configuration, DatabaseSettings, EmailProvider, PostmarkWebhookSettings, Settings,
+        TokenBucketSettings,
+    },
+    email_client::EmailSender,
+    email_outbox,
+    email_templates::EmailTemplates,
+    issue_delivery_worker,
+    startup::{get_connection_pool, Application, HmacSecret},
     telemetry::{get_subscriber, init_subscriber},
 };
 use once_cell::sync::Lazy;
+use secrecy::{ExposeSecret, Secret};
 use sqlx::{Connection, Executor, PgConnection, PgPool};
+use std::sync::Arc;
 use uuid::Uuid;
 // New import!
-use wiremock::MockServer;
+use wiremock::{MockServer, ResponseTemplate};
 
 pub struct TestApp {
     pub address: String,
@@ -17,18 +28,111 @@ pub struct TestApp {
     pub db_pool: PgPool,
     // New field!
     pub email_server: MockServer,
+    pub email_client: Arc<dyn EmailSender>,
+    pub email_templates: EmailTemplates,
+    pub base_url: String,
+    pub hmac_secret: HmacSecret,
+    pub postmark_webhook: PostmarkWebhookSettings,
+    pub test_user: TestUser,
+    // Keeps the cookies set by the application, like a browser would
+    pub api_client: reqwest::Client,
 }
 
-/// Confirmation links embedded in the request to the email API.
+/// A user allowed to access the restricted endpoints of the application.
+pub struct TestUser {
+    pub user_id: Uuid,
+    pub username: String,
+    pub password: String,
+}
+
+impl TestUser {
+    pub fn generate() -> Self {
+        Self {
+            user_id: Uuid::new_v4(),
+            username: Uuid::new_v4().to_string(),
+            password: Uuid::new_v4().to_string(),
+        }
+    }
+
+    pub async fn login(&self, app: &TestApp) {
+        app.post_login(&serde_json::json!({
+            "username": &self.username,
+            "password": &self.password
+        }))
+        .await;
+    }
+
+    pub async fn store(&self, pool: &PgPool) {
+        let password_hash = compute_password_hash(Secret::new(self.password.clone())).unwrap();
+        sqlx::query!(
+            "INSERT INTO users (user_id, username, password_hash)
+            VALUES ($1, $2, $3)",
+            self.user_id,
+            self.username,
+            password_hash.expose_secret(),
+        )
+        .execute(pool)
+        .await
+        .expect("Failed to store test user.");
+    }
+}
+
+/// Links embedded in the request to the email API.
 pub struct ConfirmationLinks {
     pub html: reqwest::Url,
     pub plain_text: reqwest::Url,
 }
 
 impl TestApp {
+    /// Deliver every email currently due in the outbox and in the
+    /// newsletter issue delivery queue.
+    ///
+    /// The application's own workers run concurrently: we keep going
+    /// until no due task is left in either table, including those they
+    /// might be in the middle of delivering.
+    pub async fn dispatch_all_pending_emails(&self) {
+        loop {
+            let outbox_outcome =
+                email_outbox::try_execute_task(&self.db_pool, self.email_client.as_ref())
+                    .await
+                    .unwrap();
+            let issue_outcome = issue_delivery_worker::try_execute_task(
+                &self.db_pool,
+                self.email_client.as_ref(),
+                &self.email_templates,
+                &self.base_url,
+                &self.hmac_secret,
+            )
+            .await
+            .unwrap();
+            if let (
+                email_outbox::ExecutionOutcome::EmptyQueue,
+                issue_delivery_worker::ExecutionOutcome::EmptyQueue,
+            ) = (outbox_outcome, issue_outcome)
+            {
+                let pending = sqlx::query!(
+                    r#"SELECT
+                        (SELECT count(*) FROM email_outbox
+                           WHERE status = 'pending' AND execute_after <= now())
+                        + (SELECT count(*) FROM issue_delivery_queue
+                           WHERE status IN ('pending', 'in_flight')
+                             AND execute_after <= now())
+                        AS "count!""#,
+                )
+                .fetch_one(&self.db_pool)
+                .await
+                .unwrap();
+                if pending.count == 0 {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        }
+    }
+
     pub async fn post_subscriptions(&self, body: String) -> reqwest::Response {
         reqwest::Client::new()
-            .post(&format!("{}/subscriptions", &self.address))
+            .post(format!("{}/subscriptions", &self.address))
             .header("Content-Type", "application/x-www-form-urlencoded")
             .body(body)
             .send()
@@ -36,32 +140,254 @@ impl TestApp {
             .expect("Failed to execute request.")
     }
 
+    /// Submit the form behind an unsubscribe link.
+    pub async fn post_unsubscribe(&self, unsubscribe_link: reqwest::Url) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(unsubscribe_link)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_resend_confirmation(&self, body: String) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!(
+                "{}/subscriptions/resend-confirmation",
+                &self.address
+            ))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_newsletters(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/newsletters", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_newsletters_with_idempotency_key(
+        &self,
+        body: serde_json::Value,
+        idempotency_key: &str,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/newsletters", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .header("Idempotency-Key", idempotency_key)
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// Post `body` to the Postmark webhook, as Postmark would.
+    pub async fn post_postmark_webhook(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/webhooks/postmark", &self.address))
+            .basic_auth(
+                &self.postmark_webhook.username,
+                Some(self.postmark_webhook.secret.expose_secret()),
+            )
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_login<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!("{}/login", &self.address))
+            // This `reqwest` method makes sure that the body is URL-encoded
+            // and the `Content-Type` header is set accordingly.
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_login_html(&self) -> String {
+        self.api_client
+            .get(format!("{}/login", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn get_admin_dashboard(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/dashboard", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_admin_dashboard_html(&self) -> String {
+        self.get_admin_dashboard().await.text().await.unwrap()
+    }
+
+    pub async fn get_change_password(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/password", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_change_password_html(&self) -> String {
+        self.get_change_password().await.text().await.unwrap()
+    }
+
+    pub async fn post_change_password<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!("{}/admin/password", &self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_subscriber_export(&self, email: &str) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/subscribers/export", &self.address))
+            .query(&[("email", email)])
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_subscriber_erasure(&self, email: &str) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/admin/subscribers/erase", &self.address))
+            .form(&[("email", email)])
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_admin_subscribers_html(&self) -> String {
+        self.api_client
+            .get(format!("{}/admin/subscribers", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn post_logout(&self) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/admin/logout", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
     /// Extract the confirmation links embedded in the request to the email API.
     pub fn get_confirmation_links(&self, email_request: &wiremock::Request) -> ConfirmationLinks {
-        let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+        self.get_links(email_request, "/subscriptions/confirm")
+    }
+
+    /// Extract the unsubscribe links embedded in the request to the email API.
+    pub fn get_unsubscribe_links(&self, email_request: &wiremock::Request) -> ConfirmationLinks {
+        self.get_links(email_request, "/subscriptions/unsubscribe")
+    }
+
+    /// Extract the preferences link embedded in the request to the email API.
+    ///
+    /// Only the plain text body is searched: the query string of the link
+    /// is HTML-escaped in the other one.
+    pub fn get_preferences_link(&self, email_request: &wiremock::Request) -> reqwest::Url {
+        let body = email_body(email_request);
+        let links: Vec<_> = linkify::LinkFinder::new()
+            .links(body["TextBody"].as_str().unwrap())
+            .filter(|l| *l.kind() == linkify::LinkKind::Url)
+            .map(|l| reqwest::Url::parse(l.as_str()).unwrap())
+            .filter(|l| l.path() == "/subscriptions/preferences")
+            .collect();
+        assert_eq!(links.len(), 1);
+        let mut link = links[0].clone();
+        assert_eq!(link.host_str().unwrap(), "127.0.0.1");
+        link.set_port(Some(self.port)).unwrap();
+        link
+    }
+
+    pub async fn get_preferences_html(&self, preferences_link: &reqwest::Url) -> String {
+        self.api_client
+            .get(preferences_link.clone())
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn post_preferences<Body>(
+        &self,
+        preferences_link: &reqwest::Url,
+        body: &Body,
+    ) -> reqwest::Response
+    where
+        Body: serde::Serialize + ?Sized,
+    {
+        self.api_client
+            .post(preferences_link.clone())
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// Extract the link pointing at `link_path` from both bodies of
+    /// the request to the email API.
+    fn get_links(&self, email_request: &wiremock::Request, link_path: &str) -> ConfirmationLinks {
+        let body = email_body(email_request);
 
         // Extract the link from one of the request fields.
         let get_link = |s: &str| {
             let links: Vec<_> = linkify::LinkFinder::new()
                 .links(s)
                 .filter(|l| *l.kind() == linkify::LinkKind::Url)
+                .map(|l| reqwest::Url::parse(l.as_str()).unwrap())
+                .filter(|l| l.path() == link_path)
                 .collect();
             assert_eq!(links.len(), 1);
-            let raw_link = links[0].as_str().to_owned();
-            let mut confirmation_link = reqwest::Url::parse(&raw_link).unwrap();
+            let mut link = links[0].clone();
             // Let's make sure we don't call random APIs on the web
-            assert_eq!(confirmation_link.host_str().unwrap(), "127.0.0.1");
-            confirmation_link.set_port(Some(self.port)).unwrap();
-            confirmation_link
+            assert_eq!(link.host_str().unwrap(), "127.0.0.1");
+            link.set_port(Some(self.port)).unwrap();
+            link
         };
 
-        let html = get_link(&body["HtmlBody"].as_str().unwrap());
-        let plain_text = get_link(&body["TextBody"].as_str().unwrap());
+        let html = get_link(body["HtmlBody"].as_str().unwrap());
+        let plain_text = get_link(body["TextBody"].as_str().unwrap());
         ConfirmationLinks { html, plain_text }
     }
 }
 
 pub async fn spawn_app() -> TestApp {
+    spawn_app_with(|_| {}).await
+}
+
+/// Like `spawn_app`, with a chance to tweak the configuration first.
+pub async fn spawn_app_with(configure: impl FnOnce(&mut Settings)) -> TestApp {
     // The first time `initialize` is invoked the code in `TRACING` is executed.
     // All other invocations will instead skip execution.
     Lazy::force(&TRACING);
@@ -77,7 +403,22 @@ pub async fn spawn_app() -> TestApp {
         // Use a random OS port
         c.application.port = 0;
         // Use the mock server as email API
+        c.email_client.provider = EmailProvider::Postmark;
         c.email_client.base_url = email_server.uri();
+        // Retries are exercised by the email client's own tests: here every
+        // mocked failure should reach the delivery workers straight away.
+        c.email_client.retry.max_attempts = 1;
+        // Rate limits are exercised by their own tests: everywhere else,
+        // every request sent from the test suite should go through.
+        let no_limit = TokenBucketSettings {
+            capacity: u32::MAX,
+            refill_interval_milliseconds: 1,
+        };
+        c.application.rate_limits.subscribe.per_ip = no_limit.clone();
+        c.application.rate_limits.subscribe.per_email = None;
+        c.application.rate_limits.confirm.per_ip = no_limit;
+        c.application.rate_limits.confirm.per_email = None;
+        configure(&mut c);
         c
     };
 
@@ -91,14 +432,28 @@ pub async fn spawn_app() -> TestApp {
         .expect("Failed to build application.");
     // Get the port before spawning the application
     let application_port = application.port();
+    #[allow(clippy::let_underscore_future)]
     let _ = tokio::spawn(application.run_until_stopped());
 
-    TestApp {
+    let test_app = TestApp {
         address: format!("http://localhost:{}", application_port),
         port: application_port,
         db_pool: get_connection_pool(&configuration.database),
         email_server,
-    }
+        email_client: configuration.email_client.client(),
+        email_templates: configuration.email_templates.templates().unwrap(),
+        base_url: configuration.application.base_url,
+        hmac_secret: HmacSecret(configuration.application.hmac_secret),
+        postmark_webhook: configuration.webhooks.postmark,
+        test_user: TestUser::generate(),
+        api_client: reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .cookie_store(true)
+            .build()
+            .unwrap(),
+    };
+    test_app.test_user.store(&test_app.db_pool).await;
+    test_app
 }
 
 // Ensure that the `tracing` stack is only initialised once using `once_cell`
@@ -148,3 +503,56 @@ async fn configure_database(config: &DatabaseSettings) -> PgPool {
 
     connection_pool
 }
+
+pub fn assert_is_redirect_to(response: &reqwest::Response, location: &str) {
+    assert_eq!(response.status().as_u16(), 303);
+    assert_eq!(response.headers().get("Location").unwrap(), location);
+}
+
+/// The email carried by a request to the email API - the only one, for
+/// requests to the batch endpoint.
+pub fn email_body(email_request: &wiremock::Request) -> serde_json::Value {
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    match body {
+        serde_json::Value::Array(mut emails) => {
+            assert_eq!(emails.len(), 1);
+            emails.pop().unwrap()
+        }
+        email => email,
+    }
+}
+
+/// What Postmark answers when it accepts an email.
+pub fn email_accepted() -> ResponseTemplate {
+    email_accepted_with_message_id(&Uuid::new_v4().to_string())
+}
+
+pub fn email_accepted_with_message_id(message_id: &str) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_json(serde_json::json!({
+        "To": "recipient@example.com",
+        "SubmittedAt": Utc::now().to_rfc3339(),
+        "MessageID": message_id,
+        "ErrorCode": 0,
+        "Message": "OK"
+    }))
+}
+
+/// What Postmark answers when it accepts every email of a batch.
+pub fn batch_accepted() -> impl wiremock::Respond {
+    |request: &wiremock::Request| {
+        let emails: Vec<serde_json::Value> = serde_json::from_slice(&request.body).unwrap();
+        let entries: Vec<_> = emails
+            .iter()
+            .map(|email| {
+                serde_json::json!({
+                    "To": email["To"],
+                    "SubmittedAt": Utc::now().to_rfc3339(),
+                    "MessageID": Uuid::new_v4().to_string(),
+                    "ErrorCode": 0,
+                    "Message": "OK"
+                })
+            })
+            .collect();
+        ResponseTemplate::new(200).set_body_json(entries)
+    }
+}
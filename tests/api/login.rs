@@ -0,0 +1,96 @@
+//! tests/api/login.rs
+use crate::helpers::{assert_is_redirect_to, spawn_app};
+
+#[tokio::test]
+async fn an_error_flash_message_is_set_on_failure() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act - Part 1 - Try to login
+    let login_body = serde_json::json!({
+        "username": "random-username",
+        "password": "random-password"
+    });
+    let response = app.post_login(&login_body).await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/login");
+
+    // Act - Part 2 - Follow the redirect
+    let html_page = app.get_login_html().await;
+    assert!(html_page.contains(r#"<p><i>Authentication failed</i></p>"#));
+
+    // Act - Part 3 - Reload the login page
+    let html_page = app.get_login_html().await;
+    assert!(!html_page.contains(r#"<p><i>Authentication failed</i></p>"#));
+}
+
+#[tokio::test]
+async fn redirect_to_admin_dashboard_after_login_success() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act - Part 1 - Login
+    let login_body = serde_json::json!({
+        "username": &app.test_user.username,
+        "password": &app.test_user.password
+    });
+    let response = app.post_login(&login_body).await;
+    assert_is_redirect_to(&response, "/admin/dashboard");
+
+    // Act - Part 2 - Follow the redirect
+    let html_page = app.get_admin_dashboard_html().await;
+    assert!(html_page.contains(&format!("Welcome {}", app.test_user.username)));
+}
+
+#[tokio::test]
+async fn sessions_are_stored_in_postgres() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let login_body = serde_json::json!({
+        "username": &app.test_user.username,
+        "password": &app.test_user.password
+    });
+    app.post_login(&login_body).await;
+
+    // Assert
+    let saved = sqlx::query!(r#"SELECT state, expires_at > now() AS "active!" FROM sessions"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved session.");
+    assert!(saved.active);
+    assert_eq!(
+        saved.state["user_id"],
+        serde_json::json!(format!(r#""{}""#, app.test_user.user_id))
+    );
+}
+
+#[tokio::test]
+async fn the_session_cookie_is_signed_and_http_only() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let login_body = serde_json::json!({
+        "username": &app.test_user.username,
+        "password": &app.test_user.password
+    });
+    let response = app.post_login(&login_body).await;
+
+    // Assert
+    let session_cookie = response
+        .cookies()
+        .find(|c| c.name() == "id")
+        .expect("No session cookie was set.");
+    assert!(session_cookie.http_only());
+    // The value starts with the signature, not with the session key.
+    let session_key: String = sqlx::query!("SELECT session_key FROM sessions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .session_key;
+    assert_ne!(session_cookie.value(), session_key);
+    assert!(session_cookie.value().ends_with(&session_key));
+}
@@ -0,0 +1,259 @@
+//! tests/api/mailing_lists.rs
+use crate::helpers::{batch_accepted, email_accepted, spawn_app, ConfirmationLinks, TestApp};
+use wiremock::matchers::{any, method, path};
+use wiremock::Mock;
+
+const SECOND_LIST: &str = "rust-weekly";
+
+async fn create_second_list(app: &TestApp) {
+    sqlx::query!(
+        r#"INSERT INTO lists (list_id, slug, name)
+        VALUES (gen_random_uuid(), $1, 'Rust Weekly')"#,
+        SECOND_LIST
+    )
+    .execute(&app.db_pool)
+    .await
+    .expect("Failed to create the second list.");
+}
+
+/// Subscribe to `list` (the default list if `None`), returning the
+/// confirmation email.
+async fn subscribe_to(app: &TestApp, list: Option<&str>) -> wiremock::Request {
+    let mut body = "name=le%20guin&email=ursula_le_guin%40gmail.com".to_string();
+    if let Some(list) = list {
+        body.push_str(&format!("&list={}", list));
+    }
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(email_accepted())
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_subscriptions(body)
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+    app.email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap()
+}
+
+async fn confirm(links: ConfirmationLinks) {
+    reqwest::get(links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+}
+
+/// The status of the subscriber on each list, by list slug.
+async fn membership_statuses(app: &TestApp) -> Vec<(String, String)> {
+    sqlx::query!(
+        r#"SELECT l.slug, m.status
+        FROM list_memberships m
+        JOIN lists l ON l.list_id = m.list_id
+        ORDER BY l.slug"#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|row| (row.slug, row.status))
+    .collect()
+}
+
+#[tokio::test]
+async fn subscribing_to_a_second_list_reuses_the_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    create_second_list(&app).await;
+    subscribe_to(&app, None).await;
+
+    // Act
+    let email_request = subscribe_to(&app, Some(SECOND_LIST)).await;
+
+    // Assert
+    let subscribers = sqlx::query!(r#"SELECT count(*) AS "count!" FROM subscriptions"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(subscribers.count, 1);
+    assert_eq!(
+        membership_statuses(&app).await,
+        vec![
+            ("newsletter".into(), "pending_confirmation".into()),
+            (SECOND_LIST.into(), "pending_confirmation".into()),
+        ]
+    );
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert!(body["TextBody"].as_str().unwrap().contains("Rust Weekly"));
+}
+
+#[tokio::test]
+async fn subscribing_to_an_unknown_list_is_rejected_with_a_400() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(any())
+        .respond_with(email_accepted())
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_subscriptions(
+            "name=le%20guin&email=ursula_le_guin%40gmail.com&list=not-a-list".into(),
+        )
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+    assert!(membership_statuses(&app).await.is_empty());
+}
+
+#[tokio::test]
+async fn confirmation_links_only_confirm_the_list_they_were_sent_for() {
+    // Arrange
+    let app = spawn_app().await;
+    create_second_list(&app).await;
+    subscribe_to(&app, None).await;
+    let email_request = subscribe_to(&app, Some(SECOND_LIST)).await;
+
+    // Act
+    confirm(app.get_confirmation_links(&email_request)).await;
+
+    // Assert
+    assert_eq!(
+        membership_statuses(&app).await,
+        vec![
+            ("newsletter".into(), "pending_confirmation".into()),
+            (SECOND_LIST.into(), "confirmed".into()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn unsubscribing_from_a_list_keeps_the_other_memberships() {
+    // Arrange
+    let app = spawn_app().await;
+    create_second_list(&app).await;
+    let first = subscribe_to(&app, None).await;
+    confirm(app.get_confirmation_links(&first)).await;
+    let second = subscribe_to(&app, Some(SECOND_LIST)).await;
+    confirm(app.get_confirmation_links(&second)).await;
+
+    // Act
+    app.post_unsubscribe(app.get_unsubscribe_links(&second).html)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    // Assert
+    assert_eq!(
+        membership_statuses(&app).await,
+        vec![
+            ("newsletter".into(), "confirmed".into()),
+            (SECOND_LIST.into(), "unsubscribed".into()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn newsletters_are_only_delivered_to_members_of_their_list() {
+    // Arrange
+    let app = spawn_app().await;
+    create_second_list(&app).await;
+    let email_request = subscribe_to(&app, None).await;
+    confirm(app.get_confirmation_links(&email_request)).await;
+
+    Mock::given(any())
+        .respond_with(email_accepted())
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "list": SECOND_LIST,
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 202);
+    // Mock verifies on Drop that we haven't sent the newsletter email
+}
+
+#[tokio::test]
+async fn newsletters_carry_the_unsubscribe_link_of_their_list() {
+    // Arrange
+    let app = spawn_app().await;
+    create_second_list(&app).await;
+    let first = subscribe_to(&app, None).await;
+    confirm(app.get_confirmation_links(&first)).await;
+    let second = subscribe_to(&app, Some(SECOND_LIST)).await;
+    confirm(app.get_confirmation_links(&second)).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(batch_accepted())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_newsletters(serde_json::json!({
+        "title": "Newsletter title",
+        "list": SECOND_LIST,
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    }))
+    .await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let newsletter = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    assert_eq!(
+        app.get_unsubscribe_links(&newsletter).html,
+        app.get_unsubscribe_links(&second).html
+    );
+}
+
+#[tokio::test]
+async fn publishing_to_an_unknown_list_is_rejected_with_a_400() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "list": "not-a-list",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+}
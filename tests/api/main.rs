@@ -1,6 +1,17 @@
 //! tests/api/main.rs
+mod admin_dashboard;
+mod change_password;
 mod health_check;
 mod helpers;
+mod login;
+mod mailing_lists;
+mod newsletters;
+mod rate_limits;
+mod subscriber_data;
 mod subscriptions;
 // New module!
 mod subscriptions_confirm;
+mod subscriptions_preferences;
+mod subscriptions_resend_confirmation;
+mod subscriptions_unsubscribe;
+mod webhooks_postmark;
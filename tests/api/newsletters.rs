@@ -0,0 +1,858 @@
+//! tests/api/newsletters.rs
+use crate::helpers::{
+    batch_accepted, email_accepted, email_body, spawn_app, ConfirmationLinks, TestApp, TestUser,
+};
+use newsletter::domain::SubscriberEmail;
+use newsletter::email_client::{BatchEmail, EmailError, EmailSender, SendEmailResponse};
+use newsletter::issue_delivery_worker::{self, MAX_ATTEMPTS};
+use uuid::Uuid;
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+#[tokio::test]
+async fn newsletters_are_not_delivered_to_unconfirmed_subscribers() {
+    // Arrange
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber(&app).await;
+
+    Mock::given(any())
+        .respond_with(email_accepted())
+        // We assert that no request is fired at Postmark!
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 202);
+    // Mock verifies on Drop that we haven't sent the newsletter email
+}
+
+#[tokio::test]
+async fn newsletters_are_delivered_to_confirmed_subscribers() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(batch_accepted())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 202);
+    // Mock verifies on Drop that we have sent the newsletter email
+}
+
+#[tokio::test]
+async fn newsletters_written_in_markdown_are_delivered_as_html_and_plain_text() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(batch_accepted())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "markdown": "Read **the** [blog](https://example.com/blog)!\n\n\
+                    <script>alert('pwned')</script>"
+            }
+        }))
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 202);
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body = email_body(&email_request);
+    let html = body["HtmlBody"].as_str().unwrap();
+    assert!(html.contains("<strong>the</strong>"));
+    assert!(html.contains("https://example.com/blog"));
+    assert!(!html.contains("<script>"));
+    let text = body["TextBody"].as_str().unwrap();
+    assert!(text.starts_with("Read the blog [1]!"));
+    assert!(text.contains("[1] https://example.com/blog"));
+}
+
+#[tokio::test]
+async fn newsletters_are_delivered_in_the_locale_of_the_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    sqlx::query!("UPDATE subscriptions SET locale = 'fr'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(batch_accepted())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body = email_body(&email_request);
+    assert!(body["HtmlBody"]
+        .as_str()
+        .unwrap()
+        .contains("Se désinscrire"));
+    assert!(body["TextBody"]
+        .as_str()
+        .unwrap()
+        .contains("Se désinscrire"));
+    // Links still work in every language.
+    app.get_unsubscribe_links(&email_request);
+}
+
+#[tokio::test]
+async fn newsletters_are_not_delivered_to_unsubscribed_subscribers() {
+    // Arrange
+    let app = spawn_app().await;
+    let confirmation_links = create_unconfirmed_subscriber(&app).await;
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let unsubscribe_links = app.get_unsubscribe_links(email_request);
+    app.post_unsubscribe(unsubscribe_links.html)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    Mock::given(any())
+        .respond_with(email_accepted())
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 202);
+}
+
+#[tokio::test]
+async fn confirmed_subscribers_with_an_invalid_stored_email_are_skipped() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    // Bypass the domain validation to simulate a row that no longer
+    // satisfies our constraints.
+    let subscriber_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"INSERT INTO subscriptions (id, email, name, subscribed_at)
+        VALUES ($1, 'definitely-not-an-email', 'le guin', now())"#,
+        subscriber_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    sqlx::query!(
+        r#"INSERT INTO list_memberships (subscriber_id, list_id, status, subscribed_at)
+        SELECT $1, list_id, 'confirmed', now() FROM lists WHERE slug = 'newsletter'"#,
+        subscriber_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    sqlx::query!(
+        r#"INSERT INTO unsubscribe_tokens (unsubscribe_token, subscriber_id, list_id)
+        SELECT 'an-unsubscribe-token', $1, list_id FROM lists WHERE slug = 'newsletter'"#,
+        subscriber_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(batch_accepted())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 202);
+}
+
+#[tokio::test]
+async fn newsletters_are_sent_in_batches_with_an_outcome_per_recipient() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"INSERT INTO subscriptions (id, email, name, subscribed_at)
+        VALUES ($1, 'inactive@example.com', 'le guin', now())"#,
+        subscriber_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    sqlx::query!(
+        r#"INSERT INTO list_memberships (subscriber_id, list_id, status, subscribed_at)
+        SELECT $1, list_id, 'confirmed', now() FROM lists WHERE slug = 'newsletter'"#,
+        subscriber_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    sqlx::query!(
+        r#"INSERT INTO unsubscribe_tokens (unsubscribe_token, subscriber_id, list_id)
+        SELECT 'an-unsubscribe-token', $1, list_id FROM lists WHERE slug = 'newsletter'"#,
+        subscriber_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    // Postmark accepts the batch, but turns down one of its emails.
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(|request: &wiremock::Request| {
+            let emails: Vec<serde_json::Value> = serde_json::from_slice(&request.body).unwrap();
+            let entries: Vec<_> = emails
+                .iter()
+                .map(|email| {
+                    if email["To"] == "inactive@example.com" {
+                        serde_json::json!({
+                            "ErrorCode": 406,
+                            "Message": "You tried to send to a recipient that has been marked as inactive."
+                        })
+                    } else {
+                        serde_json::json!({
+                            "To": email["To"],
+                            "SubmittedAt": "2022-06-04T10:15:02Z",
+                            "MessageID": Uuid::new_v4().to_string(),
+                            "ErrorCode": 0,
+                            "Message": "OK"
+                        })
+                    }
+                })
+                .collect();
+            ResponseTemplate::new(200).set_body_json(entries)
+        })
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_newsletters(newsletter_request_body())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let batch = &app.email_server.received_requests().await.unwrap()[1];
+    let emails: Vec<serde_json::Value> = serde_json::from_slice(&batch.body).unwrap();
+    assert_eq!(emails.len(), 2);
+    let statuses = sqlx::query!(
+        r#"SELECT s.email, q.status
+        FROM issue_delivery_queue q
+        JOIN subscriptions s ON s.id = q.subscriber_id
+        ORDER BY s.email"#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|row| (row.email, row.status))
+    .collect::<Vec<_>>();
+    assert_eq!(
+        statuses,
+        vec![
+            (
+                "inactive@example.com".to_string(),
+                "dead_letter".to_string()
+            ),
+            (
+                "ursula_le_guin@gmail.com".to_string(),
+                "delivered".to_string()
+            ),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn tasks_that_cannot_be_prepared_are_moved_to_the_dead_letter_state() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    // Without an unsubscribe link, there is no way to render the issue.
+    sqlx::query!("DELETE FROM unsubscribe_tokens")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    Mock::given(any())
+        .respond_with(email_accepted())
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_newsletters(newsletter_request_body())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let task = get_delivery_task(&app).await;
+    assert_eq!(task.status, "dead_letter");
+    assert!(task.last_error.is_some());
+}
+
+#[tokio::test]
+async fn transient_delivery_failures_are_retried_later() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 202);
+    let task = get_delivery_task(&app).await;
+    assert_eq!(task.status, "pending");
+    assert_eq!(task.n_retries, 1);
+    assert!(task.last_error.is_some());
+}
+
+#[tokio::test]
+async fn permanent_delivery_failures_are_moved_to_the_dead_letter_state() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(422))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_newsletters(newsletter_request_body())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let task = get_delivery_task(&app).await;
+    assert_eq!(task.status, "dead_letter");
+    assert_eq!(task.n_retries, 1);
+}
+
+#[tokio::test]
+async fn deliveries_are_moved_to_the_dead_letter_state_after_too_many_attempts() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(503))
+        .expect(MAX_ATTEMPTS as u64)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_newsletters(newsletter_request_body())
+        .await
+        .error_for_status()
+        .unwrap();
+    for _ in 0..MAX_ATTEMPTS {
+        app.dispatch_all_pending_emails().await;
+        // Skip the back-off period.
+        sqlx::query!("UPDATE issue_delivery_queue SET execute_after = now()")
+            .execute(&app.db_pool)
+            .await
+            .unwrap();
+    }
+
+    // Assert
+    let task = get_delivery_task(&app).await;
+    assert_eq!(task.status, "dead_letter");
+    assert_eq!(task.n_retries, MAX_ATTEMPTS);
+}
+
+#[tokio::test]
+async fn deliveries_claimed_by_another_worker_are_left_alone() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(batch_accepted())
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_newsletters(newsletter_request_body())
+        .await
+        .error_for_status()
+        .unwrap();
+    sqlx::query!(
+        "UPDATE issue_delivery_queue \
+        SET status = 'in_flight', execute_after = now() + interval '5 minutes'"
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let task = get_delivery_task(&app).await;
+    assert_eq!(task.status, "in_flight");
+}
+
+#[tokio::test]
+async fn deliveries_are_claimed_again_once_their_lease_runs_out() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(batch_accepted())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_newsletters(newsletter_request_body())
+        .await
+        .error_for_status()
+        .unwrap();
+    // The worker that claimed the task never reported back.
+    sqlx::query!(
+        "UPDATE issue_delivery_queue \
+        SET status = 'in_flight', execute_after = now() - interval '1 minute'"
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let task = get_delivery_task(&app).await;
+    assert_eq!(task.status, "delivered");
+}
+
+/// An email client that loses track of the outcomes of a batch.
+struct ForgetfulEmailClient;
+
+#[async_trait::async_trait]
+impl EmailSender for ForgetfulEmailClient {
+    async fn send_email(
+        &self,
+        _recipient: &SubscriberEmail,
+        _subject: &str,
+        _html_content: &str,
+        _text_content: &str,
+        _unsubscribe_link: Option<&str>,
+    ) -> Result<SendEmailResponse, EmailError> {
+        unreachable!("Newsletter issues are sent in batches.")
+    }
+
+    async fn send_batch(
+        &self,
+        _batch: &[BatchEmail<'_>],
+    ) -> Vec<Result<SendEmailResponse, EmailError>> {
+        Vec::new()
+    }
+}
+
+#[tokio::test]
+async fn deliveries_without_an_outcome_are_moved_to_the_dead_letter_state() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.post_newsletters(newsletter_request_body())
+        .await
+        .error_for_status()
+        .unwrap();
+
+    // Act
+    issue_delivery_worker::try_execute_task(
+        &app.db_pool,
+        &ForgetfulEmailClient,
+        &app.email_templates,
+        &app.base_url,
+        &app.hmac_secret,
+    )
+    .await
+    .unwrap();
+
+    // Assert
+    let task = get_delivery_task(&app).await;
+    assert_eq!(task.status, "dead_letter");
+    assert!(task.last_error.is_some());
+}
+
+#[tokio::test]
+async fn requests_missing_authorization_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .json(&newsletter_request_body())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(
+        r#"Basic realm="publish""#,
+        response.headers()["WWW-Authenticate"]
+    );
+}
+
+#[tokio::test]
+async fn non_existing_user_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    // Random credentials
+    let username = Uuid::new_v4().to_string();
+    let password = Uuid::new_v4().to_string();
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .basic_auth(username, Some(password))
+        .json(&newsletter_request_body())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(
+        r#"Basic realm="publish""#,
+        response.headers()["WWW-Authenticate"]
+    );
+}
+
+#[tokio::test]
+async fn invalid_password_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    let username = &app.test_user.username;
+    // Random password
+    let password = Uuid::new_v4().to_string();
+    assert_ne!(app.test_user.password, password);
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .basic_auth(username, Some(password))
+        .json(&newsletter_request_body())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(
+        r#"Basic realm="publish""#,
+        response.headers()["WWW-Authenticate"]
+    );
+}
+
+#[tokio::test]
+async fn newsletter_creation_is_idempotent() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(batch_accepted())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act - Part 1 - Publish the newsletter
+    let idempotency_key = Uuid::new_v4().to_string();
+    let response = app
+        .post_newsletters_with_idempotency_key(newsletter_request_body(), &idempotency_key)
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+
+    // Act - Part 2 - Publish it again
+    let response = app
+        .post_newsletters_with_idempotency_key(newsletter_request_body(), &idempotency_key)
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+    assert_eq!(response.headers()["Idempotent-Replayed"], "true");
+
+    // Assert
+    app.dispatch_all_pending_emails().await;
+    // Mock verifies on Drop that we have sent the newsletter email **once**
+}
+
+#[tokio::test]
+async fn concurrent_form_submission_is_handled_gracefully() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(batch_accepted())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act - Submit two newsletter forms concurrently
+    let idempotency_key = Uuid::new_v4().to_string();
+    let response1 =
+        app.post_newsletters_with_idempotency_key(newsletter_request_body(), &idempotency_key);
+    let response2 =
+        app.post_newsletters_with_idempotency_key(newsletter_request_body(), &idempotency_key);
+    let (response1, response2) = tokio::join!(response1, response2);
+
+    // Assert
+    assert_eq!(response1.status(), response2.status());
+    assert_eq!(
+        response1.text().await.unwrap(),
+        response2.text().await.unwrap()
+    );
+    app.dispatch_all_pending_emails().await;
+    // Mock verifies on Drop that we have sent the newsletter email **once**
+}
+
+#[tokio::test]
+async fn invalid_idempotency_keys_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_cases = vec![("".to_string(), "empty"), ("a".repeat(51), "too long")];
+
+    for (idempotency_key, description) in test_cases {
+        // Act
+        let response = app
+            .post_newsletters_with_idempotency_key(newsletter_request_body(), &idempotency_key)
+            .await;
+
+        // Assert
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not fail with 400 Bad Request when the idempotency key was {}.",
+            description
+        );
+    }
+    let n_issues = sqlx::query!(r#"SELECT count(*) AS "count!" FROM newsletter_issues"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(n_issues.count, 0);
+}
+
+#[tokio::test]
+async fn idempotency_keys_are_scoped_to_the_user() {
+    // Arrange
+    let app = spawn_app().await;
+    let other_user = TestUser::generate();
+    other_user.store(&app.db_pool).await;
+    let idempotency_key = Uuid::new_v4().to_string();
+
+    // Act - Two users publish with the same key
+    for user in [&app.test_user, &other_user] {
+        let response = reqwest::Client::new()
+            .post(format!("{}/newsletters", &app.address))
+            .basic_auth(&user.username, Some(&user.password))
+            .header("Idempotency-Key", &idempotency_key)
+            .json(&newsletter_request_body())
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        assert_eq!(response.status().as_u16(), 202);
+        assert!(response.headers().get("Idempotent-Replayed").is_none());
+    }
+
+    // Assert
+    let n_issues = sqlx::query!(r#"SELECT count(*) AS "count!" FROM newsletter_issues"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(n_issues.count, 2);
+}
+
+#[tokio::test]
+async fn idempotency_keys_without_credentials_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .header("Idempotency-Key", Uuid::new_v4().to_string())
+        .json(&newsletter_request_body())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn newsletters_returns_400_for_invalid_data() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_cases = vec![
+        (
+            serde_json::json!({
+                "content": {
+                    "text": "Newsletter body as plain text",
+                    "html": "<p>Newsletter body as HTML</p>",
+                }
+            }),
+            "missing title",
+        ),
+        (
+            serde_json::json!({"title": "Newsletter!"}),
+            "missing content",
+        ),
+    ];
+
+    for (invalid_body, error_message) in test_cases {
+        // Act
+        let response = app.post_newsletters(invalid_body).await;
+
+        // Assert
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not fail with 400 Bad Request when the payload was {}.",
+            error_message
+        );
+    }
+}
+
+fn newsletter_request_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    })
+}
+
+/// Use the public API of the application under test to create
+/// an unconfirmed subscriber.
+async fn create_unconfirmed_subscriber(app: &TestApp) -> ConfirmationLinks {
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(email_accepted())
+        .named("Create unconfirmed subscriber")
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_subscriptions(body.into())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // We now inspect the requests received by the mock Postmark server
+    // to retrieve the confirmation link and return it
+    let email_request = &app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    app.get_confirmation_links(email_request)
+}
+
+async fn create_confirmed_subscriber(app: &TestApp) {
+    // We can then reuse the same helper and just add
+    // an extra step to actually call the confirmation link!
+    let confirmation_link = create_unconfirmed_subscriber(app).await;
+    reqwest::get(confirmation_link.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+}
+
+struct DeliveryTask {
+    status: String,
+    n_retries: i16,
+    last_error: Option<String>,
+}
+
+async fn get_delivery_task(app: &TestApp) -> DeliveryTask {
+    sqlx::query_as!(
+        DeliveryTask,
+        "SELECT status, n_retries, last_error FROM issue_delivery_queue",
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("Failed to fetch the delivery task.")
+}
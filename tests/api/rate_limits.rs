@@ -0,0 +1,284 @@
+//! tests/api/rate_limits.rs
+use crate::helpers::{email_accepted, spawn_app_with, TestApp};
+use newsletter::configuration::{RateLimitStoreKind, Settings, TokenBucketSettings};
+use wiremock::matchers::{method, path};
+use wiremock::Mock;
+
+// A bucket that does not refill while a test runs.
+fn bucket(capacity: u32) -> TokenBucketSettings {
+    TokenBucketSettings {
+        capacity,
+        refill_interval_milliseconds: 3_600_000,
+    }
+}
+
+async fn spawn_rate_limited_app(configure: impl FnOnce(&mut Settings)) -> TestApp {
+    let app = spawn_app_with(configure).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(email_accepted())
+        .mount(&app.email_server)
+        .await;
+    app
+}
+
+async fn post_subscriptions_from(app: &TestApp, ip: &str, email: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .header("X-Forwarded-For", ip)
+        .form(&[("name", "le guin"), ("email", email)])
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_429_once_an_ip_has_used_up_its_requests() {
+    // Arrange
+    let app = spawn_rate_limited_app(|c| {
+        c.application.rate_limits.subscribe.per_ip = bucket(2);
+    })
+    .await;
+
+    // Act
+    let mut statuses = Vec::new();
+    for i in 0..3 {
+        let body = format!("name=le%20guin&email=ursula{}%40gmail.com", i);
+        statuses.push(app.post_subscriptions(body).await);
+    }
+
+    // Assert
+    assert_eq!(statuses[0].status().as_u16(), 200);
+    assert_eq!(statuses[1].status().as_u16(), 200);
+    let limited = &statuses[2];
+    assert_eq!(limited.status().as_u16(), 429);
+    let retry_after: u64 = limited.headers()["Retry-After"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(retry_after > 0 && retry_after <= 3600);
+}
+
+#[tokio::test]
+async fn rate_limited_subscriptions_are_not_stored() {
+    // Arrange
+    let app = spawn_rate_limited_app(|c| {
+        c.application.rate_limits.subscribe.per_ip = bucket(1);
+    })
+    .await;
+    app.post_subscriptions("name=le%20guin&email=ursula0%40gmail.com".into())
+        .await;
+
+    // Act
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula1%40gmail.com".into())
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 429);
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].email, "ursula0@gmail.com");
+}
+
+#[tokio::test]
+async fn the_email_limit_holds_across_ips() {
+    // Arrange
+    let app = spawn_rate_limited_app(|c| {
+        c.application.rate_limits.trust_forwarded_for = true;
+        c.application.rate_limits.subscribe.per_email = Some(bucket(2));
+    })
+    .await;
+
+    // Act
+    let first = post_subscriptions_from(&app, "10.0.0.1", "ursula@gmail.com").await;
+    let second = post_subscriptions_from(&app, "10.0.0.2", "ursula@gmail.com").await;
+    let third = post_subscriptions_from(&app, "10.0.0.3", "URSULA@gmail.com").await;
+    let other_email = post_subscriptions_from(&app, "10.0.0.3", "le_guin@gmail.com").await;
+
+    // Assert
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 200);
+    assert_eq!(third.status().as_u16(), 429);
+    assert_eq!(other_email.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn forwarded_ips_are_only_trusted_when_configured() {
+    // Arrange
+    let app = spawn_rate_limited_app(|c| {
+        c.application.rate_limits.subscribe.per_ip = bucket(1);
+    })
+    .await;
+
+    // Act
+    let first = post_subscriptions_from(&app, "10.0.0.1", "ursula0@gmail.com").await;
+    let second = post_subscriptions_from(&app, "10.0.0.2", "ursula1@gmail.com").await;
+
+    // Assert
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 429);
+}
+
+#[tokio::test]
+async fn trusted_forwarded_ips_are_limited_separately() {
+    // Arrange
+    let app = spawn_rate_limited_app(|c| {
+        c.application.rate_limits.trust_forwarded_for = true;
+        c.application.rate_limits.subscribe.per_ip = bucket(1);
+    })
+    .await;
+
+    // Act
+    let first = post_subscriptions_from(&app, "10.0.0.1", "ursula0@gmail.com").await;
+    let second = post_subscriptions_from(&app, "10.0.0.2", "ursula1@gmail.com").await;
+    let third = post_subscriptions_from(&app, "10.0.0.1", "ursula2@gmail.com").await;
+
+    // Assert
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 200);
+    assert_eq!(third.status().as_u16(), 429);
+}
+
+#[tokio::test]
+async fn confirm_returns_a_429_once_an_ip_has_used_up_its_requests() {
+    // Arrange
+    let app = spawn_rate_limited_app(|c| {
+        c.application.rate_limits.confirm.per_ip = bucket(2);
+    })
+    .await;
+    let url = format!(
+        "{}/subscriptions/confirm?subscription_token=not-a-real-token",
+        app.address
+    );
+
+    // Act
+    let first = reqwest::get(&url).await.unwrap();
+    let second = reqwest::get(&url).await.unwrap();
+    let third = reqwest::get(&url).await.unwrap();
+
+    // Assert
+    assert_eq!(first.status().as_u16(), 401);
+    assert_eq!(second.status().as_u16(), 401);
+    assert_eq!(third.status().as_u16(), 429);
+    assert!(third.headers().contains_key("Retry-After"));
+}
+
+#[tokio::test]
+async fn routes_are_limited_separately() {
+    // Arrange
+    let app = spawn_rate_limited_app(|c| {
+        c.application.rate_limits.subscribe.per_ip = bucket(1);
+        c.application.rate_limits.confirm.per_ip = bucket(1);
+    })
+    .await;
+    app.post_subscriptions("name=le%20guin&email=ursula%40gmail.com".into())
+        .await;
+
+    // Act
+    let response = reqwest::get(format!(
+        "{}/subscriptions/confirm?subscription_token=not-a-real-token",
+        app.address
+    ))
+    .await
+    .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn the_postgres_store_enforces_the_limits() {
+    // Arrange
+    let app = spawn_rate_limited_app(|c| {
+        c.application.rate_limits.store = RateLimitStoreKind::Postgres;
+        c.application.rate_limits.subscribe.per_ip = bucket(1);
+    })
+    .await;
+
+    // Act
+    let first = app
+        .post_subscriptions("name=le%20guin&email=ursula0%40gmail.com".into())
+        .await;
+    let second = app
+        .post_subscriptions("name=le%20guin&email=ursula1%40gmail.com".into())
+        .await;
+
+    // Assert
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 429);
+    let buckets = sqlx::query!("SELECT key, tokens FROM rate_limit_buckets")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(buckets.len(), 1);
+    assert!(buckets[0].key.starts_with("subscribe:ip:"));
+    assert!(buckets[0].tokens < 1.0);
+}
+
+#[tokio::test]
+async fn requests_turned_down_on_their_email_do_not_use_up_the_ip_limit() {
+    for store in [RateLimitStoreKind::Memory, RateLimitStoreKind::Postgres] {
+        // Arrange
+        let app = spawn_rate_limited_app(|c| {
+            c.application.rate_limits.store = store;
+            c.application.rate_limits.trust_forwarded_for = true;
+            c.application.rate_limits.subscribe.per_ip = bucket(2);
+            c.application.rate_limits.subscribe.per_email = Some(bucket(1));
+        })
+        .await;
+
+        // Act
+        let first = post_subscriptions_from(&app, "10.0.0.1", "ursula@gmail.com").await;
+        let same_email = post_subscriptions_from(&app, "10.0.0.1", "ursula@gmail.com").await;
+        let other_email = post_subscriptions_from(&app, "10.0.0.1", "le_guin@gmail.com").await;
+
+        // Assert
+        assert_eq!(first.status().as_u16(), 200);
+        assert_eq!(same_email.status().as_u16(), 429);
+        assert_eq!(other_email.status().as_u16(), 200);
+    }
+}
+
+#[tokio::test]
+async fn resend_confirmation_returns_a_429_once_an_ip_has_used_up_its_requests() {
+    // Arrange
+    let app = spawn_rate_limited_app(|c| {
+        c.application.rate_limits.subscribe.per_ip = bucket(2);
+    })
+    .await;
+
+    // Act
+    let mut statuses = Vec::new();
+    for i in 0..3 {
+        let body = format!("email=ursula{}%40gmail.com", i);
+        statuses.push(app.post_resend_confirmation(body).await.status().as_u16());
+    }
+
+    // Assert
+    assert_eq!(statuses[0], 200);
+    assert_eq!(statuses[1], 200);
+    assert_eq!(statuses[2], 429);
+}
+
+#[tokio::test]
+async fn spoofed_forwarded_ips_do_not_get_a_fresh_bucket() {
+    // Arrange
+    let app = spawn_rate_limited_app(|c| {
+        c.application.rate_limits.trust_forwarded_for = true;
+        c.application.rate_limits.subscribe.per_ip = bucket(1);
+    })
+    .await;
+
+    // Act - The client makes up the first entry, our proxy appends the second
+    let first = post_subscriptions_from(&app, "1.1.1.1, 10.0.0.1", "ursula0@gmail.com").await;
+    let second = post_subscriptions_from(&app, "2.2.2.2, 10.0.0.1", "ursula1@gmail.com").await;
+
+    // Assert
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 429);
+}
@@ -0,0 +1,384 @@
+//! tests/api/subscriber_data.rs
+use crate::helpers::{assert_is_redirect_to, batch_accepted, email_accepted, spawn_app, TestApp};
+use wiremock::matchers::{any, method, path};
+use wiremock::Mock;
+
+const EMAIL: &str = "ursula_le_guin@gmail.com";
+
+/// Subscribe, confirm and receive one issue, returning the link to the
+/// preferences page.
+async fn create_subscriber_with_history(app: &TestApp) -> reqwest::Url {
+    let _confirmation_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(email_accepted())
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    let _newsletter_guard = Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(batch_accepted())
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    reqwest::get(app.get_confirmation_links(email_request).html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    app.post_newsletters(serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+    app.dispatch_all_pending_emails().await;
+    app.get_preferences_link(email_request)
+}
+
+/// The number of rows left about any subscriber, across every table
+/// that refers to them.
+async fn stored_rows(app: &TestApp) -> i64 {
+    sqlx::query!(
+        r#"SELECT
+            (SELECT count(*) FROM subscriptions)
+            + (SELECT count(*) FROM subscription_tokens)
+            + (SELECT count(*) FROM unsubscribe_tokens)
+            + (SELECT count(*) FROM list_memberships)
+            + (SELECT count(*) FROM issue_delivery_queue)
+            + (SELECT count(*) FROM email_outbox)
+            + (SELECT count(*) FROM email_change_requests)
+            + (SELECT count(*) FROM data_request_tokens)
+            AS "count!""#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap()
+    .count
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_export_or_erase_subscriber_data() {
+    // Arrange
+    let app = spawn_app().await;
+    create_subscriber_with_history(&app).await;
+
+    // Act
+    let export = app.get_subscriber_export(EMAIL).await;
+    let erasure = app.post_subscriber_erasure(EMAIL).await;
+
+    // Assert
+    assert_is_redirect_to(&export, "/login");
+    assert_is_redirect_to(&erasure, "/login");
+    assert!(stored_rows(&app).await > 0);
+}
+
+#[tokio::test]
+async fn admins_can_export_everything_we_hold_about_an_email() {
+    // Arrange
+    let app = spawn_app().await;
+    create_subscriber_with_history(&app).await;
+    app.test_user.login(&app).await;
+
+    // Act
+    let response = app.get_subscriber_export(EMAIL).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let data: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(data["subscriber"]["email"], EMAIL);
+    assert_eq!(data["subscriber"]["name"], "le guin");
+    assert_eq!(data["list_memberships"][0]["list"], "newsletter");
+    assert_eq!(data["list_memberships"][0]["status"], "confirmed");
+    assert_eq!(data["subscription_tokens"].as_array().unwrap().len(), 1);
+    assert_eq!(data["unsubscribe_tokens"].as_array().unwrap().len(), 1);
+    assert_eq!(data["deliveries"][0]["title"], "Newsletter title");
+    assert_eq!(data["deliveries"][0]["status"], "delivered");
+}
+
+#[tokio::test]
+async fn admins_can_look_up_subscribers_regardless_of_case() {
+    // Arrange
+    let app = spawn_app().await;
+    create_subscriber_with_history(&app).await;
+    app.test_user.login(&app).await;
+
+    // Act
+    let export = app.get_subscriber_export("Ursula_Le_Guin@Gmail.com").await;
+    let erasure = app
+        .post_subscriber_erasure("URSULA_LE_GUIN@GMAIL.COM")
+        .await;
+
+    // Assert
+    assert_eq!(export.status().as_u16(), 200);
+    let data: serde_json::Value = export.json().await.unwrap();
+    assert_eq!(data["subscriber"]["email"], EMAIL);
+    assert_is_redirect_to(&erasure, "/admin/subscribers");
+    assert_eq!(stored_rows(&app).await, 0);
+}
+
+#[tokio::test]
+async fn exporting_an_unknown_email_returns_a_404() {
+    // Arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    // Act
+    let response = app.get_subscriber_export(EMAIL).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn admins_can_erase_everything_we_hold_about_an_email() {
+    // Arrange
+    let app = spawn_app().await;
+    create_subscriber_with_history(&app).await;
+    app.test_user.login(&app).await;
+
+    // Act - Part 1 - Erase
+    let response = app.post_subscriber_erasure(EMAIL).await;
+    assert_is_redirect_to(&response, "/admin/subscribers");
+
+    // Act - Part 2 - Follow the redirect
+    let html_page = app.get_admin_subscribers_html().await;
+    assert!(html_page.contains(&format!("The data of {} has been erased.", EMAIL)));
+
+    // Assert
+    assert_eq!(stored_rows(&app).await, 0);
+    let tombstone = sqlx::query!("SELECT email_hash FROM erased_subscribers")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(!tombstone.email_hash.contains("ursula"));
+}
+
+#[tokio::test]
+async fn erasing_an_unknown_email_leaves_no_tombstone() {
+    // Arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    // Act
+    let response = app.post_subscriber_erasure(EMAIL).await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/admin/subscribers");
+    let html_page = app.get_admin_subscribers_html().await;
+    assert!(html_page.contains(&format!(
+        "There is no subscriber with the address {}.",
+        EMAIL
+    )));
+    let tombstones = sqlx::query!(r#"SELECT count(*) AS "count!" FROM erased_subscribers"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(tombstones.count, 0);
+}
+
+#[tokio::test]
+async fn erased_addresses_are_not_subscribed_again() {
+    // Arrange
+    let app = spawn_app().await;
+    create_subscriber_with_history(&app).await;
+    app.test_user.login(&app).await;
+    app.post_subscriber_erasure(EMAIL).await;
+
+    Mock::given(any())
+        .respond_with(email_accepted())
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_subscriptions("name=le%20guin&email=Ursula_Le_Guin%40gmail.com".into())
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(stored_rows(&app).await, 0);
+    // Mock verifies on Drop that we haven't sent a confirmation email
+}
+
+/// Ask for a data request from the preferences page, returning the link
+/// emailed to the subscriber.
+async fn request_data(app: &TestApp, preferences_link: &reqwest::Url, kind: &str) -> reqwest::Url {
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(email_accepted())
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    let mut request_link = preferences_link.clone();
+    request_link.set_path(&format!("/subscriptions/preferences/{}", kind));
+    let response = app.api_client.post(request_link).send().await.unwrap();
+    assert_is_redirect_to(
+        &response,
+        &format!(
+            "{}?{}",
+            preferences_link.path(),
+            preferences_link.query().unwrap()
+        ),
+    );
+    app.dispatch_all_pending_emails().await;
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["To"], EMAIL);
+    let mut link = linkify::LinkFinder::new()
+        .links(body["TextBody"].as_str().unwrap())
+        .map(|l| reqwest::Url::parse(l.as_str()).unwrap())
+        .find(|l| l.path() == "/subscriptions/data-request")
+        .unwrap();
+    assert_eq!(link.host_str().unwrap(), "127.0.0.1");
+    link.set_port(Some(app.port)).unwrap();
+    link
+}
+
+#[tokio::test]
+async fn subscribers_can_export_their_own_data_from_the_preferences_page() {
+    // Arrange
+    let app = spawn_app().await;
+    let preferences_link = create_subscriber_with_history(&app).await;
+
+    // Act - Part 1 - Ask for the export
+    let link = request_data(&app, &preferences_link, "export").await;
+
+    // Assert - Part 1 - Following the link does not use it up
+    let form = app.api_client.get(link.clone()).send().await.unwrap();
+    assert_eq!(form.status().as_u16(), 200);
+    assert!(form.text().await.unwrap().contains("Download my data"));
+
+    // Act - Part 2 - Confirm
+    let response = app.api_client.post(link).send().await.unwrap();
+
+    // Assert - Part 2
+    assert_eq!(response.status().as_u16(), 200);
+    let data: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(data["subscriber"]["email"], EMAIL);
+}
+
+#[tokio::test]
+async fn subscribers_can_erase_their_own_data_from_the_preferences_page() {
+    // Arrange
+    let app = spawn_app().await;
+    let preferences_link = create_subscriber_with_history(&app).await;
+    let link = request_data(&app, &preferences_link, "erase").await;
+
+    // Act
+    let response = app.api_client.post(link).send().await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(stored_rows(&app).await, 0);
+    let response = app.api_client.get(preferences_link).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn the_preferences_link_alone_cannot_erase_data() {
+    // Arrange
+    let app = spawn_app().await;
+    let preferences_link = create_subscriber_with_history(&app).await;
+
+    // Act
+    request_data(&app, &preferences_link, "erase").await;
+
+    // Assert
+    assert!(stored_rows(&app).await > 0);
+    let subscribers = sqlx::query!(r#"SELECT count(*) AS "count!" FROM subscriptions"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(subscribers.count, 1);
+}
+
+#[tokio::test]
+async fn data_request_links_can_only_be_used_once() {
+    // Arrange
+    let app = spawn_app().await;
+    let preferences_link = create_subscriber_with_history(&app).await;
+    let link = request_data(&app, &preferences_link, "export").await;
+    app.api_client
+        .post(link.clone())
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    // Act
+    let response = app.api_client.post(link).send().await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn expired_data_request_links_are_rejected_with_a_410() {
+    // Arrange
+    let app = spawn_app().await;
+    let preferences_link = create_subscriber_with_history(&app).await;
+    let link = request_data(&app, &preferences_link, "erase").await;
+    sqlx::query!("UPDATE data_request_tokens SET requested_at = now() - interval '2 hours'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // Act
+    let response = app.api_client.post(link).send().await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 410);
+    let subscribers = sqlx::query!(r#"SELECT count(*) AS "count!" FROM subscriptions"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(subscribers.count, 1);
+}
+
+#[tokio::test]
+async fn erasing_requires_a_signed_link() {
+    // Arrange
+    let app = spawn_app().await;
+    let link = create_subscriber_with_history(&app).await;
+    let subscriber_id = link
+        .query_pairs()
+        .find(|(name, _)| name == "subscriber_id")
+        .unwrap()
+        .1
+        .into_owned();
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!(
+            "{}/subscriptions/preferences/erase?subscriber_id={}&version=0&tag={}",
+            app.address,
+            subscriber_id,
+            "0".repeat(64)
+        ))
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+    assert!(stored_rows(&app).await > 0);
+}
@@ -1,6 +1,8 @@
 //! tests/api/subscriptions.rs
-use crate::helpers::spawn_app;
+use crate::helpers::{email_accepted, email_accepted_with_message_id, spawn_app};
+use newsletter::email_outbox::MAX_ATTEMPTS;
 // New imports!
+use uuid::Uuid;
 use wiremock::matchers::{method, path};
 use wiremock::{Mock, ResponseTemplate};
 
@@ -12,7 +14,7 @@ async fn subscribe_returns_a_200_for_valid_form_data() {
 
     Mock::given(path("/email"))
         .and(method("POST"))
-        .respond_with(ResponseTemplate::new(200))
+        .respond_with(email_accepted())
         .mount(&app.email_server)
         .await;
 
@@ -23,6 +25,32 @@ async fn subscribe_returns_a_200_for_valid_form_data() {
     assert_eq!(200, response.status().as_u16());
 }
 
+#[tokio::test]
+async fn subscribe_ignores_idempotency_keys() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(email_accepted())
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("Idempotency-Key", Uuid::new_v4().to_string())
+        .body(body)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+}
+
 #[tokio::test]
 async fn subscribe_persists_the_new_subscriber() {
     // Arrange
@@ -31,15 +59,16 @@ async fn subscribe_persists_the_new_subscriber() {
 
     Mock::given(path("/email"))
         .and(method("POST"))
-        .respond_with(ResponseTemplate::new(200))
+        .respond_with(email_accepted())
         .mount(&app.email_server)
         .await;
 
     // Act
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
 
     // Assert
-    let saved = sqlx::query!("SELECT email, name, status FROM subscriptions",)
+    let saved = sqlx::query!(r#"SELECT email AS "email!", name AS "name!", status AS "status!" FROM subscriptions JOIN list_memberships ON subscriber_id = id"#,)
         .fetch_one(&app.db_pool)
         .await
         .expect("Failed to fetch saved subscription.");
@@ -104,18 +133,74 @@ async fn subscribe_sends_a_confirmation_email_for_valid_data() {
 
     Mock::given(path("/email"))
         .and(method("POST"))
-        .respond_with(ResponseTemplate::new(200))
+        .respond_with(email_accepted())
         .expect(1)
         .mount(&app.email_server)
         .await;
 
     // Act
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
 
     // Assert
     // Mock asserts on drop
 }
 
+#[tokio::test]
+async fn subscribe_stores_the_message_id_of_the_confirmation_email() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    let message_id = Uuid::new_v4().to_string();
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(email_accepted_with_message_id(&message_id))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let saved = sqlx::query!(
+        "SELECT confirmation_email_message_id, confirmation_email_submitted_at \
+        FROM subscriptions"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.confirmation_email_message_id, Some(message_id));
+    assert!(saved.confirmation_email_submitted_at.is_some());
+}
+
+#[tokio::test]
+async fn no_message_id_is_stored_if_the_confirmation_email_fails() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let saved = sqlx::query!("SELECT confirmation_email_message_id FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.confirmation_email_message_id, None);
+}
+
 #[tokio::test]
 async fn subscribe_sends_a_confirmation_email_with_a_link() {
     // Arrange
@@ -124,7 +209,7 @@ async fn subscribe_sends_a_confirmation_email_with_a_link() {
 
     Mock::given(path("/email"))
         .and(method("POST"))
-        .respond_with(ResponseTemplate::new(200))
+        .respond_with(email_accepted())
         // We are not setting an expectation here anymore
         // The test is focused on another aspect of the app
         // behaviour.
@@ -133,12 +218,478 @@ async fn subscribe_sends_a_confirmation_email_with_a_link() {
 
     // Act
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
 
     // Assert
     // Get the first intercepted request
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_links = app.get_confirmation_links(&email_request);
+    let confirmation_links = app.get_confirmation_links(email_request);
 
     // The two links should be identical
     assert_eq!(confirmation_links.html, confirmation_links.plain_text);
 }
+
+#[tokio::test]
+async fn subscribing_twice_while_pending_resends_the_confirmation_email() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(email_accepted())
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let first_response = app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+    let second_response = app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(first_response.status().as_u16(), 200);
+    assert_eq!(second_response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM list_memberships",)
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions.");
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].status, "pending_confirmation");
+
+    // Only the most recent confirmation link is valid
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let old_link = app.get_confirmation_links(&email_requests[0]).html;
+    let new_link = app.get_confirmation_links(&email_requests[1]).html;
+    assert_eq!(reqwest::get(old_link).await.unwrap().status().as_u16(), 401);
+    assert_eq!(reqwest::get(new_link).await.unwrap().status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn concurrent_submissions_of_the_same_address_are_handled_gracefully() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(email_accepted())
+        .mount(&app.email_server)
+        .await;
+
+    // Act - Double (and triple, and quadruple) submit the form
+    let (response1, response2, response3, response4) = tokio::join!(
+        app.post_subscriptions(body.into()),
+        app.post_subscriptions(body.into()),
+        app.post_subscriptions(body.into()),
+        app.post_subscriptions(body.into()),
+    );
+
+    // Assert
+    for response in [response1, response2, response3, response4] {
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    let saved = sqlx::query!("SELECT status FROM list_memberships",)
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions.");
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].status, "pending_confirmation");
+}
+
+#[tokio::test]
+async fn subscribing_again_once_confirmed_is_a_no_op() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(email_accepted())
+        // Only the first subscription triggers an email
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM list_memberships",)
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions.");
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].status, "confirmed");
+}
+
+#[tokio::test]
+async fn subscribing_again_after_unsubscribing_requires_a_new_confirmation() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(email_accepted())
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    reqwest::get(app.get_confirmation_links(email_request).html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    app.post_unsubscribe(app.get_unsubscribe_links(email_request).html)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM list_memberships",)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "pending_confirmation");
+
+    let email_request = &app.email_server.received_requests().await.unwrap()[1];
+    reqwest::get(app.get_confirmation_links(email_request).html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    let saved = sqlx::query!("SELECT status FROM list_memberships",)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn subscribe_fails_if_there_is_a_fatal_database_error() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    // Sabotage the database
+    sqlx::query!("ALTER TABLE subscriptions DROP COLUMN email;",)
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 500);
+}
+
+#[tokio::test]
+async fn no_subscriber_is_persisted_if_storing_the_token_fails() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    // Sabotage the second write: inserting the subscriber succeeds,
+    // storing its confirmation token does not.
+    sqlx::query!("ALTER TABLE subscription_tokens DROP COLUMN subscription_token;",)
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 500);
+    let saved = sqlx::query!("SELECT email FROM subscriptions",)
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions.");
+    assert!(saved.is_empty());
+}
+
+#[tokio::test]
+async fn a_failed_attempt_does_not_prevent_subscribing_again() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(email_accepted())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    // Make the last write of the transaction fail.
+    sqlx::query!("ALTER TABLE unsubscribe_tokens RENAME COLUMN unsubscribe_token TO broken;",)
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    let response = app.post_subscriptions(body.into()).await;
+    assert_eq!(response.status().as_u16(), 500);
+    sqlx::query!("ALTER TABLE unsubscribe_tokens RENAME COLUMN broken TO unsubscribe_token;",)
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM list_memberships",)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "pending_confirmation");
+}
+
+#[tokio::test]
+async fn subscribe_succeeds_even_if_the_email_provider_is_down() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    // The confirmation email is still in the outbox, waiting to be retried
+    let queued = sqlx::query!("SELECT status, n_retries, execute_after FROM email_outbox",)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the queued confirmation email.");
+    assert_eq!(queued.status, "pending");
+    assert_eq!(queued.n_retries, 1);
+    assert!(queued.execute_after > chrono::Utc::now());
+}
+
+#[tokio::test]
+async fn confirmation_emails_rejected_by_the_provider_are_not_retried() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        // e.g. Postmark rejecting the recipient
+        .respond_with(ResponseTemplate::new(422))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_subscriptions(body.into())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let queued = sqlx::query!("SELECT status, n_retries FROM email_outbox",)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the queued confirmation email.");
+    assert_eq!(queued.status, "dead_letter");
+    assert_eq!(queued.n_retries, 1);
+}
+
+#[tokio::test]
+async fn confirmation_emails_with_an_invalid_recipient_are_moved_to_the_dead_letter_state() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(email_accepted())
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into())
+        .await
+        .error_for_status()
+        .unwrap();
+    sqlx::query!("UPDATE email_outbox SET recipient_email = 'definitely-not-an-email'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let queued = sqlx::query!("SELECT status, last_error FROM email_outbox",)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the queued confirmation email.");
+    assert_eq!(queued.status, "dead_letter");
+    assert!(queued.last_error.is_some());
+}
+
+#[tokio::test]
+async fn confirmation_emails_are_moved_to_the_dead_letter_state_after_too_many_attempts() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(503))
+        .expect(MAX_ATTEMPTS as u64)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_subscriptions(body.into())
+        .await
+        .error_for_status()
+        .unwrap();
+    for _ in 0..MAX_ATTEMPTS {
+        app.dispatch_all_pending_emails().await;
+        // Skip the back-off period.
+        sqlx::query!("UPDATE email_outbox SET execute_after = now()")
+            .execute(&app.db_pool)
+            .await
+            .unwrap();
+    }
+
+    // Assert
+    let queued = sqlx::query!("SELECT status, n_retries, last_error FROM email_outbox",)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the queued confirmation email.");
+    assert_eq!(queued.status, "dead_letter");
+    assert_eq!(queued.n_retries, MAX_ATTEMPTS);
+    assert!(queued.last_error.is_some());
+}
+
+#[tokio::test]
+async fn subscribe_stores_the_locale_picked_in_the_form() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com&locale=fr";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(email_accepted())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let saved = sqlx::query!("SELECT locale FROM subscriptions",)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.locale, "fr");
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["Subject"], "Bienvenue !");
+    assert!(body["TextBody"]
+        .as_str()
+        .unwrap()
+        .contains("Se désinscrire"));
+}
+
+#[tokio::test]
+async fn subscribe_falls_back_to_the_first_supported_accept_language() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(email_accepted())
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        // We have no German catalog: French it is.
+        .header("Accept-Language", "de-DE, fr-CA;q=0.8, en;q=0.5")
+        .body(body)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .error_for_status()
+        .unwrap();
+
+    // Assert
+    let saved = sqlx::query!("SELECT locale FROM subscriptions",)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.locale, "fr-CA");
+}
+
+#[tokio::test]
+async fn subscribe_uses_the_default_locale_when_none_is_given() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(email_accepted())
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_subscriptions(body.into()).await;
+
+    // Assert
+    let saved = sqlx::query!("SELECT locale FROM subscriptions",)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.locale, "en");
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_400_for_an_invalid_locale() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com&locale=not-a-locale";
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+
+    // Assert
+    assert_eq!(400, response.status().as_u16());
+}
@@ -0,0 +1,576 @@
+//! tests/api/subscriptions_preferences.rs
+use crate::helpers::{assert_is_redirect_to, email_accepted, spawn_app, TestApp};
+use wiremock::matchers::{any, method, path};
+use wiremock::Mock;
+
+const EMAIL: &str = "ursula_le_guin@gmail.com";
+
+/// Subscribe and confirm, returning the link to the preferences page.
+async fn create_confirmed_subscriber(app: &TestApp) -> reqwest::Url {
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(email_accepted())
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    reqwest::get(app.get_confirmation_links(email_request).html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    app.get_preferences_link(email_request)
+}
+
+/// The fields of the preferences form, as the page pre-fills them for
+/// our confirmed subscriber.
+fn current_preferences() -> Vec<(&'static str, &'static str)> {
+    vec![
+        ("name", "le guin"),
+        ("email", EMAIL),
+        ("send_frequency", "immediately"),
+        ("list", "newsletter"),
+    ]
+}
+
+fn with(
+    mut form: Vec<(&'static str, &'static str)>,
+    field: &'static str,
+    value: &'static str,
+) -> Vec<(&'static str, &'static str)> {
+    form.retain(|(name, _)| *name != field);
+    form.push((field, value));
+    form
+}
+
+/// Extract the link confirming a new address from the email sent to it.
+fn email_change_link(app: &TestApp, email_request: &wiremock::Request) -> reqwest::Url {
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let mut link = linkify::LinkFinder::new()
+        .links(body["TextBody"].as_str().unwrap())
+        .map(|l| reqwest::Url::parse(l.as_str()).unwrap())
+        .find(|l| l.path() == "/subscriptions/confirm-email-change")
+        .unwrap();
+    assert_eq!(link.host_str().unwrap(), "127.0.0.1");
+    link.set_port(Some(app.port)).unwrap();
+    link
+}
+
+fn relative(link: &reqwest::Url) -> String {
+    format!("{}?{}", link.path(), link.query().unwrap())
+}
+
+async fn membership_statuses(app: &TestApp) -> Vec<(String, String)> {
+    sqlx::query!(
+        r#"SELECT l.slug, m.status
+        FROM list_memberships m
+        JOIN lists l ON l.list_id = m.list_id
+        ORDER BY l.slug"#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|row| (row.slug, row.status))
+    .collect()
+}
+
+#[tokio::test]
+async fn the_preferences_page_shows_the_current_settings() {
+    // Arrange
+    let app = spawn_app().await;
+    let link = create_confirmed_subscriber(&app).await;
+
+    // Act
+    let html_page = app.get_preferences_html(&link).await;
+
+    // Assert
+    assert!(html_page.contains(r#"value="le guin""#));
+    assert!(html_page.contains(&format!(r#"value="{}""#, EMAIL)));
+    assert!(html_page.contains(r#"value="newsletter" checked"#));
+    assert!(html_page.contains(r#"value="immediately" selected"#));
+}
+
+#[tokio::test]
+async fn links_with_an_invalid_tag_are_rejected_with_a_401() {
+    // Arrange
+    let app = spawn_app().await;
+    let mut link = create_confirmed_subscriber(&app).await;
+    let subscriber_id = link
+        .query_pairs()
+        .find(|(name, _)| name == "subscriber_id")
+        .unwrap()
+        .1
+        .into_owned();
+    link.set_query(Some(&format!(
+        "subscriber_id={}&version=0&tag={}",
+        subscriber_id,
+        "0".repeat(64)
+    )));
+
+    // Act
+    let get_response = app.api_client.get(link.clone()).send().await.unwrap();
+    let post_response = app
+        .post_preferences(&link, &with(current_preferences(), "name", "mallory"))
+        .await;
+
+    // Assert
+    assert_eq!(get_response.status().as_u16(), 401);
+    assert_eq!(post_response.status().as_u16(), 401);
+    let saved = sqlx::query!("SELECT name FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.name, "le guin");
+}
+
+#[tokio::test]
+async fn subscribers_can_change_their_name() {
+    // Arrange
+    let app = spawn_app().await;
+    let link = create_confirmed_subscriber(&app).await;
+
+    // Act - Part 1 - Save the form
+    let response = app
+        .post_preferences(&link, &with(current_preferences(), "name", "ursula"))
+        .await;
+    assert_is_redirect_to(&response, &relative(&link));
+
+    // Act - Part 2 - Follow the redirect
+    let html_page = app.get_preferences_html(&link).await;
+    assert!(html_page.contains("<p><i>Your preferences have been saved.</i></p>"));
+
+    // Assert
+    let saved = sqlx::query!("SELECT name FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.name, "ursula");
+}
+
+#[tokio::test]
+async fn invalid_preferences_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    let link = create_confirmed_subscriber(&app).await;
+    let test_cases = vec![
+        (with(current_preferences(), "name", " "), "an empty name"),
+        (
+            with(current_preferences(), "name", "{ursula}"),
+            "a name with forbidden characters",
+        ),
+        (
+            with(current_preferences(), "email", "not-an-email"),
+            "an invalid email",
+        ),
+        (
+            with(current_preferences(), "send_frequency", "hourly"),
+            "an unknown send frequency",
+        ),
+    ];
+
+    for (form, description) in test_cases {
+        // Act
+        let response = app.post_preferences(&link, &form).await;
+
+        // Assert
+        assert_is_redirect_to(&response, &relative(&link));
+        let html_page = app.get_preferences_html(&link).await;
+        assert!(
+            !html_page.contains("Your preferences have been saved."),
+            "The preferences were saved with {}.",
+            description
+        );
+    }
+    let saved = sqlx::query!("SELECT name, email, send_frequency FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.name, "le guin");
+    assert_eq!(saved.email, EMAIL);
+    assert_eq!(saved.send_frequency, "immediately");
+}
+
+#[tokio::test]
+async fn newsletters_are_held_back_until_the_next_slot_of_the_send_frequency() {
+    // Arrange
+    let app = spawn_app().await;
+    let link = create_confirmed_subscriber(&app).await;
+    app.post_preferences(
+        &link,
+        &with(current_preferences(), "send_frequency", "weekly"),
+    )
+    .await;
+
+    Mock::given(any())
+        .respond_with(email_accepted())
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 202);
+    let held_back = sqlx::query!(
+        r#"SELECT count(*) AS "count!" FROM issue_delivery_queue
+        WHERE status = 'pending' AND execute_after > now()"#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(held_back.count, 1);
+    // Mock verifies on Drop that we haven't sent the newsletter email
+}
+
+#[tokio::test]
+async fn joining_a_list_requires_a_confirmation() {
+    // Arrange
+    let app = spawn_app().await;
+    let link = create_confirmed_subscriber(&app).await;
+    sqlx::query!(
+        r#"INSERT INTO lists (list_id, slug, name)
+        VALUES (gen_random_uuid(), 'rust-weekly', 'Rust Weekly')"#
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(email_accepted())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act - Part 1 - Tick the new list
+    let mut form = current_preferences();
+    form.push(("list", "rust-weekly"));
+    app.post_preferences(&link, &form).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert - Part 1
+    assert_eq!(
+        membership_statuses(&app).await,
+        vec![
+            ("newsletter".into(), "confirmed".into()),
+            ("rust-weekly".into(), "pending_confirmation".into()),
+        ]
+    );
+
+    // Act - Part 2 - Follow the link in the confirmation email
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    reqwest::get(app.get_confirmation_links(&email_request).html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    // Assert - Part 2
+    assert_eq!(
+        membership_statuses(&app).await,
+        vec![
+            ("newsletter".into(), "confirmed".into()),
+            ("rust-weekly".into(), "confirmed".into()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn unticking_a_list_unsubscribes_from_it() {
+    // Arrange
+    let app = spawn_app().await;
+    let link = create_confirmed_subscriber(&app).await;
+    let mut form = current_preferences();
+    form.retain(|(name, _)| *name != "list");
+
+    // Act
+    app.post_preferences(&link, &form).await;
+
+    // Assert
+    assert_eq!(
+        membership_statuses(&app).await,
+        vec![("newsletter".into(), "unsubscribed".into())]
+    );
+}
+
+#[tokio::test]
+async fn subscribers_can_unsubscribe_from_all_lists() {
+    // Arrange
+    let app = spawn_app().await;
+    let link = create_confirmed_subscriber(&app).await;
+    let mut unsubscribe_link = link.clone();
+    unsubscribe_link.set_path("/subscriptions/preferences/unsubscribe");
+
+    // Act
+    let response = app.api_client.post(unsubscribe_link).send().await.unwrap();
+
+    // Assert
+    assert_is_redirect_to(&response, &relative(&link));
+    assert_eq!(
+        membership_statuses(&app).await,
+        vec![("newsletter".into(), "unsubscribed".into())]
+    );
+    let html_page = app.get_preferences_html(&link).await;
+    assert!(html_page.contains("You have been unsubscribed from all our lists."));
+}
+
+#[tokio::test]
+async fn new_email_addresses_only_take_effect_once_confirmed() {
+    // Arrange
+    let app = spawn_app().await;
+    let link = create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(email_accepted())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act - Part 1 - Ask for the change
+    app.post_preferences(
+        &link,
+        &with(current_preferences(), "email", "ursula@example.com"),
+    )
+    .await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert - Part 1
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.email, EMAIL);
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["To"], "ursula@example.com");
+
+    // Act - Part 2 - Follow the link sent to the new address
+    let response = reqwest::get(email_change_link(&app, &email_request))
+        .await
+        .unwrap();
+
+    // Assert - Part 2
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.email, "ursula@example.com");
+}
+
+#[tokio::test]
+async fn preferences_links_stop_working_once_the_address_changes() {
+    // Arrange
+    let app = spawn_app().await;
+    let link = create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(email_accepted())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.post_preferences(
+        &link,
+        &with(current_preferences(), "email", "ursula@example.com"),
+    )
+    .await;
+    app.dispatch_all_pending_emails().await;
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    reqwest::get(email_change_link(&app, &email_request))
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    // Act
+    let response = app.api_client.get(link).send().await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn email_change_emails_do_not_replace_the_confirmation_message_id() {
+    // Arrange
+    let app = spawn_app().await;
+    let link = create_confirmed_subscriber(&app).await;
+    let before = sqlx::query!("SELECT confirmation_email_message_id FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(email_accepted())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_preferences(
+        &link,
+        &with(current_preferences(), "email", "ursula@example.com"),
+    )
+    .await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let after = sqlx::query!("SELECT confirmation_email_message_id FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(before.confirmation_email_message_id.is_some());
+    assert_eq!(
+        after.confirmation_email_message_id,
+        before.confirmation_email_message_id
+    );
+}
+
+#[tokio::test]
+async fn confirming_a_change_to_a_taken_address_returns_a_409() {
+    // Arrange
+    let app = spawn_app().await;
+    let link = create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(email_accepted())
+        .mount(&app.email_server)
+        .await;
+    app.post_preferences(
+        &link,
+        &with(current_preferences(), "email", "ursula@example.com"),
+    )
+    .await;
+    app.dispatch_all_pending_emails().await;
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let confirmation_link = email_change_link(&app, &email_request);
+    // Someone else subscribes with the new address in the meantime.
+    app.post_subscriptions("name=someone&email=ursula%40example.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+
+    // Act
+    let response = reqwest::get(confirmation_link).await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 409);
+    let saved = sqlx::query!("SELECT email FROM subscriptions WHERE name = 'le guin'")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.email, EMAIL);
+}
+
+#[tokio::test]
+async fn changes_to_an_erased_address_are_not_applied() {
+    // Arrange
+    let app = spawn_app().await;
+    let link = create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(email_accepted())
+        .mount(&app.email_server)
+        .await;
+    app.post_preferences(
+        &link,
+        &with(current_preferences(), "email", "ursula@example.com"),
+    )
+    .await;
+    app.dispatch_all_pending_emails().await;
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let confirmation_link = email_change_link(&app, &email_request);
+    // The new address subscribed and had its data erased in the meantime.
+    app.post_subscriptions("name=someone&email=ursula%40example.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.test_user.login(&app).await;
+    app.post_subscriber_erasure("ursula@example.com")
+        .await
+        .error_for_status()
+        .unwrap();
+
+    // Act
+    let response = reqwest::get(confirmation_link).await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.email, EMAIL);
+    let requests = sqlx::query!(r#"SELECT count(*) AS "count!" FROM email_change_requests"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(requests.count, 0);
+}
+
+#[tokio::test]
+async fn unknown_email_change_tokens_are_rejected_with_a_401() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::get(format!(
+        "{}/subscriptions/confirm-email-change?token=not-a-token",
+        app.address
+    ))
+    .await
+    .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+}
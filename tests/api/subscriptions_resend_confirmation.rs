@@ -0,0 +1,111 @@
+//! tests/api/subscriptions_resend_confirmation.rs
+use crate::helpers::{email_accepted, spawn_app};
+use wiremock::matchers::{any, method, path};
+use wiremock::Mock;
+
+#[tokio::test]
+async fn resend_confirmation_sends_a_new_link_and_invalidates_the_old_one() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(email_accepted())
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    // Act
+    let response = app
+        .post_resend_confirmation("email=ursula_le_guin%40gmail.com".into())
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let old_link = app.get_confirmation_links(&email_requests[0]).html;
+    let new_link = app.get_confirmation_links(&email_requests[1]).html;
+    assert_ne!(old_link, new_link);
+
+    let response = reqwest::get(old_link).await.unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+    let response = reqwest::get(new_link).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn resend_confirmation_renews_an_expired_link() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(email_accepted())
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    app.dispatch_all_pending_emails().await;
+    sqlx::query!("UPDATE subscription_tokens SET issued_at = now() - interval '30 days'",)
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // Act
+    app.post_resend_confirmation("email=ursula_le_guin%40gmail.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let new_link = app.get_confirmation_links(&email_requests[1]).html;
+    let response = reqwest::get(new_link).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn resend_confirmation_does_not_send_emails_to_unknown_addresses() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(any())
+        .respond_with(email_accepted())
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_resend_confirmation("email=ursula_le_guin%40gmail.com".into())
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    // We do not disclose whether the address is on the list or not
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn resend_confirmation_returns_a_400_for_invalid_emails() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_cases = vec![
+        ("", "missing email"),
+        ("email=", "empty email"),
+        ("email=definitely-not-an-email", "invalid email"),
+    ];
+    for (body, description) in test_cases {
+        // Act
+        let response = app.post_resend_confirmation(body.into()).await;
+        // Assert
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not return a 400 Bad Request when the payload was {}.",
+            description
+        );
+    }
+}
@@ -0,0 +1,371 @@
+//! tests/api/webhooks_postmark.rs
+use crate::helpers::{email_accepted, spawn_app, TestApp};
+use secrecy::ExposeSecret;
+use wiremock::matchers::{any, method, path};
+use wiremock::Mock;
+
+const EMAIL: &str = "ursula_le_guin@gmail.com";
+
+fn bounce(bounce_type: &str) -> serde_json::Value {
+    serde_json::json!({
+        "RecordType": "Bounce",
+        "ID": 4323372036854775807i64,
+        "Type": bounce_type,
+        "TypeCode": 1,
+        "Name": "Hard bounce",
+        "MessageID": "883953f4-6105-42a2-a16a-77a8eac79483",
+        "MessageStream": "outbound",
+        "Description": "The server was unable to deliver your message (ex: unknown user, mailbox not found).",
+        "Details": "Test bounce details",
+        "Email": EMAIL,
+        "From": "sender@example.com",
+        "BouncedAt": "2022-06-04T10:15:02Z",
+        "Inactive": true,
+        "CanActivate": true
+    })
+}
+
+fn spam_complaint() -> serde_json::Value {
+    serde_json::json!({
+        "RecordType": "SpamComplaint",
+        "ID": 42,
+        "Type": "SpamComplaint",
+        "TypeCode": 512,
+        "Name": "Spam complaint",
+        "MessageID": "00000000-0000-0000-0000-000000000000",
+        "MessageStream": "outbound",
+        "Email": EMAIL,
+        "From": "sender@example.com",
+        "BouncedAt": "2022-06-04T10:15:02Z",
+        "Inactive": true,
+        "CanActivate": false
+    })
+}
+
+fn delivery() -> serde_json::Value {
+    serde_json::json!({
+        "RecordType": "Delivery",
+        "ServerID": 23,
+        "MessageStream": "outbound",
+        "MessageID": "883953f4-6105-42a2-a16a-77a8eac79483",
+        "Recipient": EMAIL,
+        "Tag": "",
+        "DeliveredAt": "2022-06-04T10:15:02Z",
+        "Details": "Test delivery webhook details",
+        "Metadata": {}
+    })
+}
+
+async fn create_confirmed_subscriber(app: &TestApp) {
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(email_accepted())
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_subscriptions(body.into())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    reqwest::get(app.get_confirmation_links(email_request).html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+}
+
+async fn subscriber_status(app: &TestApp) -> String {
+    sqlx::query!("SELECT status FROM list_memberships")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.")
+        .status
+}
+
+#[tokio::test]
+async fn hard_bounces_mark_the_subscriber_as_bounced() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    // Act
+    let response = app.post_postmark_webhook(bounce("HardBounce")).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "bounced");
+}
+
+#[tokio::test]
+async fn spam_complaints_mark_the_subscriber_as_complained() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    // Act
+    let response = app.post_postmark_webhook(spam_complaint()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "complained");
+}
+
+#[tokio::test]
+async fn subscribers_are_suppressed_once_soft_bounces_reach_the_threshold() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let threshold = app.postmark_webhook.soft_bounce_threshold;
+
+    // Act - Part 1 - Just below the threshold
+    for _ in 1..threshold {
+        let response = app.post_postmark_webhook(bounce("SoftBounce")).await;
+        assert_eq!(response.status().as_u16(), 200);
+    }
+
+    // Assert - Part 1
+    assert_eq!(subscriber_status(&app).await, "confirmed");
+
+    // Act - Part 2 - Reaching it
+    app.post_postmark_webhook(bounce("SoftBounce")).await;
+
+    // Assert - Part 2
+    let saved = sqlx::query!(r#"SELECT status AS "status!", soft_bounce_count AS "soft_bounce_count!" FROM subscriptions JOIN list_memberships ON subscriber_id = id"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "bounced");
+    assert_eq!(saved.soft_bounce_count, threshold);
+}
+
+#[tokio::test]
+async fn a_delivery_resets_the_soft_bounce_count() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let threshold = app.postmark_webhook.soft_bounce_threshold;
+    for _ in 1..threshold {
+        app.post_postmark_webhook(bounce("SoftBounce")).await;
+    }
+
+    // Act
+    let response = app.post_postmark_webhook(delivery()).await;
+    app.post_postmark_webhook(bounce("SoftBounce")).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!(r#"SELECT status AS "status!", soft_bounce_count AS "soft_bounce_count!" FROM subscriptions JOIN list_memberships ON subscriber_id = id"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+    assert_eq!(saved.soft_bounce_count, 1);
+}
+
+#[tokio::test]
+async fn bounces_are_matched_to_subscribers_regardless_of_case() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let mut hard_bounce = bounce("HardBounce");
+    hard_bounce["Email"] = EMAIL.to_uppercase().into();
+
+    // Act
+    let response = app.post_postmark_webhook(hard_bounce).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "bounced");
+}
+
+#[tokio::test]
+async fn suppressed_subscribers_do_not_receive_newsletters() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.post_postmark_webhook(bounce("HardBounce")).await;
+
+    Mock::given(any())
+        .respond_with(email_accepted())
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_newsletters(serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    }))
+    .await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    // Mock verifies on Drop that we haven't sent the newsletter email
+}
+
+#[tokio::test]
+async fn subscribing_again_after_a_hard_bounce_sends_no_email() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.post_postmark_webhook(bounce("HardBounce")).await;
+
+    Mock::given(any())
+        .respond_with(email_accepted())
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "bounced");
+}
+
+#[tokio::test]
+async fn events_we_do_not_act_upon_are_acknowledged() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let test_cases = vec![
+        (bounce("AutoResponder"), "an auto-responder bounce"),
+        (
+            serde_json::json!({ "RecordType": "Open", "Recipient": EMAIL }),
+            "an open",
+        ),
+        (
+            serde_json::json!({
+                "RecordType": "Bounce",
+                "Type": "HardBounce",
+                "Email": "someone-else@example.com"
+            }),
+            "a bounce for an address we do not know",
+        ),
+    ];
+
+    for (body, description) in test_cases {
+        // Act
+        let response = app.post_postmark_webhook(body).await;
+
+        // Assert
+        assert_eq!(
+            response.status().as_u16(),
+            200,
+            "The webhook did not return a 200 OK for {}.",
+            description
+        );
+    }
+    assert_eq!(subscriber_status(&app).await, "confirmed");
+}
+
+#[tokio::test]
+async fn requests_with_the_shared_secret_header_are_accepted() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/webhooks/postmark", &app.address))
+        .header(
+            "X-Webhook-Secret",
+            app.postmark_webhook.secret.expose_secret(),
+        )
+        .json(&spam_complaint())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "complained");
+}
+
+#[tokio::test]
+async fn requests_without_valid_credentials_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let url = format!("{}/webhooks/postmark", &app.address);
+    let client = reqwest::Client::new();
+    let test_cases = vec![
+        (client.post(&url), "no credentials"),
+        (
+            client
+                .post(&url)
+                .basic_auth(&app.postmark_webhook.username, Some("wrong-secret")),
+            "a wrong secret",
+        ),
+        (
+            client.post(&url).basic_auth(
+                "someone-else",
+                Some(app.postmark_webhook.secret.expose_secret()),
+            ),
+            "a wrong username",
+        ),
+        (
+            client.post(&url).header("X-Webhook-Secret", "wrong-secret"),
+            "a wrong secret header",
+        ),
+    ];
+
+    for (request, description) in test_cases {
+        // Act
+        let response = request
+            .json(&spam_complaint())
+            .send()
+            .await
+            .expect("Failed to execute request.");
+
+        // Assert
+        assert_eq!(
+            response.status().as_u16(),
+            401,
+            "The webhook did not reject a request with {}.",
+            description
+        );
+    }
+    assert_eq!(subscriber_status(&app).await, "confirmed");
+}
+
+#[tokio::test]
+async fn unsubscribing_after_a_hard_bounce_keeps_the_subscriber_suppressed() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let unsubscribe_links = app.get_unsubscribe_links(email_request);
+    app.post_postmark_webhook(bounce("HardBounce")).await;
+
+    // Act
+    let response = app.post_unsubscribe(unsubscribe_links.html).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "bounced");
+}
+
+#[tokio::test]
+async fn complaints_are_not_downgraded_to_bounces() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.post_postmark_webhook(spam_complaint()).await;
+
+    // Act
+    let response = app.post_postmark_webhook(bounce("HardBounce")).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "complained");
+}